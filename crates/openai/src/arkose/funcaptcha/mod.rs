@@ -1,8 +1,10 @@
 mod breaker;
 pub mod model;
+pub mod sample;
 pub mod solver;
 
 use self::model::{Challenge, ConciseChallenge, FunCaptcha, RequestChallenge, TGuess};
+use self::sample::{Sample, Verdict};
 use super::{crypto, ArkoseSolverContext};
 use crate::arkose::error::ArkoseError;
 use crate::arkose::funcaptcha::model::{SubmitChallenge, TGuessResp};
@@ -419,10 +421,12 @@ impl Session {
         self.funcaptcha.as_ref()
     }
 
+    /// Store the funcaptcha images with a sidecar of the answer and its verdict
     pub async fn save_funcaptcha_to_dir(
         self,
         dir: impl AsRef<Path>,
         guess: Vec<i32>,
+        verdict: Verdict,
     ) -> FunResult<()> {
         if let Some(funcaptcha) = self.funcaptcha {
             if guess.len() != funcaptcha.len() {
@@ -430,8 +434,9 @@ impl Session {
                 return Ok(());
             }
 
+            let created_at = now_duration()?.as_secs();
             for (index, fun) in funcaptcha.into_iter().enumerate() {
-                let game_variant_dir = dir.as_ref().join(fun.game_variant);
+                let game_variant_dir = dir.as_ref().join(&fun.game_variant);
                 if !game_variant_dir.exists() {
                     if let Some(err) = tokio::fs::create_dir(&game_variant_dir).await.err() {
                         tracing::warn!(
@@ -447,9 +452,16 @@ impl Session {
                 // Write image to file
                 let image_path =
                     game_variant_dir.join(format!("{}_{index}.png", self.session_token));
-                // Write image guess to file
-                let image_guess_path =
-                    game_variant_dir.join(format!("{}_{index}.txt", self.session_token));
+                // Write image sidecar to file
+                let sidecar_path =
+                    game_variant_dir.join(format!("{}_{index}.json", self.session_token));
+                let sidecar = serde_json::to_vec_pretty(&Sample {
+                    game_variant: fun.game_variant,
+                    instructions: fun.instructions,
+                    answer: guess[index],
+                    verdict,
+                    created_at,
+                })?;
 
                 if let Some(err) = tokio::fs::write(&image_path, image).await.err() {
                     tracing::warn!(
@@ -458,13 +470,10 @@ impl Session {
                     );
                 }
 
-                if let Some(err) = tokio::fs::write(&image_guess_path, guess[index].to_string())
-                    .await
-                    .err()
-                {
+                if let Some(err) = tokio::fs::write(&sidecar_path, sidecar).await.err() {
                     tracing::warn!(
-                        "Failed to write image guess to file: {}, error: {err}",
-                        image_guess_path.display()
+                        "Failed to write image sidecar to file: {}, error: {err}",
+                        sidecar_path.display()
                     );
                 }
             }
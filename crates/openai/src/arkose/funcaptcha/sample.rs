@@ -0,0 +1,136 @@
+//! Solved funcaptcha samples of the image store.
+//!
+//! A sample is stored as `<dir>/<game_variant>/<session>_<index>.png` with a
+//! `<session>_<index>.json` sidecar. Older stores carry a `.txt` file with the answer only,
+//! they were only written for the accepted answers.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::warn;
+
+/// Verdict of the submitted answers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Solved,
+    Rejected,
+}
+
+impl Verdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Solved => "solved",
+            Self::Rejected => "rejected",
+        }
+    }
+}
+
+/// Sidecar of a stored funcaptcha image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub game_variant: String,
+    #[serde(default)]
+    pub instructions: String,
+    /// Submitted answer index
+    pub answer: i32,
+    pub verdict: Verdict,
+    /// Unix timestamp (seconds)
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+/// Read the samples of the image store, returns the image paths with their sidecars
+pub fn read_samples(dir: impl AsRef<Path>) -> anyhow::Result<Vec<(PathBuf, Sample)>> {
+    let mut samples = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let variant_dir = entry?.path();
+        if !variant_dir.is_dir() {
+            continue;
+        }
+        let variant = variant_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        for entry in std::fs::read_dir(&variant_dir)? {
+            let image = entry?.path();
+            if image.extension().map_or(true, |ext| ext != "png") {
+                continue;
+            }
+
+            let sidecar = image.with_extension("json");
+            let legacy = image.with_extension("txt");
+            let sample = if sidecar.is_file() {
+                read_sidecar(&sidecar)
+            } else if legacy.is_file() {
+                read_legacy(&legacy, &variant)
+            } else {
+                continue;
+            };
+
+            // A malformed sample must not abort the whole store
+            match sample {
+                Ok(sample) => samples.push((image, sample)),
+                Err(err) => warn!("Skip malformed sample {}: {err}", image.display()),
+            }
+        }
+    }
+    samples.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(samples)
+}
+
+fn read_sidecar(path: &Path) -> anyhow::Result<Sample> {
+    Ok(serde_json::from_slice::<Sample>(&std::fs::read(path)?)?)
+}
+
+fn read_legacy(path: &Path, variant: &str) -> anyhow::Result<Sample> {
+    Ok(Sample {
+        game_variant: variant.to_owned(),
+        instructions: String::new(),
+        answer: std::fs::read_to_string(path)?.trim().parse()?,
+        verdict: Verdict::Solved,
+        created_at: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let variant_dir = dir.path().join("3d_rollball_objects");
+        std::fs::create_dir_all(&variant_dir).unwrap();
+
+        let sample = Sample {
+            game_variant: "3d_rollball_objects".to_owned(),
+            instructions: "Pick the dice".to_owned(),
+            answer: 2,
+            verdict: Verdict::Rejected,
+            created_at: 1700000000,
+        };
+        std::fs::write(variant_dir.join("a_0.png"), b"png").unwrap();
+        std::fs::write(
+            variant_dir.join("a_0.json"),
+            serde_json::to_vec(&sample).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(variant_dir.join("b_0.png"), b"png").unwrap();
+        std::fs::write(variant_dir.join("b_0.txt"), b"4").unwrap();
+        std::fs::write(variant_dir.join("c_0.png"), b"png").unwrap();
+        std::fs::write(variant_dir.join("d_0.png"), b"png").unwrap();
+        std::fs::write(variant_dir.join("d_0.json"), b"{").unwrap();
+        std::fs::write(variant_dir.join("e_0.png"), b"png").unwrap();
+        std::fs::write(variant_dir.join("e_0.txt"), b"four").unwrap();
+
+        let samples = read_samples(dir.path()).unwrap();
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].1, sample);
+        assert_eq!(samples[1].1.answer, 4);
+        assert_eq!(samples[1].1.verdict, Verdict::Solved);
+        assert_eq!(samples[1].1.game_variant, "3d_rollball_objects");
+    }
+}
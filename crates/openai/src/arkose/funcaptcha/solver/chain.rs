@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::{ArkoseSolver, CaptchaSolver, SolverTask};
+use crate::arkose::error::ArkoseError;
+use crate::arkose::funcaptcha::model::FunCaptcha;
+use crate::warn;
+
+/// Consecutive failures to demote a solver
+const DEMOTE_THRESHOLD: u32 = 3;
+
+/// Demotion duration, a demoted solver is tried after the others
+const DEMOTE_DURATION: Duration = Duration::from_secs(300);
+
+#[derive(Default)]
+struct Stats {
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    /// Total latency of the successful tasks
+    latency: Duration,
+    errors: HashMap<&'static str, u64>,
+    demoted_until: Option<Instant>,
+}
+
+/// Solver statistics snapshot
+#[derive(Serialize)]
+pub struct SolverStatus {
+    pub solver: String,
+    pub successes: u64,
+    pub failures: u64,
+    pub success_rate: Option<f64>,
+    /// Average latency of the successful tasks (milliseconds)
+    pub avg_latency_ms: Option<u64>,
+    /// Failures by error code
+    pub errors: HashMap<&'static str, u64>,
+    /// Remaining demotion (seconds)
+    pub demoted_secs: Option<u64>,
+}
+
+struct Member {
+    solver: ArkoseSolver,
+    stats: Mutex<Stats>,
+}
+
+impl Member {
+    fn lock(&self) -> MutexGuard<'_, Stats> {
+        self.stats.lock().expect("solver stats lock poisoned")
+    }
+
+    fn is_demoted(&self, now: Instant) -> bool {
+        self.lock().demoted_until.is_some_and(|until| until > now)
+    }
+
+    fn success(&self, latency: Duration) {
+        let mut stats = self.lock();
+        stats.successes += 1;
+        stats.consecutive_failures = 0;
+        stats.latency += latency;
+        stats.demoted_until = None;
+    }
+
+    fn failure(&self, code: &'static str) {
+        let mut stats = self.lock();
+        let now = Instant::now();
+        stats.failures += 1;
+        stats.consecutive_failures += 1;
+        *stats.errors.entry(code).or_default() += 1;
+        #[cfg(feature = "serve")]
+        metrics::increment_counter!(
+            "ninja_arkose_solver_errors_total",
+            "solver" => self.solver.name().to_owned(),
+            "code" => code
+        );
+
+        let demoted = stats.demoted_until.is_some_and(|until| until > now);
+        if !demoted && stats.consecutive_failures >= DEMOTE_THRESHOLD {
+            stats.demoted_until = Some(now + DEMOTE_DURATION);
+            warn!(
+                "Solver `{}` demoted for {}s after {} consecutive failures",
+                self.solver.name(),
+                DEMOTE_DURATION.as_secs(),
+                stats.consecutive_failures
+            );
+        }
+    }
+}
+
+/// Funcaptcha answers and the solvers that answered them
+pub struct Solved {
+    pub answers: Vec<i32>,
+    solvers: Vec<usize>,
+}
+
+/// Ordered solvers, a failed task fails over to the next solver until the retry budget is spent.
+/// Consistently failing solvers are demoted behind the others, the statistics reset when the config is reloaded.
+pub struct SolverChain {
+    members: Vec<Member>,
+    retries: usize,
+}
+
+impl SolverChain {
+    pub fn new(solvers: Vec<ArkoseSolver>, retries: usize) -> Self {
+        Self {
+            members: solvers
+                .into_iter()
+                .map(|solver| Member {
+                    solver,
+                    stats: Mutex::new(Stats::default()),
+                })
+                .collect(),
+            retries,
+        }
+    }
+
+    /// Get the solvers
+    pub fn solvers(&self) -> impl Iterator<Item = &ArkoseSolver> {
+        self.members.iter().map(|member| &member.solver)
+    }
+
+    /// Member indexes in the configured order, the demoted ones last
+    fn order(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let (demoted, active): (Vec<usize>, Vec<usize>) =
+            (0..self.members.len()).partition(|&i| self.members[i].is_demoted(now));
+        active.into_iter().chain(demoted).collect()
+    }
+
+    /// Solve the funcaptcha images, the remaining images fail over to the next solver on errors
+    pub async fn solve(&self, funs: &[FunCaptcha]) -> anyhow::Result<Solved> {
+        let order = self.order();
+        if order.is_empty() {
+            anyhow::bail!(ArkoseError::NoSolverAvailable)
+        }
+
+        let mut solved = Solved {
+            answers: Vec::with_capacity(funs.len()),
+            solvers: Vec::new(),
+        };
+        let mut attempt = 0;
+        'solve: while solved.answers.len() < funs.len() {
+            let index = order[attempt % order.len()];
+            let member = &self.members[index];
+            for task in SolverTask::split(&funs[solved.answers.len()..], member.solver.limit()) {
+                let start = Instant::now();
+                let result = member.solver.solve(&task).await;
+                let elapsed = start.elapsed();
+                #[cfg(feature = "serve")]
+                metrics::histogram!(
+                    "ninja_arkose_solver_duration_seconds",
+                    elapsed.as_secs_f64(),
+                    "solver" => member.solver.name().to_owned(),
+                    "result" => if result.is_ok() { "success" } else { "error" }
+                );
+
+                match result {
+                    Ok(answers) => {
+                        member.success(elapsed);
+                        solved.answers.extend(answers);
+                        if !solved.solvers.contains(&index) {
+                            solved.solvers.push(index);
+                        }
+                    }
+                    Err(err) => {
+                        member.failure(error_code(&err));
+                        if attempt >= self.retries {
+                            return Err(err);
+                        }
+                        warn!(
+                            "Solver `{}` error, failing over: {err}",
+                            member.solver.name()
+                        );
+                        attempt += 1;
+                        continue 'solve;
+                    }
+                }
+            }
+        }
+        Ok(solved)
+    }
+
+    /// Report the answers rejected by funcaptcha
+    pub fn rejected(&self, solved: &Solved) {
+        for &index in &solved.solvers {
+            self.members[index].failure("rejected");
+        }
+    }
+
+    /// Get the statistics of the solvers
+    pub fn status(&self) -> Vec<SolverStatus> {
+        let now = Instant::now();
+        self.members
+            .iter()
+            .map(|member| {
+                let stats = member.lock();
+                let total = stats.successes + stats.failures;
+                SolverStatus {
+                    solver: member.solver.name().to_owned(),
+                    successes: stats.successes,
+                    failures: stats.failures,
+                    success_rate: (total > 0).then(|| stats.successes as f64 / total as f64),
+                    avg_latency_ms: (stats.successes > 0)
+                        .then(|| (stats.latency.as_millis() / stats.successes as u128) as u64),
+                    errors: stats.errors.clone(),
+                    demoted_secs: stats
+                        .demoted_until
+                        .filter(|until| *until > now)
+                        .map(|until| (until - now).as_secs()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Get the error code of the solver error
+fn error_code(err: &anyhow::Error) -> &'static str {
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        return if err.is_timeout() {
+            "timeout"
+        } else if err.is_connect() {
+            "connect"
+        } else if err.is_decode() {
+            "decode"
+        } else {
+            "request"
+        };
+    }
+    match err.downcast_ref::<ArkoseError>() {
+        Some(ArkoseError::SolverTaskError(_)) => "task",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arkose::funcaptcha::solver::Solver;
+
+    fn chain() -> SolverChain {
+        let solver = |solver| ArkoseSolver::new(solver, "key".to_owned(), None, None, 1);
+        SolverChain::new(
+            vec![
+                solver(Solver::Fcsrv),
+                solver(Solver::Capsolver),
+                solver(Solver::Yescaptcha),
+            ],
+            2,
+        )
+    }
+
+    #[test]
+    fn test_demote() {
+        let chain = chain();
+        assert_eq!(chain.order(), vec![0, 1, 2]);
+
+        (0..DEMOTE_THRESHOLD).for_each(|_| chain.members[0].failure("timeout"));
+        assert_eq!(chain.order(), vec![1, 2, 0]);
+
+        let status = chain.status();
+        assert_eq!(status[0].errors.get("timeout"), Some(&3));
+        assert_eq!(status[0].success_rate, Some(0.0));
+        assert!(status[0].demoted_secs.is_some());
+        assert!(status[1].success_rate.is_none());
+
+        chain.members[0].success(Duration::from_millis(300));
+        assert_eq!(chain.order(), vec![0, 1, 2]);
+        assert_eq!(chain.status()[0].avg_latency_ms, Some(300));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let chain = chain();
+        let solved = Solved {
+            answers: vec![1, 2],
+            solvers: vec![0, 2],
+        };
+        chain.rejected(&solved);
+        let status = chain.status();
+        assert_eq!(status[0].errors.get("rejected"), Some(&1));
+        assert!(status[1].errors.is_empty());
+        assert_eq!(status[2].failures, 1);
+    }
+}
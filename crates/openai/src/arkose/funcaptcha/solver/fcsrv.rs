@@ -0,0 +1,39 @@
+//! The task API of fcsrv
+
+use serde::{Deserialize, Serialize};
+
+use super::{check_answers, post_json, ArkoseSolver, SolverTask};
+use crate::arkose::error::ArkoseError;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct TaskResp {
+    error: Option<String>,
+    objects: Vec<i32>,
+}
+
+#[derive(Serialize, Debug)]
+struct ReqBody<'a> {
+    api_key: Option<&'a str>,
+    #[serde(rename = "type")]
+    typed: &'a str,
+    images: &'a Vec<&'a String>,
+}
+
+pub(super) async fn solve(
+    arkose_solver: &ArkoseSolver,
+    task: &SolverTask<'_>,
+) -> anyhow::Result<Vec<i32>> {
+    let body = ReqBody {
+        api_key: Some(&arkose_solver.client_key),
+        typed: task.game_variant,
+        images: &task.images,
+    };
+
+    let resp = post_json::<_, TaskResp>(&arkose_solver.endpoint, &body).await?;
+    if let Some(error) = resp.error {
+        anyhow::bail!(ArkoseError::SolverTaskError(error))
+    }
+
+    check_answers(task, resp.objects)
+}
@@ -0,0 +1,166 @@
+//! Generic JSON-over-HTTP solver protocol, e.g. to plug in an in-house image classifier.
+//!
+//! Create a task, `POST <endpoint>`:
+//!
+//! ```json
+//! {
+//!   "client_key": "optional solver key",
+//!   "task": {
+//!     "type": "funcaptcha",
+//!     "game_variant": "3d_rollball_objects",
+//!     "instructions": "Use the arrows to rotate the object to face in the direction of the hand",
+//!     "images": ["<base64 image>", "<base64 image>"]
+//!   }
+//! }
+//! ```
+//!
+//! The solver answers one index per image, at most `--arkose-solver-limit` images are sent in a task:
+//!
+//! ```json
+//! { "status": "ready", "objects": [2, 0] }
+//! ```
+//!
+//! An asynchronous solver returns a task id instead:
+//!
+//! ```json
+//! { "status": "processing", "task_id": "c0ffee" }
+//! ```
+//!
+//! The result is then polled every 2 seconds for up to 60 seconds, `POST <result endpoint>`
+//! (`<endpoint>/result` unless `--arkose-solver-result-endpoint` is set):
+//!
+//! ```json
+//! { "client_key": "optional solver key", "task_id": "c0ffee" }
+//! ```
+//!
+//! The poll is answered with the same response as the task creation. A failed task responds
+//! `{ "status": "failed", "error": "reason" }` or a non-2xx status.
+
+use serde::{Deserialize, Serialize};
+
+use super::{check_answers, poll_task, post_json, ArkoseSolver, SolverTask};
+use crate::arkose::error::ArkoseError;
+
+/// Create task request
+#[derive(Serialize, Debug)]
+pub struct TaskRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<&'a str>,
+    pub task: Task<'a>,
+}
+
+/// Funcaptcha classification task
+#[derive(Serialize, Debug)]
+pub struct Task<'a> {
+    /// Always `funcaptcha`
+    #[serde(rename = "type")]
+    pub typed: &'a str,
+    pub game_variant: &'a str,
+    pub instructions: &'a str,
+    /// Base64 encoded images
+    pub images: Vec<&'a str>,
+}
+
+/// Task result request of an asynchronous solver
+#[derive(Serialize, Debug)]
+pub struct ResultRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<&'a str>,
+    pub task_id: &'a str,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    #[default]
+    Ready,
+    Processing,
+    Failed,
+}
+
+/// Create task and task result response
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TaskResponse {
+    pub status: TaskStatus,
+    /// Answer index per image, set when the task is ready
+    pub objects: Vec<i32>,
+    /// Set when the task is processing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl TaskResponse {
+    /// Get the answers, `None` if the task is still processing
+    fn answers(self) -> anyhow::Result<Option<Vec<i32>>> {
+        if let Some(error) = self.error {
+            anyhow::bail!(ArkoseError::SolverTaskError(error))
+        }
+        match self.status {
+            TaskStatus::Ready => Ok(Some(self.objects)),
+            TaskStatus::Processing => Ok(None),
+            TaskStatus::Failed => anyhow::bail!(ArkoseError::SolverTaskError(
+                "Task failed without an error".to_owned()
+            )),
+        }
+    }
+}
+
+pub(super) async fn solve(
+    arkose_solver: &ArkoseSolver,
+    task: &SolverTask<'_>,
+) -> anyhow::Result<Vec<i32>> {
+    let client_key = Some(arkose_solver.client_key.as_str()).filter(|key| !key.is_empty());
+    let body = TaskRequest {
+        client_key,
+        task: Task {
+            typed: "funcaptcha",
+            game_variant: task.game_variant,
+            instructions: task.instructions,
+            images: task.images.iter().map(|image| image.as_str()).collect(),
+        },
+    };
+
+    let resp = post_json::<_, TaskResponse>(&arkose_solver.endpoint, &body).await?;
+    let task_id = resp.task_id.clone();
+    let answers = match resp.answers()? {
+        Some(answers) => answers,
+        None => {
+            let task_id = task_id.ok_or_else(|| {
+                ArkoseError::SolverTaskError("Processing task without a task id".to_owned())
+            })?;
+            let url = arkose_solver.result_endpoint()?;
+            let body = ResultRequest {
+                client_key,
+                task_id: &task_id,
+            };
+            let body = &body;
+            poll_task(|| async move { post_json::<_, TaskResponse>(url, body).await?.answers() })
+                .await?
+        }
+    };
+
+    check_answers(task, answers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_task_response() {
+        let resp: TaskResponse = serde_json::from_str(r#"{"objects":[2,0]}"#).unwrap();
+        assert_eq!(resp.answers().unwrap(), Some(vec![2, 0]));
+
+        let resp: TaskResponse =
+            serde_json::from_str(r#"{"status":"processing","task_id":"c0ffee"}"#).unwrap();
+        assert_eq!(resp.task_id.as_deref(), Some("c0ffee"));
+        assert_eq!(resp.answers().unwrap(), None);
+
+        let resp: TaskResponse =
+            serde_json::from_str(r#"{"status":"failed","error":"no model"}"#).unwrap();
+        assert!(resp.answers().is_err());
+    }
+}
@@ -0,0 +1,421 @@
+mod chain;
+mod fcsrv;
+pub mod generic;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+mod yescaptcha;
+
+pub use chain::{Solved, SolverChain, SolverStatus};
+
+use std::future::Future;
+#[cfg(feature = "onnx")]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::model::FunCaptcha;
+use crate::{arkose::error::ArkoseError, with_context};
+
+/// Interval of polling the asynchronous task result
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Give up polling the asynchronous task result after the timeout
+const POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Solver {
+    Yescaptcha,
+    Capsolver,
+    Fcsrv,
+    Generic,
+    #[cfg(feature = "onnx")]
+    Onnx,
+}
+
+impl Default for Solver {
+    fn default() -> Self {
+        Self::Fcsrv
+    }
+}
+
+/// Supported solver names
+#[cfg(feature = "onnx")]
+const SOLVERS: &str = "`yescaptcha` / `capsolver` / `fcsrv` / `generic` / `onnx`";
+#[cfg(not(feature = "onnx"))]
+const SOLVERS: &str = "`yescaptcha` / `capsolver` / `fcsrv` / `generic`";
+
+impl FromStr for Solver {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yescaptcha" => Ok(Self::Yescaptcha),
+            "capsolver" => Ok(Self::Capsolver),
+            "fcsrv" => Ok(Self::Fcsrv),
+            "generic" => Ok(Self::Generic),
+            #[cfg(feature = "onnx")]
+            "onnx" => Ok(Self::Onnx),
+            _ => anyhow::bail!("Only support {SOLVERS} solver"),
+        }
+    }
+}
+
+impl ToString for Solver {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Yescaptcha => "yescaptcha".to_string(),
+            Self::Capsolver => "capsolver".to_string(),
+            Self::Fcsrv => "fcsrv".to_string(),
+            Self::Generic => "generic".to_string(),
+            #[cfg(feature = "onnx")]
+            Self::Onnx => "onnx".to_string(),
+        }
+    }
+}
+
+impl Solver {
+    /// Check if the solver requires a client key, the in-house and local solvers do not
+    pub fn requires_key(&self) -> bool {
+        match self {
+            Self::Generic => false,
+            #[cfg(feature = "onnx")]
+            Self::Onnx => false,
+            _ => true,
+        }
+    }
+
+    /// Check if the solver requires a model directory, only the local onnx solver does
+    pub fn requires_model_dir(&self) -> bool {
+        match self {
+            #[cfg(feature = "onnx")]
+            Self::Onnx => true,
+            _ => false,
+        }
+    }
+}
+
+/// Funcaptcha images of a game variant, submitted to the solver in one task
+#[derive(Debug)]
+pub struct SolverTask<'a> {
+    /// Game variant, e.g. `3d_rollball_objects`
+    pub game_variant: &'a str,
+    /// Instructions shown to the user
+    pub instructions: &'a str,
+    /// Base64 encoded images
+    pub images: Vec<&'a String>,
+}
+
+impl<'a> SolverTask<'a> {
+    /// Split the funcaptcha images into tasks of at most `limit` images.
+    /// Only the consecutive images of the same game variant share a task, so the answers keep the image order.
+    pub fn split(funs: &'a [FunCaptcha], limit: usize) -> Vec<Self> {
+        let mut tasks = Vec::new();
+        let mut start = 0;
+        while start < funs.len() {
+            let variant = &funs[start].game_variant;
+            let end = funs[start..]
+                .iter()
+                .position(|fun| fun.game_variant.ne(variant))
+                .map_or(funs.len(), |len| start + len);
+
+            tasks.extend(funs[start..end].chunks(limit.max(1)).map(|chunk| Self {
+                game_variant: variant,
+                instructions: &chunk[0].instructions,
+                images: chunk.iter().map(|fun| &fun.image).collect(),
+            }));
+            start = end;
+        }
+        tasks
+    }
+}
+
+/// Funcaptcha image classifier
+#[trait_variant::make(CaptchaSolver: Send)]
+pub trait LocalCaptchaSolver: Sync {
+    /// Solver name in logs and metrics
+    fn name(&self) -> &str;
+
+    /// Maximum images of a task
+    fn limit(&self) -> usize;
+
+    /// Solve the task, returns one answer index per image
+    async fn solve(&self, task: &SolverTask<'_>) -> anyhow::Result<Vec<i32>>;
+}
+
+/// Fallback solver entry of the config file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SolverEntry {
+    pub solver: Solver,
+    /// Client key, optional for the generic solver
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub result_endpoint: Option<String>,
+    /// Maximum images of a task
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /// Model directory of the onnx solver
+    #[cfg(feature = "onnx")]
+    #[serde(default)]
+    pub model_dir: Option<PathBuf>,
+}
+
+fn default_limit() -> usize {
+    1
+}
+
+impl TryFrom<SolverEntry> for ArkoseSolver {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: SolverEntry) -> Result<Self, Self::Error> {
+        #[cfg(feature = "onnx")]
+        if entry.solver.requires_model_dir() && entry.model_dir.is_none() {
+            anyhow::bail!(
+                "Fallback solver `{}` requires the `model_dir`",
+                entry.solver.to_string()
+            )
+        }
+
+        let solver = ArkoseSolver::new(
+            entry.solver,
+            entry.key.unwrap_or_default(),
+            entry.endpoint,
+            entry.result_endpoint,
+            entry.limit,
+        );
+        #[cfg(feature = "onnx")]
+        let solver = match entry.model_dir {
+            Some(dir) => solver.with_model_dir(dir),
+            None => solver,
+        };
+        Ok(solver)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArkoseSolver {
+    pub solver: Solver,
+    pub limit: usize,
+    client_key: String,
+    endpoint: String,
+    /// Result endpoint of the asynchronous tasks
+    result_endpoint: Option<String>,
+    /// Local classifier of the onnx solver
+    #[cfg(feature = "onnx")]
+    #[serde(skip)]
+    classifier: Option<std::sync::Arc<onnx::OnnxClassifier>>,
+}
+
+impl ArkoseSolver {
+    pub fn new(
+        solver: Solver,
+        client_key: String,
+        endpoint: Option<String>,
+        result_endpoint: Option<String>,
+        limit: usize,
+    ) -> Self {
+        let endpoint = match solver {
+            Solver::Yescaptcha => {
+                endpoint.unwrap_or("https://api.yescaptcha.com/createTask".to_string())
+            }
+            Solver::Capsolver => {
+                endpoint.unwrap_or("https://api.capsolver.com/createTask".to_string())
+            }
+            Solver::Fcsrv => endpoint.unwrap_or("http://127.0.0.1:8000/task".to_string()),
+            Solver::Generic => endpoint.unwrap_or("http://127.0.0.1:8000/task".to_string()),
+            #[cfg(feature = "onnx")]
+            Solver::Onnx => endpoint.unwrap_or_default(),
+        };
+        let result_endpoint = match solver {
+            Solver::Yescaptcha | Solver::Capsolver => {
+                result_endpoint.or(Some(endpoint.replace("createTask", "getTaskResult")))
+            }
+            Solver::Generic => {
+                result_endpoint.or(Some(format!("{}/result", endpoint.trim_end_matches('/'))))
+            }
+            Solver::Fcsrv => None,
+            #[cfg(feature = "onnx")]
+            Solver::Onnx => None,
+        };
+        Self {
+            solver,
+            client_key,
+            endpoint,
+            result_endpoint,
+            limit,
+            #[cfg(feature = "onnx")]
+            classifier: None,
+        }
+    }
+
+    /// Set the model directory of the onnx solver
+    #[cfg(feature = "onnx")]
+    pub fn with_model_dir(mut self, dir: PathBuf) -> Self {
+        self.classifier = Some(std::sync::Arc::new(onnx::OnnxClassifier::new(dir)));
+        self
+    }
+
+    /// Get the result endpoint of the asynchronous tasks
+    fn result_endpoint(&self) -> anyhow::Result<&str> {
+        self.result_endpoint.as_deref().ok_or_else(|| {
+            ArkoseError::SolverTaskError(format!(
+                "{} solver does not support asynchronous tasks",
+                self.solver.to_string()
+            ))
+            .into()
+        })
+    }
+}
+
+impl CaptchaSolver for ArkoseSolver {
+    fn name(&self) -> &str {
+        match self.solver {
+            Solver::Yescaptcha => "yescaptcha",
+            Solver::Capsolver => "capsolver",
+            Solver::Fcsrv => "fcsrv",
+            Solver::Generic => "generic",
+            #[cfg(feature = "onnx")]
+            Solver::Onnx => "onnx",
+        }
+    }
+
+    fn limit(&self) -> usize {
+        match self.solver {
+            // The yescaptcha classification task takes a single image
+            Solver::Yescaptcha => 1,
+            // The local classifier takes any number of images
+            #[cfg(feature = "onnx")]
+            Solver::Onnx => usize::MAX,
+            _ => self.limit.max(1),
+        }
+    }
+
+    async fn solve(&self, task: &SolverTask<'_>) -> anyhow::Result<Vec<i32>> {
+        match self.solver {
+            Solver::Yescaptcha | Solver::Capsolver => yescaptcha::solve(self, task).await,
+            Solver::Fcsrv => fcsrv::solve(self, task).await,
+            Solver::Generic => generic::solve(self, task).await,
+            #[cfg(feature = "onnx")]
+            Solver::Onnx => onnx::solve(self, task).await,
+        }
+    }
+}
+
+/// Post the JSON body to the solver, the error status is a task error
+async fn post_json<B: Serialize, R: DeserializeOwned>(url: &str, body: &B) -> anyhow::Result<R> {
+    let resp = with_context!(arkose_client)
+        .post(url)
+        .json(body)
+        .send()
+        .await?;
+
+    match resp.error_for_status_ref() {
+        Ok(_) => Ok(resp.json::<R>().await?),
+        Err(_) => {
+            let body = resp.text().await?;
+            anyhow::bail!(ArkoseError::SolverTaskError(body))
+        }
+    }
+}
+
+/// Poll the asynchronous task until the answers are ready
+async fn poll_task<F, Fut>(mut ready: F) -> anyhow::Result<Vec<i32>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<Vec<i32>>>>,
+{
+    let deadline = Instant::now() + POLL_TIMEOUT;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        if let Some(answers) = ready().await? {
+            return Ok(answers);
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!(ArkoseError::SolverTaskError(format!(
+                "Task result is not ready after {}s",
+                POLL_TIMEOUT.as_secs()
+            )))
+        }
+    }
+}
+
+/// Check that the solver answers every image of the task
+fn check_answers(task: &SolverTask<'_>, answers: Vec<i32>) -> anyhow::Result<Vec<i32>> {
+    if answers.len() != task.images.len() {
+        anyhow::bail!(ArkoseError::SolverTaskError(format!(
+            "Expected {} answers, got {}",
+            task.images.len(),
+            answers.len()
+        )))
+    }
+    Ok(answers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fun(variant: &str, image: &str) -> FunCaptcha {
+        FunCaptcha {
+            image: image.to_owned(),
+            instructions: format!("{variant} instructions"),
+            game_variant: variant.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let funs = vec![
+            fun("a", "1"),
+            fun("a", "2"),
+            fun("a", "3"),
+            fun("b", "4"),
+            fun("a", "5"),
+        ];
+        let tasks = SolverTask::split(&funs, 2)
+            .into_iter()
+            .map(|task| {
+                let images = task.images.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                (task.game_variant, images)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tasks,
+            vec![
+                ("a", vec!["1", "2"]),
+                ("a", vec!["3"]),
+                ("b", vec!["4"]),
+                ("a", vec!["5"]),
+            ]
+        );
+        assert_eq!(SolverTask::split(&funs, 0).len(), 5);
+    }
+
+    #[test]
+    fn test_result_endpoint() {
+        let solver = ArkoseSolver::new(Solver::Capsolver, "key".to_owned(), None, None, 1);
+        assert_eq!(
+            solver.result_endpoint().unwrap(),
+            "https://api.capsolver.com/getTaskResult"
+        );
+        let solver = ArkoseSolver::new(
+            Solver::Generic,
+            String::new(),
+            Some("http://classifier:8000/task/".to_owned()),
+            None,
+            8,
+        );
+        assert_eq!(
+            solver.result_endpoint().unwrap(),
+            "http://classifier:8000/task/result"
+        );
+        assert_eq!(solver.limit(), 8);
+        let solver = ArkoseSolver::new(Solver::Fcsrv, "key".to_owned(), None, None, 1);
+        assert!(solver.result_endpoint().is_err());
+    }
+}
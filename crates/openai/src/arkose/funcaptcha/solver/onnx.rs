@@ -0,0 +1,180 @@
+//! Local funcaptcha image classifier, the ONNX models run in process on the CPU.
+//!
+//! The model of a game variant is loaded from `<model dir>/<game_variant>.onnx`, e.g. `3d_rollball_objects.onnx`.
+//! It takes the whole challenge image as a `[1, 3, height, width]` RGB tensor scaled to `[0, 1]`,
+//! the input size is fixed by the model, and outputs the score of each answer index, the best score is the answer.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use base64::{engine::general_purpose, Engine as _};
+use image::imageops::FilterType;
+use tract_onnx::prelude::*;
+
+use super::{check_answers, ArkoseSolver, SolverTask};
+use crate::{arkose::error::ArkoseError, info};
+
+struct Model {
+    plan: TypedRunnableModel<TypedModel>,
+    height: usize,
+    width: usize,
+}
+
+/// ONNX models of the game variants, loaded on first use
+pub struct OnnxClassifier {
+    dir: PathBuf,
+    models: Mutex<HashMap<String, Arc<Model>>>,
+}
+
+impl std::fmt::Debug for OnnxClassifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OnnxClassifier")
+            .field("dir", &self.dir)
+            .finish()
+    }
+}
+
+impl OnnxClassifier {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Arc<Model>>> {
+        self.models.lock().expect("onnx models lock poisoned")
+    }
+
+    /// Get the model path of the game variant, the variant comes from the challenge so it must be a plain name
+    fn model_path(&self, variant: &str) -> anyhow::Result<PathBuf> {
+        let valid = !variant.is_empty()
+            && variant
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            anyhow::bail!(ArkoseError::SolverTaskError(format!(
+                "Invalid game variant: {variant}"
+            )))
+        }
+        Ok(self.dir.join(format!("{variant}.onnx")))
+    }
+
+    /// Get the model of the game variant, the model is loaded and optimized on first use.
+    /// The loading runs outside the lock so it does not block the other variants.
+    fn model(&self, variant: &str) -> anyhow::Result<Arc<Model>> {
+        if let Some(model) = self.lock().get(variant) {
+            return Ok(model.clone());
+        }
+
+        let path = self.model_path(variant)?;
+        if !path.is_file() {
+            anyhow::bail!(ArkoseError::SolverTaskError(format!(
+                "No onnx model of game variant `{variant}`: {}",
+                path.display()
+            )))
+        }
+
+        let typed = tract_onnx::onnx().model_for_path(&path)?.into_optimized()?;
+        let (height, width) = match typed.input_fact(0)?.shape.as_concrete() {
+            Some(&[1, 3, height, width]) => (height, width),
+            shape => anyhow::bail!(ArkoseError::SolverTaskError(format!(
+                "Onnx model input must be [1, 3, height, width], got {shape:?}: {}",
+                path.display()
+            ))),
+        };
+        let model = Arc::new(Model {
+            plan: typed.into_runnable()?,
+            height,
+            width,
+        });
+
+        // Another task may have loaded the same variant meanwhile, keep the first one
+        let model = self
+            .lock()
+            .entry(variant.to_owned())
+            .or_insert_with(|| {
+                info!("Loaded onnx model: {}", path.display());
+                model
+            })
+            .clone();
+        Ok(model)
+    }
+
+    /// Classify the base64 encoded image, returns the answer index
+    fn classify(&self, variant: &str, data: &str) -> anyhow::Result<i32> {
+        let model = self.model(variant)?;
+        let bytes = general_purpose::STANDARD.decode(data)?;
+        let rgb = image::load_from_memory(&bytes)?
+            .resize_exact(
+                model.width as u32,
+                model.height as u32,
+                FilterType::Triangle,
+            )
+            .to_rgb8();
+
+        let input: Tensor = tract_ndarray::Array4::from_shape_fn(
+            (1, 3, model.height, model.width),
+            |(_, c, y, x)| rgb.get_pixel(x as u32, y as u32).0[c] as f32 / 255.0,
+        )
+        .into();
+        let outputs = model.plan.run(tvec!(input.into()))?;
+        let scores = outputs[0].to_array_view::<f32>()?;
+
+        scores
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(index, _)| index as i32)
+            .ok_or_else(|| {
+                ArkoseError::SolverTaskError("Onnx model has no output".to_owned()).into()
+            })
+    }
+}
+
+pub(super) async fn solve(
+    arkose_solver: &ArkoseSolver,
+    task: &SolverTask<'_>,
+) -> anyhow::Result<Vec<i32>> {
+    let classifier = arkose_solver.classifier.clone().ok_or_else(|| {
+        ArkoseError::SolverTaskError("The onnx solver requires the model directory".to_owned())
+    })?;
+    let variant = task.game_variant.to_owned();
+    let images = task
+        .images
+        .iter()
+        .map(|image| image.to_string())
+        .collect::<Vec<_>>();
+
+    // The inference is CPU bound, keep it off the async workers
+    let answers = tokio::task::spawn_blocking(move || {
+        images
+            .iter()
+            .map(|image| classifier.classify(&variant, image))
+            .collect::<anyhow::Result<Vec<i32>>>()
+    })
+    .await??;
+
+    check_answers(task, answers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_path() {
+        let classifier = OnnxClassifier::new(PathBuf::from("models"));
+        assert_eq!(
+            classifier.model_path("3d_rollball_objects").unwrap(),
+            PathBuf::from("models/3d_rollball_objects.onnx")
+        );
+        assert!(classifier.model_path("../secret").is_err());
+        assert!(classifier.model_path("").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let classifier = OnnxClassifier::new(dir.path().to_owned());
+        assert!(classifier.model("unknown_variant").is_err());
+    }
+}
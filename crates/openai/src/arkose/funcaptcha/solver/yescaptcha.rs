@@ -0,0 +1,121 @@
+//! The `createTask` / `getTaskResult` API of yescaptcha and capsolver
+
+use serde::{Deserialize, Serialize};
+
+use super::{check_answers, poll_task, post_json, ArkoseSolver, Solver, SolverTask};
+use crate::arkose::error::ArkoseError;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct TaskResp {
+    #[serde(rename = "errorId")]
+    error_id: i32,
+    #[serde(rename = "errorCode")]
+    error_code: String,
+    #[serde(rename = "errorDescription")]
+    error_description: Option<String>,
+    status: String,
+    solution: SolutionResp,
+    #[serde(rename = "taskId")]
+    task_id: String,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct SolutionResp {
+    objects: Vec<i32>,
+}
+
+#[derive(Serialize, Debug)]
+struct ReqBody<'a> {
+    #[serde(rename = "clientKey")]
+    client_key: &'a str,
+    task: ReqTask<'a>,
+    #[serde(rename = "softID", skip_serializing_if = "Option::is_none")]
+    soft_id: Option<&'static str>,
+    #[serde(rename = "appId", skip_serializing_if = "Option::is_none")]
+    app_id: Option<&'static str>,
+}
+
+#[derive(Serialize, Debug)]
+struct ReqTask<'a> {
+    #[serde(rename = "type")]
+    type_field: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<&'a Vec<&'a String>>,
+    question: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct ResultBody<'a> {
+    #[serde(rename = "clientKey")]
+    client_key: &'a str,
+    #[serde(rename = "taskId")]
+    task_id: &'a str,
+}
+
+impl TaskResp {
+    /// Get the answers, `None` if the task is still processing
+    fn answers(self) -> anyhow::Result<Option<Vec<i32>>> {
+        if self.error_id != 0 || self.error_description.is_some() {
+            anyhow::bail!(ArkoseError::SolverTaskError(
+                self.error_description.unwrap_or(self.error_code)
+            ))
+        }
+        match self.status.as_str() {
+            "processing" | "idle" => Ok(None),
+            _ => Ok(Some(self.solution.objects)),
+        }
+    }
+}
+
+pub(super) async fn solve(
+    arkose_solver: &ArkoseSolver,
+    task: &SolverTask<'_>,
+) -> anyhow::Result<Vec<i32>> {
+    let body = match arkose_solver.solver {
+        // Yescaptcha classifies a single image with the instructions
+        Solver::Yescaptcha => ReqBody {
+            client_key: &arkose_solver.client_key,
+            task: ReqTask {
+                type_field: "FunCaptchaClassification",
+                image: task.images.first().copied(),
+                images: None,
+                question: task.instructions,
+            },
+            soft_id: Some("26299"),
+            app_id: None,
+        },
+        _ => ReqBody {
+            client_key: &arkose_solver.client_key,
+            task: ReqTask {
+                type_field: "FunCaptchaClassification",
+                image: None,
+                images: Some(&task.images),
+                question: task.game_variant,
+            },
+            soft_id: None,
+            app_id: Some("60632CB0-8BE8-41D3-808F-60CC2442F16E"),
+        },
+    };
+
+    let resp = post_json::<_, TaskResp>(&arkose_solver.endpoint, &body).await?;
+    let task_id = resp.task_id.clone();
+    let answers = match resp.answers()? {
+        Some(answers) => answers,
+        None => {
+            let url = arkose_solver.result_endpoint()?;
+            let body = ResultBody {
+                client_key: &arkose_solver.client_key,
+                task_id: &task_id,
+            };
+            let body = &body;
+            poll_task(|| async move { post_json::<_, TaskResp>(url, body).await?.answers() })
+                .await?
+        }
+    };
+
+    check_answers(task, answers)
+}
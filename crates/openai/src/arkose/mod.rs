@@ -9,6 +9,7 @@ use rand::thread_rng;
 use reqwest::Client;
 use serde::Serialize;
 use std::str::FromStr;
+use std::sync::Arc;
 use typed_builder::TypedBuilder;
 
 use base64::Engine;
@@ -18,9 +19,9 @@ use reqwest::Method;
 use serde::Deserialize;
 use tokio::sync::OnceCell;
 
-use self::funcaptcha::solver::ArkoseSolver;
-use self::funcaptcha::solver::Solver;
-use self::funcaptcha::solver::SubmitSolver;
+use self::funcaptcha::sample::Verdict;
+use self::funcaptcha::solver::SolverChain;
+use crate::client::MemberHealth;
 use crate::context::arkose::har;
 use crate::generate_random_string;
 use crate::gpt_model::GPTModel;
@@ -46,7 +47,8 @@ async fn get_or_init_regex() -> &'static Regex {
         .await
 }
 
-#[derive(Hash, PartialEq, Eq, Debug, Clone, Copy)]
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Type {
     GPT3,
     GPT4,
@@ -114,13 +116,19 @@ impl Type {
             Type::Platform | Type::SignUp => "https://openai-api.arkoselabs.com",
         }
     }
+
+    /// Check if the token carries the account data blob, see `blob::get_blob`
+    pub fn is_account_bound(&self) -> bool {
+        matches!(self, Type::GPT4 | Type::SignUp)
+    }
 }
 
 impl From<GPTModel> for Type {
     fn from(value: GPTModel) -> Self {
         match value {
             GPTModel::Gpt35 => Type::GPT3,
-            GPTModel::Gpt4 | GPTModel::Gpt4Mobile => Type::GPT4,
+            // The model table decides the type of the custom model, GPT-4 is assumed here
+            GPTModel::Gpt4 | GPTModel::Gpt4Mobile | GPTModel::Custom(_) => Type::GPT4,
         }
     }
 }
@@ -134,6 +142,7 @@ impl FromStr for Type {
             "gpt4" => Ok(Type::GPT4),
             "auth" => Ok(Type::Auth),
             "platform" => Ok(Type::Platform),
+            "signup" => Ok(Type::SignUp),
             _ => anyhow::bail!(ArkoseError::InvalidPlatformType(s.to_owned())),
         }
     }
@@ -147,6 +156,18 @@ pub struct ArkoseContext {
     #[builder(setter(into), default)]
     identifier: Option<String>,
     client: Client,
+    /// Health of the client pool member, the outcome of the token request is reported
+    #[builder(setter(into), default)]
+    health: Option<Arc<MemberHealth>>,
+}
+
+impl ArkoseContext {
+    /// Report the outcome of the token request to the client pool member
+    fn observe(&self, result: &Result<reqwest::Response, reqwest::Error>) {
+        if let Some(health) = self.health.as_ref() {
+            health.observe(result);
+        }
+    }
 }
 
 #[derive(TypedBuilder)]
@@ -285,7 +306,7 @@ impl ArkoseToken {
             form.push(("data[blob]", blob));
         }
 
-        let arkose_token = ctx
+        let result = ctx
             .client
             .post(format!("{}/fc/gt2/public_key/{pk}", ctx.typed.origin_url()))
             .header("Accept", "*/*")
@@ -317,12 +338,10 @@ impl ArkoseToken {
             .header("sec-ch-ua-platform", "\"macOS\"")
             .body(serde_urlencoded::to_string(&form)?)
             .send()
-            .await?
-            .error_for_status()?
-            .json::<ArkoseToken>()
-            .await?;
+            .await;
+        ctx.observe(&result);
 
-        Ok(arkose_token)
+        Ok(result?.error_for_status()?.json::<ArkoseToken>().await?)
     }
 
     /// Get ArkoseLabs token from HAR file (Support ChatGPT, Platform, Auth)
@@ -392,12 +411,22 @@ impl ArkoseToken {
         // Update user agent
         ctx.user_agent = Some(entry.bv);
 
-        Ok(builder
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<ArkoseToken>()
-            .await?)
+        let result = builder.send().await;
+        ctx.observe(&result);
+
+        Ok(result?.error_for_status()?.json::<ArkoseToken>().await?)
+    }
+
+    /// Get ArkoseLabs token from the pre-fetch pool, fallback to the context when the pool is empty.
+    /// The account-bound tokens always come from the context, with the account blob and the account proxy
+    pub async fn acquire(ctx: ArkoseContext) -> anyhow::Result<Self> {
+        let account_bound = ctx.typed.is_account_bound();
+        if let Some(pool) = with_context!(arkose_token_pool).filter(|_| !account_bound) {
+            if let Some(arkose_token) = pool.pop(ctx.typed) {
+                return Ok(arkose_token);
+            }
+        }
+        Self::new_from_context(ctx).await
     }
 
     /// Get ArkoseLabs token from context (Support ChatGPT, Platform, Auth)
@@ -424,6 +453,7 @@ impl ArkoseToken {
 
             let rid = rng.gen_range(1..=99);
             // experiment token
+            record_outcome(Type::GPT3, "experiment", true);
             let fake_token = format!("{before_dot}.{after_dot}|r=us-west-2|meta=3|metabgclr=transparent|metaiconclr=%23757575|guitextcolor=%23000000|pk=35536E1E-65B4-4D96-9D97-6ADB7EFF8147|at=40|sup=1|rid={rid}|ag=101|cdn_url=https%3A%2F%2Ftcr9i.chat.openai.com%2Fcdn%2Ffc|lurl=https%3A%2F%2Faudio-us-west-2.arkoselabs.com|surl=https%3A%2F%2Ftcr9i.chat.openai.com|smurl=https%3A%2F%2Ftcr9i.chat.openai.com%2Fcdn%2Ffc%2Fassets%2Fstyle-manager");
             return Ok(ArkoseToken::from(fake_token));
         }
@@ -440,21 +470,32 @@ impl ArkoseToken {
                 .arkose_token(arkose_token)
                 .client(ctx.client)
                 .build();
-            return Ok(valid_arkose_token(arkose_solver, solver_context).await);
+            let arkose_token = valid_arkose_token(arkose_solver.as_deref(), solver_context).await;
+            record_outcome(typed, "har", arkose_token.success());
+            return Ok(arkose_token);
         }
 
         // If arkose solver is not empty, use bx
         if arkose_solver.is_some() {
-            let arkose_token = ArkoseToken::new(&mut ctx).await?;
+            let arkose_token = match ArkoseToken::new(&mut ctx).await {
+                Ok(arkose_token) => arkose_token,
+                Err(err) => {
+                    record_outcome(typed, "solver", false);
+                    return Err(err);
+                }
+            };
             let solver_context = ArkoseSolverContext::builder()
                 .user_agent(ctx.user_agent)
                 .typed(typed)
                 .arkose_token(arkose_token)
                 .client(ctx.client)
                 .build();
-            return Ok(valid_arkose_token(arkose_solver, solver_context).await);
+            let arkose_token = valid_arkose_token(arkose_solver.as_deref(), solver_context).await;
+            record_outcome(typed, "solver", arkose_token.success());
+            return Ok(arkose_token);
         }
 
+        record_outcome(typed, "none", false);
         Err(ArkoseError::NoSolverAvailable.into())
     }
 
@@ -513,8 +554,20 @@ impl ArkoseToken {
     }
 }
 
+/// Record the arkose token outcome, the token is solved if it contains `sup=1`
+#[cfg_attr(not(feature = "serve"), allow(unused_variables))]
+fn record_outcome(typed: Type, source: &'static str, solved: bool) {
+    #[cfg(feature = "serve")]
+    metrics::increment_counter!(
+        "ninja_arkose_tokens_total",
+        "type" => format!("{typed:?}").to_lowercase(),
+        "source" => source,
+        "result" => if solved { "success" } else { "failed" }
+    );
+}
+
 async fn valid_arkose_token(
-    arkose_solver: Option<&'static ArkoseSolver>,
+    arkose_solver: Option<&SolverChain>,
     ctx: ArkoseSolverContext,
 ) -> ArkoseToken {
     // If success, return token
@@ -537,7 +590,7 @@ async fn valid_arkose_token(
 }
 
 async fn submit_funcaptcha(
-    arkose_solver: Option<&'static ArkoseSolver>,
+    arkose_solver: Option<&SolverChain>,
     ctx: &ArkoseSolverContext,
 ) -> ArkoseResult<ArkoseToken> {
     // Try get arkose solver
@@ -550,60 +603,26 @@ async fn submit_funcaptcha(
         .funcaptcha()
         .ok_or_else(|| ArkoseError::InvalidFunCaptcha)?;
 
-    let mut answers = Vec::new();
-
-    match arkose_solver.solver {
-        Solver::Yescaptcha => {
-            for (_, fun) in funs.iter().enumerate() {
-                let submit_task = SubmitSolver::builder()
-                    .arkose_solver(arkose_solver)
-                    .question(&fun.instructions)
-                    .image(&fun.image)
-                    .build();
-                answers.extend(funcaptcha::solver::submit_task(submit_task).await?)
-            }
-        }
-        Solver::Capsolver | Solver::Fcsrv => {
-            let mut classified_data = std::collections::HashMap::new();
-
-            for item in funs.iter() {
-                let question = item.game_variant.clone();
-                classified_data
-                    .entry(question)
-                    .or_insert(Vec::new())
-                    .push(item);
-            }
+    // Solve with the solver chain, the remaining images fail over to the next solver
+    let solved = arkose_solver.solve(funs).await?;
 
-            for data in classified_data {
-                let images_chunks = data
-                    .1
-                    .chunks(arkose_solver.limit.max(1))
-                    .map(|item| {
-                        item.iter()
-                            .map(|item| &item.image)
-                            .collect::<Vec<&String>>()
-                    })
-                    .collect::<Vec<Vec<&String>>>();
-
-                for (_, images) in images_chunks.into_iter().enumerate() {
-                    let submit_task = SubmitSolver::builder()
-                        .arkose_solver(arkose_solver)
-                        .question(&data.0)
-                        .images(images)
-                        .build();
-                    answers.extend(funcaptcha::solver::submit_task(submit_task).await?)
-                }
-            }
+    // Submit answers
+    let result = session.submit_answer(solved.answers.as_slice()).await;
+    let verdict = match &result {
+        Ok(_) => Some(Verdict::Solved),
+        // The rejected answers count as failures of the solvers
+        Err(ArkoseError::FuncaptchaNotSolvedError(_)) => {
+            arkose_solver.rejected(&solved);
+            Some(Verdict::Rejected)
         }
+        Err(_) => None,
     };
 
-    // Submit answers
-    let _ = session.submit_answer(answers.as_slice()).await?;
-
-    // Store funcaptcha solved image
-    if let Some(dir) = with_context!(arkose_solver_image_dir) {
-        tokio::spawn(session.save_funcaptcha_to_dir(dir, answers));
+    // Store funcaptcha images with the verdict of the answers
+    if let (Some(dir), Some(verdict)) = (with_context!(arkose_solver_image_dir), verdict) {
+        tokio::spawn(session.save_funcaptcha_to_dir(dir, solved.answers, verdict));
     }
+    result?;
 
     let new_token = ctx.arkose_token.value().replace("at=40", "at=40|sup=1");
     Ok(ArkoseToken::from(new_token))
@@ -53,6 +53,11 @@ pub struct AuthClient {
 }
 
 impl AuthClient {
+    /// Get the http client, e.g. to probe the upstream through the pool member
+    pub(crate) fn http(&self) -> &Client {
+        &self.inner
+    }
+
     pub async fn refresh_session(&self, session: &str) -> AuthResult<model::AccessToken> {
         let resp = self
             .inner
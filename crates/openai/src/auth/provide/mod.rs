@@ -112,14 +112,19 @@ impl<'a> RequestContext<'a> {
     async fn load_arkose_token(&mut self) -> AuthResult<()> {
         let arkose_token = match self.account.arkose_token.as_deref() {
             Some(arkose_token) => ArkoseToken::from(arkose_token),
-            None => arkose::ArkoseToken::new_from_context(
-                ArkoseContext::builder()
-                    .client(with_context!(arkose_client))
-                    .typed(Type::Auth)
-                    .build(),
-            )
-            .await
-            .map_err(AuthError::InvalidArkoseToken)?,
+            None => {
+                let (client, health) =
+                    with_context!(checkout_arkose_client, Some(self.account.username.as_str()));
+                arkose::ArkoseToken::acquire(
+                    ArkoseContext::builder()
+                        .client(client)
+                        .health(health)
+                        .typed(Type::Auth)
+                        .build(),
+                )
+                .await
+                .map_err(AuthError::InvalidArkoseToken)?
+            }
         };
 
         self.cookie
@@ -7,6 +7,7 @@ pub mod resp;
 /// - `System`, for starting system message, that sets the tone of model
 /// - `Assistant`, for messages sent by ChatGPT
 /// - `User`, for messages sent by user
+/// - `Tool`, for messages sent by tools (browsing, plugins or function results)
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize, Eq, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
@@ -18,6 +19,9 @@ pub enum Role {
     User,
     /// A system message
     Critic,
+    /// A message sent by a tool
+    #[serde(alias = "function")]
+    Tool,
 }
 
 impl ToString for Role {
@@ -27,6 +31,7 @@ impl ToString for Role {
             Role::Assistant => "assistant".to_string(),
             Role::User => "user".to_string(),
             Role::Critic => "critic".to_string(),
+            Role::Tool => "tool".to_string(),
         }
     }
 }
@@ -7,13 +7,37 @@ use typed_builder::TypedBuilder;
 #[derive(Serialize, TypedBuilder)]
 pub struct Content<'a> {
     content_type: ContentText,
-    parts: Vec<&'a str>,
+    parts: Vec<Part<'a>>,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ContentText {
     Text,
+    #[serde(rename = "multimodal_text")]
+    MultimodalText,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Part<'a> {
+    Text(&'a str),
+    Image(ImagePart),
+}
+
+impl<'a> From<&'a str> for Part<'a> {
+    fn from(value: &'a str) -> Self {
+        Part::Text(value)
+    }
+}
+
+#[derive(Serialize, TypedBuilder, Clone)]
+pub struct ImagePart {
+    /// The uploaded file pointer, e.g. file-service://file-xxx
+    asset_pointer: String,
+    size_bytes: usize,
+    width: usize,
+    height: usize,
 }
 
 #[derive(Serialize, TypedBuilder)]
@@ -24,8 +48,22 @@ pub struct Messages<'a> {
     metadata: Metadata,
 }
 
-#[derive(Serialize)]
-pub struct Metadata {}
+#[derive(Serialize, Default)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Serialize, TypedBuilder, Clone)]
+pub struct Attachment {
+    id: String,
+    name: String,
+    size: usize,
+    #[serde(rename = "mimeType")]
+    mime_type: String,
+    width: usize,
+    height: usize,
+}
 
 #[derive(Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -124,10 +162,10 @@ impl<'a> From<PostNextConvoRequest<'a>> for PostConvoRequest<'a> {
                 .content(
                     Content::builder()
                         .content_type(ContentText::Text)
-                        .parts(vec![value.prompt])
+                        .parts(vec![value.prompt.into()])
                         .build(),
                 )
-                .metadata(Metadata {})
+                .metadata(Metadata::default())
                 .build()])
             .model(value.model)
             .conversation_id(value.conversation_id)
@@ -161,10 +199,10 @@ impl<'a> From<PostVaraintConvoRequest<'a>> for PostConvoRequest<'a> {
                 .content(
                     Content::builder()
                         .content_type(ContentText::Text)
-                        .parts(vec![value.prompt])
+                        .parts(vec![value.prompt.into()])
                         .build(),
                 )
-                .metadata(Metadata {})
+                .metadata(Metadata::default())
                 .build()])
             .model(value.model)
             .arkose_token(value.arkose_token)
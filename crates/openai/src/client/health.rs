@@ -0,0 +1,355 @@
+use reqwest::header::{HeaderMap, CONTENT_TYPE, SERVER};
+use reqwest::{Response, StatusCode};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::auth::{error::AuthError, provide::AuthResult};
+use crate::{info, warn};
+
+/// Maximum ejection backoff
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Pool member ejection policy
+#[derive(Clone, Copy, Debug)]
+pub struct HealthPolicy {
+    /// Consecutive failures to eject the member, 0 disables the ejection
+    pub threshold: u32,
+    /// Ejection backoff, doubled on every consecutive ejection
+    pub backoff: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    consecutive_failures: u32,
+    ejections: u32,
+    last_error: Option<String>,
+    successes: u64,
+    failures: u64,
+}
+
+/// Ejection deadlines of the pool members, shared by the members so the last healthy one is never ejected
+struct Ejections(Mutex<Vec<Option<Instant>>>);
+
+impl Ejections {
+    fn lock(&self) -> MutexGuard<'_, Vec<Option<Instant>>> {
+        self.0.lock().expect("member ejections lock poisoned")
+    }
+}
+
+/// Pool member health snapshot
+#[derive(Serialize)]
+pub struct HealthStatus {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub ejections: u32,
+    /// Remaining ejection (seconds)
+    pub ejected_secs: Option<u64>,
+    pub last_error: Option<String>,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+/// Pool member health, fed by the active probes and the outcomes of the requests
+pub struct MemberHealth {
+    label: String,
+    policy: HealthPolicy,
+    /// Member index in the pool ejections
+    index: usize,
+    ejections: Arc<Ejections>,
+    state: Mutex<State>,
+}
+
+impl MemberHealth {
+    /// Build the health of the pool members
+    pub fn pool(labels: Vec<String>, policy: HealthPolicy) -> Vec<Arc<Self>> {
+        let ejections = Arc::new(Ejections(Mutex::new(vec![None; labels.len()])));
+        labels
+            .into_iter()
+            .enumerate()
+            .map(|(index, label)| {
+                Arc::new(Self {
+                    label,
+                    policy,
+                    index,
+                    ejections: ejections.clone(),
+                    state: Mutex::new(State::default()),
+                })
+            })
+            .collect()
+    }
+
+    /// Member name in logs
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("member health lock poisoned")
+    }
+
+    /// Check if the member is ejected, the backoff is not elapsed yet
+    fn is_ejected(until: Option<Instant>, now: Instant) -> bool {
+        until.is_some_and(|until| until > now)
+    }
+
+    /// Get the ejection deadline of the member
+    fn ejected_until(&self) -> Option<Instant> {
+        self.ejections.lock()[self.index]
+    }
+
+    /// Check if the member takes requests, an ejected member is retried once the backoff elapses
+    pub fn is_available(&self) -> bool {
+        !Self::is_ejected(self.ejected_until(), Instant::now())
+    }
+
+    /// Report a successful request, the member is reinstated
+    pub fn success(&self) {
+        let mut state = self.lock();
+        state.successes += 1;
+        state.consecutive_failures = 0;
+        state.ejections = 0;
+        if self.ejections.lock()[self.index].take().is_some() {
+            info!("Client pool member `{}` recovered", self.label);
+        }
+    }
+
+    /// Report a failed request, the member is ejected once the failures reach the threshold.
+    /// A retried member that fails again is ejected with a doubled backoff, the last healthy member is kept.
+    pub fn failure(&self, reason: impl ToString) {
+        let mut state = self.lock();
+        let now = Instant::now();
+        state.failures += 1;
+        state.consecutive_failures += 1;
+        state.last_error = Some(reason.to_string());
+
+        if self.policy.threshold == 0 || state.consecutive_failures < self.policy.threshold {
+            return;
+        }
+
+        let mut ejections = self.ejections.lock();
+        if Self::is_ejected(ejections[self.index], now) {
+            return;
+        }
+
+        // A pool without members fails every request, the last healthy member takes them instead
+        let healthy = ejections
+            .iter()
+            .filter(|until| !Self::is_ejected(**until, now))
+            .count();
+        if healthy <= 1 {
+            if state.consecutive_failures == self.policy.threshold {
+                warn!(
+                    "Client pool member `{}` is the last healthy member, it is not ejected: {}",
+                    self.label,
+                    state.last_error.as_deref().unwrap_or_default()
+                );
+            }
+            return;
+        }
+
+        let backoff = self
+            .policy
+            .backoff
+            .saturating_mul(1 << state.ejections.min(16))
+            .min(MAX_BACKOFF);
+        state.ejections += 1;
+        ejections[self.index] = Some(now + backoff);
+        drop(ejections);
+        warn!(
+            "Client pool member `{}` ejected for {}s after {} consecutive failures: {}",
+            self.label,
+            backoff.as_secs(),
+            state.consecutive_failures,
+            state.last_error.as_deref().unwrap_or_default()
+        );
+    }
+
+    /// Report the outcome of a request, connect errors, timeouts, server errors and Cloudflare challenges are failures.
+    /// The other statuses, e.g. 403 of a banned account, say nothing about the member
+    pub fn observe(&self, result: &Result<Response, reqwest::Error>) {
+        self.report(result)
+    }
+
+    /// Report the outcome of an auth request, the account errors are ignored
+    pub fn observe_auth<T>(&self, result: &AuthResult<T>) {
+        match result {
+            Ok(_) => self.success(),
+            Err(AuthError::FailedRequest(err)) if err.is_connect() || err.is_timeout() => {
+                self.failure(err)
+            }
+            Err(AuthError::ServerError(err)) => self.failure(err),
+            Err(_) => {}
+        }
+    }
+
+    /// Report the outcome of an active probe, an ejected member waits out its backoff
+    pub(super) fn probed(&self, result: &Result<Response, reqwest::Error>) {
+        if !self.is_available() {
+            return;
+        }
+        self.report(result)
+    }
+
+    fn report(&self, result: &Result<Response, reqwest::Error>) {
+        match result {
+            Ok(resp) => match failure_reason(resp.status(), resp.headers()) {
+                Some(reason) => self.failure(reason),
+                None => self.success(),
+            },
+            Err(err) if err.is_connect() || err.is_timeout() => self.failure(err),
+            Err(_) => {}
+        }
+    }
+
+    /// Get the health snapshot
+    pub fn status(&self) -> HealthStatus {
+        let state = self.lock();
+        let now = Instant::now();
+        let ejected_until = self.ejected_until();
+        HealthStatus {
+            healthy: !Self::is_ejected(ejected_until, now),
+            consecutive_failures: state.consecutive_failures,
+            ejections: state.ejections,
+            ejected_secs: ejected_until
+                .filter(|until| *until > now)
+                .map(|until| (until - now).as_secs()),
+            last_error: state.last_error.clone(),
+            successes: state.successes,
+            failures: state.failures,
+        }
+    }
+}
+
+/// Get the failure reason of the upstream response.
+/// A 403 only counts with the Cloudflare challenge markers, a JSON 403 of a banned account is not the member's fault
+fn failure_reason(status: StatusCode, headers: &HeaderMap) -> Option<String> {
+    let challenged = headers
+        .get("cf-mitigated")
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"challenge"));
+    if challenged {
+        return Some("Cloudflare challenge".to_owned());
+    }
+
+    // The challenge page is HTML served by Cloudflare, the API errors are JSON
+    let cloudflare_page = headers
+        .get(SERVER)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"cloudflare"))
+        && headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/html"));
+    if status == StatusCode::FORBIDDEN && cloudflare_page {
+        return Some(format!("{status} Cloudflare challenge"));
+    }
+
+    status.is_server_error().then(|| status.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(threshold: u32, size: usize) -> Vec<Arc<MemberHealth>> {
+        MemberHealth::pool(
+            (0..size).map(|i| format!("member-{i}")).collect(),
+            HealthPolicy {
+                threshold,
+                backoff: Duration::from_secs(30),
+            },
+        )
+    }
+
+    #[test]
+    fn test_ejection() {
+        let pool = pool(2, 2);
+        let h = &pool[0];
+        h.failure("connect error");
+        assert!(h.is_available());
+        h.failure("connect error");
+        assert!(!h.is_available());
+        assert_eq!(h.status().ejections, 1);
+        assert!(h.status().ejected_secs.is_some_and(|s| s <= 30));
+
+        // Failures during the ejection do not extend it
+        h.failure("connect error");
+        assert_eq!(h.status().ejections, 1);
+
+        h.success();
+        let status = h.status();
+        assert!(status.healthy);
+        assert_eq!((status.consecutive_failures, status.ejections), (0, 0));
+        assert_eq!((status.successes, status.failures), (1, 3));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let pool = pool(1, 2);
+        let h = &pool[0];
+        h.failure("502 Bad Gateway");
+        // Elapse the backoff, the retried member is ejected again with a doubled backoff
+        h.ejections.lock()[h.index] = Some(Instant::now());
+        assert!(h.is_available());
+        h.failure("502 Bad Gateway");
+        assert!(h.status().ejected_secs.is_some_and(|s| s > 30 && s <= 60));
+    }
+
+    #[test]
+    fn test_last_member() {
+        let members = pool(1, 2);
+        members[0].failure("connect error");
+        assert!(!members[0].is_available());
+        // The last healthy member is kept
+        members[1].failure("connect error");
+        assert!(members[1].is_available());
+        assert_eq!(members[1].status().ejections, 0);
+
+        let single = pool(1, 1);
+        (0..3).for_each(|_| single[0].failure("connect error"));
+        assert!(single[0].is_available());
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_failure_reason_challenge() {
+        let challenge = headers(&[("cf-mitigated", "challenge")]);
+        assert!(failure_reason(StatusCode::FORBIDDEN, &challenge).is_some());
+        assert!(failure_reason(StatusCode::OK, &challenge).is_some());
+    }
+
+    #[test]
+    fn test_failure_reason_forbidden() {
+        let page = headers(&[
+            ("server", "cloudflare"),
+            ("content-type", "text/html; charset=UTF-8"),
+        ]);
+        assert!(failure_reason(StatusCode::FORBIDDEN, &page).is_some());
+
+        // A banned account is a JSON 403, it says nothing about the member
+        let banned = headers(&[
+            ("server", "cloudflare"),
+            ("content-type", "application/json"),
+        ]);
+        assert!(failure_reason(StatusCode::FORBIDDEN, &banned).is_none());
+        assert!(failure_reason(StatusCode::FORBIDDEN, &HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn test_failure_reason_server_error() {
+        assert!(failure_reason(StatusCode::BAD_GATEWAY, &HeaderMap::new()).is_some());
+        assert!(failure_reason(StatusCode::NOT_FOUND, &HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let pool = pool(0, 2);
+        (0..10).for_each(|_| pool[0].failure("connect error"));
+        assert!(pool[0].is_available());
+    }
+}
@@ -1,12 +1,16 @@
+mod health;
+
 use crate::auth::{self};
 use crate::context::args::Args;
 use crate::dns::{self, TrustDnsResolver};
 use crate::{
     auth::AuthClient,
     proxy::{self, Ipv6CidrExt},
+    URL_CHATGPT_API,
 };
 use moka::sync::Cache;
 use reqwest::{impersonate::Impersonate, Client};
+use serde::Serialize;
 use std::sync::{Arc, OnceLock};
 use std::{
     net::IpAddr,
@@ -16,6 +20,8 @@ use std::{
 use trust_dns_resolver::config::LookupIpStrategy;
 use url::Url;
 
+pub use self::health::{HealthPolicy, HealthStatus, MemberHealth};
+
 /// Client type
 #[derive(Clone)]
 pub enum ClientAgent {
@@ -71,6 +77,12 @@ impl LookupIpStrategyExt {
 
 static DNS_RESOLVER: OnceLock<Cache<LookupIpStrategyExt, Arc<TrustDnsResolver>>> = OnceLock::new();
 
+/// Maximum sticky IPv6 clients of the affinity mode
+const STICKY_CLIENT_CAPACITY: u64 = 4096;
+
+/// Idle time of a sticky IPv6 client
+const STICKY_CLIENT_IDLE: Duration = Duration::from_secs(1800);
+
 struct Config {
     /// Use fastest DNS resolver
     fastest_dns: bool,
@@ -114,10 +126,41 @@ impl Config {
     }
 }
 
+/// Client pool entry
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientEntry {
+    /// Bind address
+    pub bind: Option<IpAddr>,
+    /// Upstream proxy
+    pub proxy: Option<Url>,
+}
+
+impl ClientEntry {
+    /// Member name in logs, the proxy credentials are omitted
+    fn label(&self) -> String {
+        match (&self.proxy, self.bind) {
+            (Some(proxy), _) => format!(
+                "{}://{}:{}",
+                proxy.scheme(),
+                proxy.host_str().unwrap_or_default(),
+                proxy.port_or_known_default().unwrap_or_default()
+            ),
+            (None, Some(bind)) => format!("direct@{bind}"),
+            (None, None) => "direct".to_owned(),
+        }
+    }
+}
+
 /// Client round robin balancer
 pub struct ClientRoundRobinBalancer {
     config: Config,
     pool: (AtomicUsize, Vec<ClientAgent>),
+    entries: Vec<ClientEntry>,
+    health: Vec<Arc<MemberHealth>>,
+    /// Stick the account to a pool member, or a stable IPv6 of the subnets
+    affinity: bool,
+    /// Sticky IPv6 clients
+    sticky: Cache<IpAddr, ClientAgent>,
 }
 
 impl ClientRoundRobinBalancer {
@@ -200,9 +243,14 @@ impl ClientRoundRobinBalancer {
 
         // init client pool
         let mut pool = Vec::with_capacity(proxies.len() + 1);
+        let mut entries = Vec::with_capacity(proxies.len() + 1);
 
         // Helper function to join client to the pool
         let mut join_client = |bind: Option<IpAddr>, proxy: Option<Url>| {
+            entries.push(ClientEntry {
+                bind,
+                proxy: proxy.clone(),
+            });
             let client = build_fn(&config, bind, None, proxy, args.no_keepalive);
             pool.push(client_type(client));
         };
@@ -230,6 +278,10 @@ impl ClientRoundRobinBalancer {
 
         // Join a default client to the pool if it's still empty
         if pool.is_empty() {
+            entries.push(ClientEntry {
+                bind: None,
+                proxy: None,
+            });
             pool.push(client_type(build_fn(
                 &config,
                 None,
@@ -239,17 +291,34 @@ impl ClientRoundRobinBalancer {
             )));
         }
 
+        // init pool member health
+        let policy = HealthPolicy {
+            threshold: args.proxy_eject_threshold,
+            backoff: Duration::from_secs(args.proxy_eject_backoff.max(1).into()),
+        };
+        let health = MemberHealth::pool(entries.iter().map(ClientEntry::label).collect(), policy);
+
         Ok(Self {
             config,
             pool: (AtomicUsize::new(0), pool),
+            entries,
+            health,
+            affinity: args.proxy_affinity,
+            sticky: Cache::builder()
+                .max_capacity(STICKY_CLIENT_CAPACITY)
+                .time_to_idle(STICKY_CLIENT_IDLE)
+                .build(),
         })
     }
 }
 
 impl ClientRoundRobinBalancer {
     /// rebuild client with ipv6
-    fn rebuild_client_with_ipv6(&self, client: &ClientAgent) -> ClientAgent {
-        let bind_addr = self.config.get_next_ipv6();
+    fn rebuild_client_with_ipv6(
+        &self,
+        client: &ClientAgent,
+        bind_addr: Option<IpAddr>,
+    ) -> ClientAgent {
         // if interface is not specified, use fallback bind address
         let fallback_bind_addr = self.config.get_next_interface();
         match client {
@@ -277,19 +346,110 @@ impl ClientRoundRobinBalancer {
         }
     }
 
+    /// Get the client pool entries
+    pub fn entries(&self) -> impl Iterator<Item = (&ClientEntry, &ClientAgent, &MemberHealth)> {
+        self.entries
+            .iter()
+            .zip(self.pool.1.iter())
+            .zip(self.health.iter())
+            .map(|((entry, agent), health)| (entry, agent, health.as_ref()))
+    }
+
+    /// Get the number of the healthy members and the pool size
+    pub fn healthy(&self) -> (usize, usize) {
+        let healthy = self.health.iter().filter(|h| h.is_available()).count();
+        (healthy, self.health.len())
+    }
+
+    /// Probe the upstream through each member
+    pub async fn probe(&self) {
+        for (agent, health) in self.pool.1.iter().zip(self.health.iter()) {
+            let client = match agent {
+                ClientAgent::Api(client) | ClientAgent::Arkose(client) => client,
+                ClientAgent::Auth(client) => client.http(),
+            };
+            health.probed(&client.head(URL_CHATGPT_API).send().await);
+        }
+    }
+
+    /// Get the IPv6 subnets to bind to
+    pub fn ipv6_subnets(&self) -> &[cidr::Ipv6Cidr] {
+        &self.config.ipv6_subnets.1
+    }
+
+    /// Check if the affinity mode is enabled
+    pub fn affinity(&self) -> bool {
+        self.affinity
+    }
+
     /// Get next client
     pub fn next(&self) -> ClientAgent {
+        self.checkout(None).0
+    }
+
+    /// Get next client with the health of its member, the ejected members are skipped unless all of them are ejected.
+    /// In the affinity mode, the account always gets the same member, or the same IPv6 of the subnets.
+    pub fn checkout(&self, account: Option<&str>) -> (ClientAgent, Arc<MemberHealth>) {
+        let account = account.filter(|_| self.affinity).map(str::to_lowercase);
+
         // if there is only one client, return it
         if self.pool.1.len() == 1 {
             let client = self.pool.1.first().expect("Init client failed");
+            let health = self.health[0].clone();
             if !self.config.ipv6_subnets.1.is_empty() {
-                return self.rebuild_client_with_ipv6(client);
+                let client = match account {
+                    Some(account) => self.sticky_client_with_ipv6(client, &account),
+                    None => self.rebuild_client_with_ipv6(client, self.config.get_next_ipv6()),
+                };
+                return (client, health);
             }
-            return client.clone();
+            return (client.clone(), health);
         }
 
-        let new = get_next_index(self.pool.1.len(), &self.pool.0);
-        self.pool.1[new].clone()
+        let new = match account {
+            Some(account) => self.sticky_index(&account),
+            None => self.next_index(),
+        };
+        (self.pool.1[new].clone(), self.health[new].clone())
+    }
+
+    /// Round robin over the available members
+    fn next_index(&self) -> usize {
+        let len = self.pool.1.len();
+        let mut new = get_next_index(len, &self.pool.0);
+        for _ in 1..len {
+            if self.health[new].is_available() {
+                break;
+            }
+            new = get_next_index(len, &self.pool.0);
+        }
+        new
+    }
+
+    /// Rendezvous hashing of the account over the members, the member with the next highest score
+    /// takes over while the preferred one is ejected, and the pool changes only move the accounts of the changed members
+    fn sticky_index(&self, account: &str) -> usize {
+        let mut members = self
+            .health
+            .iter()
+            .enumerate()
+            .map(|(i, h)| (affinity_hash(&[account, h.label()]), i))
+            .collect::<Vec<_>>();
+        members.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        members
+            .iter()
+            .map(|(_, i)| *i)
+            .find(|i| self.health[*i].is_available())
+            .unwrap_or(members[0].1)
+    }
+
+    /// Get the client bound to the stable IPv6 of the account
+    fn sticky_client_with_ipv6(&self, client: &ClientAgent, account: &str) -> ClientAgent {
+        let hash = affinity_hash(&[account]);
+        let subnets = &self.config.ipv6_subnets.1;
+        let addr = subnets[(hash % subnets.len() as u64) as usize].hashed_ipv6(hash);
+        self.sticky
+            .get_with(addr, || self.rebuild_client_with_ipv6(client, Some(addr)))
     }
 }
 
@@ -408,6 +568,19 @@ fn build_auth_client(
         .build()
 }
 
+/// FNV-1a with a splitmix64 finalizer, stable across the restarts and the instances
+fn affinity_hash(parts: &[&str]) -> u64 {
+    let hash = parts
+        .iter()
+        .flat_map(|p| p.bytes().chain(std::iter::once(0xff)))
+        .fold(0xcbf29ce484222325_u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+    let hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
 // get next index for round robin
 fn get_next_index(len: usize, counter: &AtomicUsize) -> usize {
     let mut old = counter.load(Ordering::Relaxed);
@@ -458,3 +631,30 @@ fn random_impersonate(impersonate_uas: Option<&Vec<Impersonate>>) -> Impersonate
     // otherwise, randomly select one from the default list
     Impersonate::OkHttp4_9
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cidr::{Cidr, Ipv6Cidr};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_affinity_hash() {
+        assert_eq!(
+            affinity_hash(&["a@example.com", "socks5://10.0.0.1:1080"]),
+            affinity_hash(&["a@example.com", "socks5://10.0.0.1:1080"])
+        );
+        // The parts are separated, so the boundaries matter
+        assert_ne!(affinity_hash(&["ab", "c"]), affinity_hash(&["a", "bc"]));
+    }
+
+    #[test]
+    fn test_hashed_ipv6() {
+        let subnet = Ipv6Cidr::from_str("2001:db8::/32").unwrap();
+        let hash = affinity_hash(&["a@example.com"]);
+        let addr = subnet.hashed_ipv6(hash);
+        assert_eq!(addr, subnet.hashed_ipv6(hash));
+        assert_ne!(addr, subnet.hashed_ipv6(affinity_hash(&["b@example.com"])));
+        assert!(subnet.contains(&addr.to_string().parse().unwrap()));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{arkose::funcaptcha::solver::ArkoseSolver, proxy};
+use crate::{arkose::funcaptcha::solver::ArkoseSolver, gpt_model::ModelEntry, proxy};
 use reqwest::impersonate::Impersonate;
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
@@ -48,6 +48,22 @@ pub struct Args {
     #[builder(default = false)]
     pub(crate) enable_direct: bool,
 
+    /// Client pool health check interval (seconds), 0 disables the active probes
+    #[builder(setter(into), default = 60)]
+    pub(crate) proxy_health_interval: u32,
+
+    /// Consecutive failures to eject a client pool member, 0 disables the ejection
+    #[builder(setter(into), default = 3)]
+    pub(crate) proxy_eject_threshold: u32,
+
+    /// Ejection backoff of a client pool member (seconds), doubled on every consecutive ejection
+    #[builder(setter(into), default = 30)]
+    pub(crate) proxy_eject_backoff: u32,
+
+    /// Stick each account to a client pool member, or a stable IPv6 of the subnets
+    #[builder(default = false)]
+    pub(crate) proxy_affinity: bool,
+
     /// Client proxies
     #[builder(setter(into), default)]
     pub(crate) proxies: Vec<proxy::Proxy>,
@@ -64,14 +80,38 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) tls_key: Option<PathBuf>,
 
+    /// Redis url, the shared state of the instances is stored in redis
+    #[builder(setter(into), default)]
+    pub(crate) redis_url: Option<String>,
+
+    /// Trusted reverse proxies, the forwarded headers are only honored from these networks
+    #[builder(setter(into), default)]
+    pub(crate) trusted_proxies: Vec<cidr::IpCidr>,
+
+    /// Require the HAProxy PROXY protocol (v1/v2) header on the listener
+    #[builder(default = false)]
+    pub(crate) proxy_protocol: bool,
+
     /// Visitor email whitelist
     #[builder(setter(into), default)]
     pub(super) visitor_email_whitelist: Option<Vec<String>>,
 
-    /// Login auth key
+    /// Visitor email deny list, takes precedence over the whitelist
+    #[builder(setter(into), default)]
+    pub(super) visitor_email_denylist: Option<Vec<String>>,
+
+    /// Visitor email whitelist file, one rule per line, reloaded on changes
+    #[builder(setter(into), default)]
+    pub(crate) visitor_email_whitelist_file: Option<PathBuf>,
+
+    /// Login auth key, granted the login/arkose/HAR/metrics route groups
     #[builder(setter(into), default)]
     pub(super) auth_key: Option<String>,
 
+    /// Scoped auth keys
+    #[builder(setter(into), default)]
+    pub(super) auth_keys: Vec<crate::context::authkey::AuthKey>,
+
     /// Enable webui
     #[builder(setter(into), default = false)]
     pub(crate) enable_webui: bool,
@@ -84,6 +124,50 @@ pub struct Args {
     #[builder(default = false)]
     pub(crate) enable_arkose_proxy: bool,
 
+    /// Enable Prometheus metrics endpoint
+    #[builder(setter(into), default = false)]
+    pub(crate) enable_metrics: bool,
+
+    /// Enable conversation continuity of the OpenAI-compatible endpoint
+    #[builder(setter(into), default = false)]
+    pub(crate) enable_conversation_continuity: bool,
+
+    /// Conversation continuity expired (second)
+    #[builder(setter(into), default = 86400)]
+    pub(crate) conversation_expired: u32,
+
+    /// Account pool API key of the OpenAI-compatible endpoint
+    #[builder(setter(into), default)]
+    pub(crate) account_pool_key: Option<String>,
+
+    /// Account pool tokens file path (JSON array)
+    #[builder(setter(into), default)]
+    pub(crate) account_pool_file: Option<PathBuf>,
+
+    /// Cooldown of the rate limited pool account (second)
+    #[builder(setter(into), default = 3600)]
+    pub(crate) account_pool_cooldown: u32,
+
+    /// Enable the background token refresher of the account pool
+    #[builder(setter(into), default = false)]
+    pub(crate) enable_token_refresh: bool,
+
+    /// Token refresh scan interval (second)
+    #[builder(setter(into), default = 600)]
+    pub(crate) token_refresh_interval: u32,
+
+    /// Refresh the token that expires within the window (second)
+    #[builder(setter(into), default = 86400)]
+    pub(crate) token_refresh_window: u32,
+
+    /// Token refresh failed webhook url
+    #[builder(setter(into), default)]
+    pub(crate) token_refresh_webhook: Option<String>,
+
+    /// GPT model table, the built-in table is used if empty
+    #[builder(setter(into), default)]
+    pub(crate) gpt_models: Vec<ModelEntry>,
+
     /// Cloudflare captcha site key
     #[builder(setter(into), default)]
     pub(crate) cf_site_key: Option<String>,
@@ -108,9 +192,13 @@ pub struct Args {
     #[builder(setter(into), default = false)]
     pub(crate) arkose_gpt3_experiment_solver: bool,
 
-    /// arkoselabs solver
+    /// arkoselabs solvers, tried in order
     #[builder(setter(into), default)]
-    pub(crate) arkose_solver: Option<ArkoseSolver>,
+    pub(crate) arkose_solvers: Vec<ArkoseSolver>,
+
+    /// Solver retries of a funcaptcha, each retry fails over to the next solver
+    #[builder(setter(into), default = 2)]
+    pub(crate) arkose_solver_retries: usize,
 
     /// About the solver tguess endpoint by ArkoseLabs
     #[builder(setter(into), default)]
@@ -120,6 +208,22 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) arkose_solver_image_dir: Option<PathBuf>,
 
+    /// Arkose token pre-fetch pool size per type, 0 disables the pool
+    #[builder(setter(into), default = 0)]
+    pub(crate) arkose_pool_size: usize,
+
+    /// Arkose token pre-fetch pool types, gpt3, auth and platform if empty
+    #[builder(setter(into), default)]
+    pub(crate) arkose_pool_types: Vec<crate::arkose::Type>,
+
+    /// Arkose token pre-fetch pool token TTL (seconds)
+    #[builder(setter(into), default = 120)]
+    pub(crate) arkose_pool_ttl: u32,
+
+    /// Arkose token pre-fetch pool refill concurrency
+    #[builder(setter(into), default = 2)]
+    pub(crate) arkose_pool_concurrency: usize,
+
     /// Enable Tokenbucket
     #[cfg(feature = "limit")]
     #[builder(setter(into), default = false)]
@@ -145,6 +249,21 @@ pub struct Args {
     #[builder(setter(into), default = 86400)]
     pub(crate) tb_expired: u32,
 
+    /// Tokenbucket limit key (ip/email/auth_key)
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default = "ip".to_string())]
+    pub(crate) tb_key: String,
+
+    /// Tokenbucket rules of the specific key, format: key=capacity/fill_rate
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default)]
+    pub(crate) tb_rules: Vec<String>,
+
+    /// Route group limit policies (sliding window/quota/model cap)
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default)]
+    pub(crate) limit_policies: Vec<crate::serve::LimitPolicy>,
+
     /// Preauth MITM server bind address
     #[cfg(feature = "preauth")]
     #[builder(setter(into), default)]
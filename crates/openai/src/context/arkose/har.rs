@@ -216,6 +216,19 @@ pub fn get_entry(_type: &arkose::Type) -> anyhow::Result<RequestEntry> {
     }
 }
 
+/// List the HAR files of each type
+pub fn list_files() -> anyhow::Result<HashMap<Type, Vec<String>>> {
+    let lock = HAR
+        .get()
+        .map(|s| s.read().ok())
+        .flatten()
+        .ok_or_else(|| anyhow!("Failed to get har lock"))?;
+    Ok(lock
+        .iter()
+        .map(|(_type, h)| (*_type, h.pool.1.clone()))
+        .collect())
+}
+
 /// Read dir
 pub async fn read_dir(_type: &Type) -> Result<ReadDir> {
     let path = get_har_path(_type)?;
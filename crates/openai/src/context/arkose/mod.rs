@@ -1,11 +1,17 @@
 pub mod har;
+pub mod pool;
 pub mod version;
 
 use self::version::ArkoseVersion;
 use crate::arkose::Type;
+#[cfg(feature = "redis")]
+use crate::context::store;
 use crate::homedir::home_dir;
+use crate::with_context;
 use moka::sync::Cache;
 use native_db::{Database, DatabaseBuilder};
+#[cfg(feature = "redis")]
+use redis::AsyncCommands;
 use std::path::PathBuf;
 use std::sync::{Arc, OnceLock};
 use std::time::Duration;
@@ -82,7 +88,7 @@ impl ArkoseVersionContext<'_> {
     }
 
     /// Upgrade the arkose version
-    async fn upgrade(&self) {
+    pub async fn upgrade(&self) {
         // Auth
         self.insert_version(Type::Auth).await;
         // GPT-4
@@ -100,7 +106,7 @@ impl ArkoseVersionContext<'_> {
     }
 
     async fn insert_version(&self, version_type: Type) {
-        match version::latest_arkose_version(version_type).await {
+        match self.latest_version(version_type).await {
             Ok(version) => {
                 if let Ok(rw) = self.db.rw_transaction() {
                     if let Some(err) = rw.insert(version).err() {
@@ -116,4 +122,36 @@ impl ArkoseVersionContext<'_> {
             }
         }
     }
+
+    /// Get the latest version
+    #[cfg(not(feature = "redis"))]
+    async fn latest_version(&self, version_type: Type) -> anyhow::Result<ArkoseVersion> {
+        version::latest_arkose_version(version_type).await
+    }
+
+    /// Get the latest version, the version shared by the other instances is preferred
+    #[cfg(feature = "redis")]
+    async fn latest_version(&self, version_type: Type) -> anyhow::Result<ArkoseVersion> {
+        let redis = match with_context!(redis) {
+            Some(redis) => redis,
+            None => return version::latest_arkose_version(version_type).await,
+        };
+
+        let key = store::key("arkose_version", version_type.pk());
+        let mut conn = redis.connection().await?;
+        if let Some(shared) = conn.get::<_, Option<String>>(&key).await? {
+            if let Ok(version) = serde_json::from_str::<ArkoseVersion>(&shared) {
+                return Ok(version);
+            }
+        }
+
+        let version = version::latest_arkose_version(version_type).await?;
+        conn.set_ex::<_, _, ()>(
+            &key,
+            serde_json::to_string(&version)?,
+            INTERVAL_SECONDS.into(),
+        )
+        .await?;
+        Ok(version)
+    }
 }
@@ -0,0 +1,168 @@
+use crate::arkose::{ArkoseContext, ArkoseToken, Type};
+use crate::{info, warn, with_context};
+use futures::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Recheck interval of a full pool, a popped token wakes up the refill immediately
+const REFILL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay after a refill round without any token, e.g. no HAR file or solver is available
+const REFILL_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Pre-fetched arkose tokens of a type, oldest first
+type Queue = Mutex<VecDeque<(Instant, String)>>;
+
+/// Background pool of the solved arkose tokens per type.
+/// The pooled types are not bound to an account, the tokens carry no data blob and come from any client pool member.
+pub struct ArkoseTokenPool {
+    size: usize,
+    ttl: Duration,
+    concurrency: usize,
+    tokens: HashMap<Type, Queue>,
+    notify: Notify,
+}
+
+impl ArkoseTokenPool {
+    pub fn new(types: Vec<Type>, size: usize, ttl: Duration, concurrency: usize) -> Self {
+        Self {
+            size,
+            ttl,
+            concurrency: concurrency.max(1),
+            tokens: types
+                .into_iter()
+                .map(|typed| (typed, Mutex::new(VecDeque::with_capacity(size))))
+                .collect(),
+            notify: Notify::new(),
+        }
+    }
+
+    fn lock(queue: &Queue) -> MutexGuard<'_, VecDeque<(Instant, String)>> {
+        queue.lock().expect("arkose token pool lock poisoned")
+    }
+
+    /// Drop the expired tokens
+    fn purge(&self, queue: &mut VecDeque<(Instant, String)>) {
+        let now = Instant::now();
+        queue.retain(|(fetched, _)| now.duration_since(*fetched) < self.ttl);
+    }
+
+    /// Pop the oldest fresh token of the type
+    pub fn pop(&self, typed: Type) -> Option<ArkoseToken> {
+        let queue = self.tokens.get(&typed)?;
+        let token = {
+            let mut queue = Self::lock(queue);
+            self.purge(&mut queue);
+            queue.pop_front()
+        };
+        #[cfg(feature = "serve")]
+        metrics::increment_counter!(
+            "ninja_arkose_pool_total",
+            "type" => format!("{typed:?}").to_lowercase(),
+            "result" => if token.is_some() { "hit" } else { "miss" }
+        );
+        self.notify.notify_one();
+        token.map(|(_, token)| ArkoseToken::from(token))
+    }
+
+    /// Get the number of the fresh tokens by type
+    pub fn sizes(&self) -> HashMap<Type, usize> {
+        self.tokens
+            .iter()
+            .map(|(typed, queue)| {
+                let mut queue = Self::lock(queue);
+                self.purge(&mut queue);
+                (*typed, queue.len())
+            })
+            .collect()
+    }
+
+    /// Keep the pool filled, the tokens are fetched with the configured concurrency
+    pub async fn periodic_refill(&self) {
+        info!(
+            "Arkose token pool periodic task is running, {} tokens per type",
+            self.size
+        );
+        loop {
+            let jobs = self
+                .sizes()
+                .into_iter()
+                .flat_map(|(typed, len)| {
+                    std::iter::repeat(typed).take(self.size.saturating_sub(len))
+                })
+                .collect::<Vec<_>>();
+
+            if jobs.is_empty() {
+                tokio::select! {
+                    _ = self.notify.notified() => {}
+                    _ = tokio::time::sleep(REFILL_INTERVAL) => {}
+                }
+                continue;
+            }
+
+            let filled = futures::stream::iter(jobs)
+                .map(|typed| self.fetch(typed))
+                .buffer_unordered(self.concurrency)
+                .filter(|filled| futures::future::ready(*filled))
+                .count()
+                .await;
+
+            if filled == 0 {
+                tokio::time::sleep(REFILL_BACKOFF).await;
+            }
+        }
+    }
+
+    /// Fetch a token, only the solved tokens are pooled
+    async fn fetch(&self, typed: Type) -> bool {
+        let (client, health) = with_context!(checkout_arkose_client, None);
+        let ctx = ArkoseContext::builder()
+            .client(client)
+            .health(health)
+            .typed(typed)
+            .build();
+
+        match ArkoseToken::new_from_context(ctx).await {
+            Ok(token) if token.success() => {
+                if let Some(queue) = self.tokens.get(&typed) {
+                    Self::lock(queue).push_back((Instant::now(), token.into()));
+                }
+                true
+            }
+            Ok(_) => {
+                warn!("Arkose token pool: unsolved {typed:?} token is dropped");
+                false
+            }
+            Err(err) => {
+                warn!("Arkose token pool: failed to fetch {typed:?} token: {err}");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pop() {
+        let pool = ArkoseTokenPool::new(vec![Type::GPT3], 2, Duration::from_secs(60), 1);
+        let queue = pool.tokens.get(&Type::GPT3).unwrap();
+        {
+            let mut queue = ArkoseTokenPool::lock(queue);
+            queue.push_back((
+                Instant::now() - Duration::from_secs(120),
+                "expired".to_owned(),
+            ));
+            queue.push_back((Instant::now(), "fresh|sup=1".to_owned()));
+        }
+
+        assert_eq!(pool.sizes().get(&Type::GPT3), Some(&1));
+        assert_eq!(pool.pop(Type::GPT3).unwrap().value(), "fresh|sup=1");
+        assert!(pool.pop(Type::GPT3).is_none());
+        assert!(pool.pop(Type::Auth).is_none());
+    }
+}
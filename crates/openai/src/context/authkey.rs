@@ -0,0 +1,346 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use cidr::{Cidr, IpCidr};
+
+use crate::now_duration;
+
+/// Header carrying the auth key of the route groups authenticated by the access token
+pub const AUTH_KEY_HEADER: &str = "x-auth-key";
+
+/// Route group protected by the auth key
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// POST /auth/token and the WebUI login page
+    Login,
+    /// GET /auth/arkose_token
+    Arkose,
+    /// HAR upload UI
+    Har,
+    /// /admin/api/*
+    Admin,
+    /// GET /metrics
+    Metrics,
+    /// /backend-api/*, the key is carried by the `X-Auth-Key` header
+    BackendApi,
+    /// /v1/*, the key is carried by the `X-Auth-Key` header
+    V1,
+}
+
+impl Scope {
+    /// The route groups gated by the legacy global auth key
+    const LEGACY: [Scope; 4] = [Scope::Login, Scope::Arkose, Scope::Har, Scope::Metrics];
+
+    /// Get the scope of the request path authenticated by the access token
+    pub fn from_path(path: &str) -> Option<Scope> {
+        if path.starts_with("/backend-api") {
+            Some(Scope::BackendApi)
+        } else if path.starts_with("/v1") {
+            Some(Scope::V1)
+        } else {
+            None
+        }
+    }
+}
+
+/// Auth key entry
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuthKey {
+    /// Key name, used in logs and for the rate limit
+    pub name: String,
+    /// Key value
+    pub key: String,
+    /// Allowed route groups
+    pub scopes: Vec<Scope>,
+    /// Expiry (unix timestamp in seconds)
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Allowed client networks, any client is allowed if unset
+    #[serde(default)]
+    pub allow_ips: Option<Vec<IpCidr>>,
+    /// Maximum requests per minute
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+}
+
+/// Auth key rejection
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum AuthKeyDenied {
+    #[error("Auth Key required!")]
+    Required,
+    #[error("Authentication Key error")]
+    Invalid,
+    #[error("Authentication Key `{0}` is expired")]
+    Expired(String),
+    #[error("Authentication Key `{0}` is not allowed to access this route")]
+    ScopeNotAllowed(String),
+    #[error("Authentication Key `{0}` is not allowed from this address")]
+    IpNotAllowed(String),
+    #[error("Authentication Key `{0}` exceeded the rate limit")]
+    RateLimited(String),
+}
+
+/// Auth key table, the keys can be rotated at runtime
+#[derive(Default)]
+pub struct AuthKeys {
+    keys: RwLock<Vec<AuthKey>>,
+    /// Key name -> (minute, requests)
+    windows: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+impl AuthKeys {
+    /// Build the table, the legacy global auth key is granted the login, arkose, HAR and metrics route groups
+    pub fn new(keys: Vec<AuthKey>, legacy: Option<String>) -> Self {
+        Self {
+            keys: RwLock::new(Self::table(keys, legacy)),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn table(mut keys: Vec<AuthKey>, legacy: Option<String>) -> Vec<AuthKey> {
+        if let Some(key) = legacy {
+            keys.push(AuthKey {
+                name: "default".to_owned(),
+                key,
+                scopes: Scope::LEGACY.to_vec(),
+                expires_at: None,
+                allow_ips: None,
+                rate_limit: None,
+            })
+        }
+        keys
+    }
+
+    /// Replace all keys, e.g. when the config is reloaded
+    pub fn replace(&self, keys: Vec<AuthKey>, legacy: Option<String>) {
+        *self.write() = Self::table(keys, legacy);
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Vec<AuthKey>> {
+        self.keys.read().expect("auth keys lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Vec<AuthKey>> {
+        self.keys.write().expect("auth keys lock poisoned")
+    }
+
+    /// Check if the route group is protected, a route group is protected once any key grants it
+    pub fn is_protected(&self, scope: Scope) -> bool {
+        self.read().iter().any(|k| k.scopes.contains(&scope))
+    }
+
+    /// Get the first key granting the route group
+    pub fn first(&self, scope: Scope) -> Option<AuthKey> {
+        self.read()
+            .iter()
+            .find(|k| k.scopes.contains(&scope))
+            .cloned()
+    }
+
+    /// Get all keys
+    pub fn list(&self) -> Vec<AuthKey> {
+        self.read().clone()
+    }
+
+    /// Insert the key, the key with the same name is replaced
+    pub fn upsert(&self, key: AuthKey) {
+        let mut keys = self.write();
+        match keys.iter_mut().find(|k| k.name.eq(&key.name)) {
+            Some(entry) => *entry = key,
+            None => keys.push(key),
+        }
+    }
+
+    /// Replace the value of the named key, returns false if the key does not exist
+    pub fn rotate(&self, name: &str, value: String) -> bool {
+        match self.write().iter_mut().find(|k| k.name.eq(name)) {
+            Some(entry) => {
+                entry.key = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the named key, returns false if the key does not exist
+    pub fn remove(&self, name: &str) -> bool {
+        let mut keys = self.write();
+        let len = keys.len();
+        keys.retain(|k| k.name.ne(name));
+        self.windows
+            .lock()
+            .expect("auth key windows lock poisoned")
+            .remove(name);
+        keys.len() != len
+    }
+
+    /// Verify the key of the route group, unprotected route groups are always allowed
+    pub fn verify(
+        &self,
+        key: Option<&str>,
+        scope: Scope,
+        ip: IpAddr,
+    ) -> Result<Option<AuthKey>, AuthKeyDenied> {
+        if !self.is_protected(scope) {
+            return Ok(None);
+        }
+
+        let key = key
+            .filter(|k| !k.is_empty())
+            .ok_or(AuthKeyDenied::Required)?;
+
+        let entry = self.lookup(key).ok_or(AuthKeyDenied::Invalid)?;
+        let now = now_duration().map(|d| d.as_secs()).unwrap_or_default();
+
+        if entry.expires_at.is_some_and(|exp| exp <= now) {
+            return Err(AuthKeyDenied::Expired(entry.name));
+        }
+
+        if !entry.scopes.contains(&scope) {
+            return Err(AuthKeyDenied::ScopeNotAllowed(entry.name));
+        }
+
+        if let Some(allow_ips) = entry.allow_ips.as_ref() {
+            if !allow_ips.iter().any(|cidr| cidr.contains(&ip)) {
+                return Err(AuthKeyDenied::IpNotAllowed(entry.name));
+            }
+        }
+
+        if let Some(limit) = entry.rate_limit {
+            if !self.acquire(&entry.name, limit, now / 60) {
+                return Err(AuthKeyDenied::RateLimited(entry.name));
+            }
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Find the unexpired key of the value, e.g. to key the rate limit by a genuine key only
+    pub fn find(&self, key: &str) -> Option<AuthKey> {
+        let now = now_duration().map(|d| d.as_secs()).unwrap_or_default();
+        self.lookup(key)
+            .filter(|entry| entry.expires_at.map_or(true, |exp| exp > now))
+    }
+
+    /// Compare with every key, so the timing does not reveal the matching entry
+    fn lookup(&self, key: &str) -> Option<AuthKey> {
+        self.read()
+            .iter()
+            .fold(None, |found, entry| {
+                let eq = constant_time_eq(entry.key.as_bytes(), key.as_bytes());
+                found.or(eq.then_some(entry))
+            })
+            .cloned()
+    }
+
+    /// Fixed window of a minute
+    fn acquire(&self, name: &str, limit: u32, minute: u64) -> bool {
+        let mut windows = self.windows.lock().expect("auth key windows lock poisoned");
+        let window = windows.entry(name.to_owned()).or_insert((minute, 0));
+        if window.0 != minute {
+            *window = (minute, 0);
+        }
+        if window.1 >= limit {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+/// Constant-time comparison, the time only depends on the length of the input
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let len = a.len().max(b.len());
+    let mut diff = (a.len() != b.len()) as u8;
+    for i in 0..len {
+        let x = a.get(i).copied().unwrap_or_default();
+        let y = b.get(i).copied().unwrap_or_default();
+        diff |= x ^ y;
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> AuthKeys {
+        let keys: Vec<AuthKey> = serde_json::from_str(
+            r#"[
+                {"name": "ops", "key": "ops-key", "scopes": ["har", "metrics"], "allow_ips": ["10.0.0.0/8"]},
+                {"name": "bot", "key": "bot-key", "scopes": ["v1"], "rate_limit": 1},
+                {"name": "old", "key": "old-key", "scopes": ["arkose"], "expires_at": 1}
+            ]"#,
+        )
+        .unwrap();
+        AuthKeys::new(keys, None)
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"", b"a"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let keys = keys();
+        let lan: IpAddr = "10.1.2.3".parse().unwrap();
+        let wan: IpAddr = "1.1.1.1".parse().unwrap();
+
+        assert_eq!(keys.verify(None, Scope::Login, wan), Ok(None));
+        assert_eq!(
+            keys.verify(None, Scope::Har, lan),
+            Err(AuthKeyDenied::Required)
+        );
+        assert_eq!(
+            keys.verify(Some("nope"), Scope::Har, lan),
+            Err(AuthKeyDenied::Invalid)
+        );
+        assert!(keys.verify(Some("ops-key"), Scope::Har, lan).is_ok());
+        assert_eq!(
+            keys.verify(Some("ops-key"), Scope::Har, wan),
+            Err(AuthKeyDenied::IpNotAllowed("ops".to_owned()))
+        );
+        assert_eq!(
+            keys.verify(Some("bot-key"), Scope::Metrics, wan),
+            Err(AuthKeyDenied::ScopeNotAllowed("bot".to_owned()))
+        );
+        assert_eq!(
+            keys.verify(Some("old-key"), Scope::Arkose, wan),
+            Err(AuthKeyDenied::Expired("old".to_owned()))
+        );
+        assert!(keys.verify(Some("bot-key"), Scope::V1, wan).is_ok());
+        assert_eq!(
+            keys.verify(Some("bot-key"), Scope::V1, wan),
+            Err(AuthKeyDenied::RateLimited("bot".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_legacy_key() {
+        let keys = AuthKeys::new(vec![], Some("legacy".to_owned()));
+        assert!(keys.is_protected(Scope::Login));
+        assert!(!keys.is_protected(Scope::V1));
+        assert_eq!(keys.first(Scope::Har).unwrap().key, "legacy");
+    }
+
+    #[test]
+    fn test_rotate() {
+        let keys = keys();
+        let wan: IpAddr = "1.1.1.1".parse().unwrap();
+        assert!(keys.rotate("old", "new-key".to_owned()));
+        assert!(!keys.rotate("nope", "new-key".to_owned()));
+        assert_eq!(
+            keys.verify(Some("old-key"), Scope::Arkose, wan),
+            Err(AuthKeyDenied::Invalid)
+        );
+        assert!(keys.remove("bot"));
+        assert!(!keys.is_protected(Scope::V1));
+    }
+}
@@ -1,14 +1,29 @@
+#[cfg(feature = "redis")]
+use super::store::RedisStore;
 use super::{
     args::Args,
     arkose::{
         har::{HarProvider, HAR},
+        pool::ArkoseTokenPool,
         ArkoseVersionContext,
     },
+    authkey::{AuthKey, AuthKeys},
     preauth::PreauthCookieProvider,
+    swap,
+    whitelist::{self, EmailRule, EmailWhitelist},
     CfTurnstile, Context, CTX,
 };
-use crate::{arkose, client::ClientRoundRobinBalancer, error};
-use std::{collections::HashMap, sync::RwLock};
+use crate::{
+    arkose::{self, funcaptcha::solver::SolverChain},
+    client::ClientRoundRobinBalancer,
+    error,
+    gpt_model::ModelRegistry,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 /// Use Once to guarantee initialization only once
 pub fn init(args: Args) {
@@ -28,24 +43,70 @@ pub fn instance() -> &'static Context {
 
 /// Init the program context
 fn init_context(args: Args) -> Context {
+    let arkose_solver = solver_chain(&args);
     Context {
-        api_client: ClientRoundRobinBalancer::new_client(&args)
-            .expect("Failed to initialize the requesting client"),
-        auth_client: ClientRoundRobinBalancer::new_auth_client(&args)
-            .expect("Failed to initialize the requesting oauth client"),
-        arkose_client: ClientRoundRobinBalancer::new_arkose_client(&args)
-            .expect("Failed to initialize the requesting arkose client"),
-        preauth_provider: args.pbind.is_some().then(|| PreauthCookieProvider::new()),
+        api_client: RwLock::new(Arc::new(
+            ClientRoundRobinBalancer::new_client(&args)
+                .expect("Failed to initialize the requesting client"),
+        )),
+        auth_client: RwLock::new(Arc::new(
+            ClientRoundRobinBalancer::new_auth_client(&args)
+                .expect("Failed to initialize the requesting oauth client"),
+        )),
+        arkose_client: RwLock::new(Arc::new(
+            ClientRoundRobinBalancer::new_arkose_client(&args)
+                .expect("Failed to initialize the requesting arkose client"),
+        )),
+        preauth_provider: (args.pbind.is_some() || args.redis_url.is_some())
+            .then(|| PreauthCookieProvider::new()),
         arkose_endpoint: args.arkose_endpoint,
         arkose_context: ArkoseVersionContext::new(),
-        arkose_solver: args.arkose_solver,
+        #[cfg(feature = "redis")]
+        redis: args
+            .redis_url
+            .map(|url| RedisStore::new(&url).expect("Failed to initialize the redis store")),
+        arkose_solver: RwLock::new(arkose_solver.map(Arc::new)),
         arkose_gpt3_experiment: args.arkose_gpt3_experiment,
         arkose_gpt3_experiment_solver: args.arkose_gpt3_experiment_solver,
         arkose_solver_tguess_endpoint: args.arkose_solver_tguess_endpoint,
         arkose_solver_image_dir: args.arkose_solver_image_dir,
+        arkose_token_pool: (args.arkose_pool_size > 0).then(|| {
+            let types = match args.arkose_pool_types.is_empty() {
+                true => vec![
+                    arkose::Type::GPT3,
+                    arkose::Type::Auth,
+                    arkose::Type::Platform,
+                ],
+                false => args.arkose_pool_types,
+            };
+            ArkoseTokenPool::new(
+                types,
+                args.arkose_pool_size,
+                Duration::from_secs(args.arkose_pool_ttl.into()),
+                args.arkose_pool_concurrency,
+            )
+        }),
         enable_file_proxy: args.enable_file_proxy,
-        auth_key: args.auth_key,
-        visitor_email_whitelist: args.visitor_email_whitelist,
+        enable_conversation_continuity: args.enable_conversation_continuity,
+        conversation_expired: args.conversation_expired,
+        account_pool_key: args.account_pool_key,
+        account_pool_cooldown: args.account_pool_cooldown,
+        token_refresh_webhook: args.token_refresh_webhook,
+        gpt_models: match args.gpt_models.is_empty() {
+            true => ModelRegistry::default(),
+            false => ModelRegistry::new(args.gpt_models),
+        },
+        auth_keys: AuthKeys::new(args.auth_keys, args.auth_key),
+        trusted_proxies: args.trusted_proxies,
+        visitor_email_whitelist: EmailWhitelist::new(
+            whitelist::parse_rules(
+                &args.visitor_email_whitelist.unwrap_or_default(),
+                &args.visitor_email_denylist.unwrap_or_default(),
+            )
+            .expect("Failed to parse the visitor email whitelist"),
+            args.visitor_email_whitelist_file,
+        )
+        .expect("Failed to initialize the visitor email whitelist"),
         cf_turnstile: args.cf_site_key.and_then(|site_key| {
             args.cf_secret_key.map(|secret_key| CfTurnstile {
                 site_key,
@@ -55,6 +116,53 @@ fn init_context(args: Args) -> Context {
     }
 }
 
+/// Build the solver chain, `None` if no solver is configured
+fn solver_chain(args: &Args) -> Option<SolverChain> {
+    (!args.arkose_solvers.is_empty())
+        .then(|| SolverChain::new(args.arkose_solvers.clone(), args.arkose_solver_retries))
+}
+
+/// Hot-swappable settings built from the reloaded args
+pub struct Reload {
+    api_client: ClientRoundRobinBalancer,
+    auth_client: ClientRoundRobinBalancer,
+    arkose_client: ClientRoundRobinBalancer,
+    arkose_solver: Option<SolverChain>,
+    visitor_email_whitelist: Vec<EmailRule>,
+    auth_keys: Vec<AuthKey>,
+    auth_key: Option<String>,
+}
+
+impl Reload {
+    /// Build the hot-swappable settings, nothing is applied if any of them fails
+    pub fn prepare(args: &Args) -> anyhow::Result<Self> {
+        Ok(Self {
+            api_client: ClientRoundRobinBalancer::new_client(args)?,
+            auth_client: ClientRoundRobinBalancer::new_auth_client(args)?,
+            arkose_client: ClientRoundRobinBalancer::new_arkose_client(args)?,
+            arkose_solver: solver_chain(args),
+            visitor_email_whitelist: whitelist::parse_rules(
+                args.visitor_email_whitelist.as_deref().unwrap_or_default(),
+                args.visitor_email_denylist.as_deref().unwrap_or_default(),
+            )?,
+            auth_keys: args.auth_keys.clone(),
+            auth_key: args.auth_key.clone(),
+        })
+    }
+
+    /// Apply the settings to the program context
+    pub fn apply(self) {
+        let ctx = instance();
+        swap(&ctx.api_client, Arc::new(self.api_client));
+        swap(&ctx.auth_client, Arc::new(self.auth_client));
+        swap(&ctx.arkose_client, Arc::new(self.arkose_client));
+        swap(&ctx.arkose_solver, self.arkose_solver.map(Arc::new));
+        ctx.visitor_email_whitelist
+            .replace(self.visitor_email_whitelist);
+        ctx.auth_keys.replace(self.auth_keys, self.auth_key);
+    }
+}
+
 fn init_har_provider(args: Args) -> HashMap<arkose::Type, HarProvider> {
     let gpt3_har_provider =
         HarProvider::new(arkose::Type::GPT3, args.arkose_har_dir.as_ref(), "gpt3");
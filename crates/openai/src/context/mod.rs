@@ -1,16 +1,28 @@
 pub mod args;
 pub mod arkose;
+pub mod authkey;
 pub mod init;
 mod preauth;
+#[cfg(feature = "redis")]
+pub mod store;
+pub mod whitelist;
 
+use self::arkose::pool::ArkoseTokenPool;
+use self::authkey::AuthKeys;
 use self::preauth::PreauthCookieProvider;
+#[cfg(feature = "redis")]
+use self::store::RedisStore;
+use self::whitelist::EmailWhitelist;
 use crate::{
-    arkose::funcaptcha::solver::ArkoseSolver, auth::AuthClient, client::ClientRoundRobinBalancer,
+    arkose::funcaptcha::solver::SolverChain,
+    auth::AuthClient,
+    client::{ClientRoundRobinBalancer, MemberHealth},
+    gpt_model::ModelRegistry,
 };
 use reqwest::Client;
 use std::{
     path::{Path, PathBuf},
-    sync::OnceLock,
+    sync::{Arc, OnceLock, RwLock},
 };
 
 pub const WORKER_DIR: &str = ".ninja";
@@ -34,28 +46,55 @@ pub fn init(args: args::Args) {
     init::init(args);
 }
 
+/// Get the current value of the hot-swappable setting
+fn load<T: Clone>(lock: &RwLock<T>) -> T {
+    lock.read().expect("context lock poisoned").clone()
+}
+
+/// Replace the hot-swappable setting
+fn swap<T>(lock: &RwLock<T>, value: T) {
+    *lock.write().expect("context lock poisoned") = value;
+}
+
 pub struct CfTurnstile {
     pub site_key: String,
     pub secret_key: String,
 }
 
 pub struct Context {
-    /// Requesting client
-    api_client: ClientRoundRobinBalancer,
-    /// Requesting oauth client
-    auth_client: ClientRoundRobinBalancer,
-    /// Requesting arkose client
-    arkose_client: ClientRoundRobinBalancer,
+    /// Requesting client, replaced when the config is reloaded
+    api_client: RwLock<Arc<ClientRoundRobinBalancer>>,
+    /// Requesting oauth client, replaced when the config is reloaded
+    auth_client: RwLock<Arc<ClientRoundRobinBalancer>>,
+    /// Requesting arkose client, replaced when the config is reloaded
+    arkose_client: RwLock<Arc<ClientRoundRobinBalancer>>,
     /// Arkoselabs context
     arkose_context: arkose::ArkoseVersionContext<'static>,
-    /// arkoselabs solver
-    arkose_solver: Option<ArkoseSolver>,
+    /// Redis store shared by the instances
+    #[cfg(feature = "redis")]
+    redis: Option<RedisStore>,
+    /// arkoselabs solver chain, replaced when the config is reloaded
+    arkose_solver: RwLock<Option<Arc<SolverChain>>>,
     /// Enable files proxy
     enable_file_proxy: bool,
-    /// Login auth key
-    auth_key: Option<String>,
-    /// visitor_email_whitelist
-    visitor_email_whitelist: Option<Vec<String>>,
+    /// Enable conversation continuity
+    enable_conversation_continuity: bool,
+    /// Conversation continuity expired (second)
+    conversation_expired: u32,
+    /// Account pool API key
+    account_pool_key: Option<String>,
+    /// Cooldown of the rate limited pool account (second)
+    account_pool_cooldown: u32,
+    /// Token refresh failed webhook url
+    token_refresh_webhook: Option<String>,
+    /// GPT model table
+    gpt_models: ModelRegistry,
+    /// Scoped auth keys
+    auth_keys: AuthKeys,
+    /// Trusted reverse proxies
+    trusted_proxies: Vec<cidr::IpCidr>,
+    /// Visitor email whitelist and deny list
+    visitor_email_whitelist: EmailWhitelist,
     /// Cloudflare Turnstile
     cf_turnstile: Option<CfTurnstile>,
     /// Arkose endpoint
@@ -68,6 +107,8 @@ pub struct Context {
     arkose_solver_tguess_endpoint: Option<String>,
     /// Arkose solver image store directory
     arkose_solver_image_dir: Option<PathBuf>,
+    /// Arkose token pre-fetch pool
+    arkose_token_pool: Option<ArkoseTokenPool>,
     /// PreAuth cookie cache
     preauth_provider: Option<PreauthCookieProvider>,
 }
@@ -75,22 +116,54 @@ pub struct Context {
 impl Context {
     /// Get the reqwest client
     pub fn api_client(&self) -> Client {
-        self.api_client.next().into()
+        load(&self.api_client).next().into()
+    }
+
+    /// Get the reqwest client of the account with the health of its pool member, the outcome of the request should be reported
+    pub fn checkout_api_client(&self, account: Option<&str>) -> (Client, Arc<MemberHealth>) {
+        let (client, health) = load(&self.api_client).checkout(account);
+        (client.into(), health)
     }
 
     /// Get the reqwest auth client
     pub fn auth_client(&self) -> AuthClient {
-        self.auth_client.next().into()
+        load(&self.auth_client).next().into()
+    }
+
+    /// Get the reqwest auth client of the account with the health of its pool member, the outcome of the request should be reported
+    pub fn checkout_auth_client(&self, account: Option<&str>) -> (AuthClient, Arc<MemberHealth>) {
+        let (client, health) = load(&self.auth_client).checkout(account);
+        (client.into(), health)
     }
 
     /// Get the reqwest arkose client
     pub fn arkose_client(&self) -> Client {
-        self.arkose_client.next().into()
+        load(&self.arkose_client).next().into()
     }
 
-    /// Get the arkoselabs solver
-    pub fn arkose_solver(&self) -> Option<&ArkoseSolver> {
-        self.arkose_solver.as_ref()
+    /// Get the reqwest arkose client of the account with the health of its pool member, the outcome of the request should be reported
+    pub fn checkout_arkose_client(&self, account: Option<&str>) -> (Client, Arc<MemberHealth>) {
+        let (client, health) = load(&self.arkose_client).checkout(account);
+        (client.into(), health)
+    }
+
+    /// Check if the accounts stick to the client pool members
+    pub fn proxy_affinity(&self) -> bool {
+        load(&self.api_client).affinity()
+    }
+
+    /// Get the client pools by name
+    pub fn client_pools(&self) -> [(&'static str, Arc<ClientRoundRobinBalancer>); 3] {
+        [
+            ("api", load(&self.api_client)),
+            ("auth", load(&self.auth_client)),
+            ("arkose", load(&self.arkose_client)),
+        ]
+    }
+
+    /// Get the arkoselabs solver chain
+    pub fn arkose_solver(&self) -> Option<Arc<SolverChain>> {
+        load(&self.arkose_solver)
     }
 
     /// Cloudflare Turnstile config
@@ -103,9 +176,20 @@ impl Context {
         self.arkose_endpoint.as_deref()
     }
 
-    /// Login auth key
-    pub fn auth_key(&self) -> Option<&str> {
-        self.auth_key.as_deref()
+    /// Scoped auth keys
+    pub fn auth_keys(&self) -> &AuthKeys {
+        &self.auth_keys
+    }
+
+    /// Redis store shared by the instances
+    #[cfg(feature = "redis")]
+    pub fn redis(&self) -> Option<&RedisStore> {
+        self.redis.as_ref()
+    }
+
+    /// Trusted reverse proxies
+    pub fn trusted_proxies(&self) -> &[cidr::IpCidr] {
+        &self.trusted_proxies
     }
 
     /// Push a preauth cookie
@@ -116,12 +200,38 @@ impl Context {
             .map(|p| p.push(value, max_age));
     }
 
+    /// Periodically sync the preauth cookies shared by the instances
+    #[cfg(all(feature = "preauth", feature = "redis"))]
+    pub async fn periodic_sync_preauth_cookie(&self) {
+        if let Some(p) = self.preauth_provider.as_ref() {
+            p.periodic_sync().await
+        }
+    }
+
     /// Pop a preauth cookie
     #[cfg(feature = "preauth")]
     pub fn pop_preauth_cookie(&self) -> Option<String> {
         self.preauth_provider.as_ref().map(|p| p.get()).flatten()
     }
 
+    /// Get the cached preauth cookies
+    #[cfg(feature = "preauth")]
+    pub fn preauth_cookies(&self) -> Vec<String> {
+        self.preauth_provider
+            .as_ref()
+            .map(|p| p.list())
+            .unwrap_or_default()
+    }
+
+    /// Flush the preauth cookies
+    #[cfg(feature = "preauth")]
+    pub async fn flush_preauth_cookies(&self) -> anyhow::Result<()> {
+        match self.preauth_provider.as_ref() {
+            Some(p) => p.flush().await,
+            None => Ok(()),
+        }
+    }
+
     /// Get the arkose gpt3 experiment
     pub fn arkose_gpt3_experiment(&self) -> bool {
         self.arkose_gpt3_experiment
@@ -132,9 +242,39 @@ impl Context {
         self.enable_file_proxy
     }
 
+    /// Enable conversation continuity
+    pub fn enable_conversation_continuity(&self) -> bool {
+        self.enable_conversation_continuity
+    }
+
+    /// Conversation continuity expired (second)
+    pub fn conversation_expired(&self) -> u32 {
+        self.conversation_expired
+    }
+
+    /// Get the account pool API key
+    pub fn account_pool_key(&self) -> Option<&str> {
+        self.account_pool_key.as_deref()
+    }
+
+    /// Cooldown of the rate limited pool account (second)
+    pub fn account_pool_cooldown(&self) -> u32 {
+        self.account_pool_cooldown
+    }
+
+    /// Get the token refresh failed webhook url
+    pub fn token_refresh_webhook(&self) -> Option<&str> {
+        self.token_refresh_webhook.as_deref()
+    }
+
+    /// Get the GPT model table
+    pub fn gpt_models(&self) -> &ModelRegistry {
+        &self.gpt_models
+    }
+
     /// Get the visitor email whitelist
-    pub fn visitor_email_whitelist(&self) -> Option<&[String]> {
-        self.visitor_email_whitelist.as_deref()
+    pub fn visitor_email_whitelist(&self) -> &EmailWhitelist {
+        &self.visitor_email_whitelist
     }
 
     /// Get the arkose gpt3 experiment solver
@@ -156,4 +296,9 @@ impl Context {
     pub fn arkose_solver_image_dir(&self) -> Option<&Path> {
         self.arkose_solver_image_dir.as_deref()
     }
+
+    /// Get the arkose token pre-fetch pool
+    pub fn arkose_token_pool(&self) -> Option<&ArkoseTokenPool> {
+        self.arkose_token_pool.as_ref()
+    }
 }
@@ -1,5 +1,11 @@
+#[cfg(feature = "redis")]
+use super::store;
 use crate::{error, homedir::home_dir, info, now_duration};
+#[cfg(feature = "redis")]
+use crate::{warn, with_context};
 use moka::sync::Cache;
+#[cfg(feature = "redis")]
+use redis::AsyncCommands;
 use std::{
     path::{Path, PathBuf},
     sync::Mutex,
@@ -9,6 +15,8 @@ use std::{
 const SEPARATOR: &str = "---";
 const DEFAULT_MAX_AGE: u32 = 3600;
 const DEFAULT_MAX_CAPACITY: u64 = 1000;
+#[cfg(feature = "redis")]
+const SYNC_INTERVAL_SECONDS: u64 = 60;
 
 static LOCK: Mutex<()> = Mutex::new(());
 static mut CACHE: Option<Cache<String, String>> = None;
@@ -125,9 +133,87 @@ impl PreauthCookieProvider {
             info!("Push PreAuth Cookie: {value}");
             get_or_init_cache(max_age).insert(device_id.to_owned(), value.to_owned());
             self.sync_to_file(&self.path, max_age);
+            #[cfg(feature = "redis")]
+            self.publish(device_id, value, max_age);
         });
     }
 
+    /// Publish the preauth cookie to the redis store shared by the instances
+    #[cfg(feature = "redis")]
+    fn publish(&self, device_id: &str, value: &str, max_age: Option<u32>) {
+        let redis = match with_context!(redis) {
+            Some(redis) => redis,
+            None => return,
+        };
+
+        let device_id = device_id.to_owned();
+        let max_age = max_age.unwrap_or(DEFAULT_MAX_AGE);
+        let value = format!("{max_age}{SEPARATOR}{value}");
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                // The hash expires with the latest cookie, so an idle cluster leaves nothing behind
+                let key = store::key("preauth", "cookies");
+                let result = match redis.connection().await {
+                    Ok(mut conn) => {
+                        redis::pipe()
+                            .atomic()
+                            .hset(&key, device_id, value)
+                            .ignore()
+                            .expire(&key, max_age as usize)
+                            .ignore()
+                            .query_async::<_, ()>(&mut conn)
+                            .await
+                    }
+                    Err(err) => Err(err),
+                };
+                if let Some(err) = result.err() {
+                    warn!("Failed to publish preauth cookie to redis: {}", err)
+                }
+            });
+        }
+    }
+
+    /// Periodically pull the preauth cookies published by the other instances
+    #[cfg(feature = "redis")]
+    pub async fn periodic_sync(&self) {
+        info!("Preauth cookie sync task is running");
+        let mut interval = tokio::time::interval(Duration::from_secs(SYNC_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            if let Some(err) = self.sync_from_redis().await.err() {
+                warn!("Failed to sync preauth cookie from redis: {}", err)
+            }
+        }
+    }
+
+    /// Load the valid preauth cookies from redis, the expired ones are removed
+    #[cfg(feature = "redis")]
+    async fn sync_from_redis(&self) -> anyhow::Result<()> {
+        let redis = match with_context!(redis) {
+            Some(redis) => redis,
+            None => return Ok(()),
+        };
+
+        let key = store::key("preauth", "cookies");
+        let mut conn = redis.connection().await?;
+        let cookies: std::collections::HashMap<String, String> = conn.hgetall(&key).await?;
+
+        for (device_id, data) in cookies {
+            let (max_age, value) = match data.split_once(SEPARATOR) {
+                Some((max_age, value)) => (max_age.parse::<u32>().ok(), value),
+                None => continue,
+            };
+
+            if Self::is_invalid(value, max_age) {
+                conn.hdel::<_, _, ()>(&key, &device_id).await?;
+            } else {
+                get_or_init_cache(max_age).insert(device_id, value.to_owned());
+            }
+        }
+
+        Ok(())
+    }
+
     /// Pop a preauth cookie
     /// Example: `id1:1704031809-xxx`
     pub fn get(&self) -> Option<String> {
@@ -142,6 +228,27 @@ impl PreauthCookieProvider {
         None
     }
 
+    /// Get the cached preauth cookies
+    pub fn list(&self) -> Vec<String> {
+        get_or_init_cache(self.max_age)
+            .iter()
+            .map(|(_, v)| v)
+            .collect()
+    }
+
+    /// Flush the preauth cookies, including the file and the redis store
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        get_or_init_cache(self.max_age).invalidate_all();
+        tokio::fs::write(&self.path, "").await?;
+        #[cfg(feature = "redis")]
+        if let Some(redis) = with_context!(redis) {
+            let mut conn = redis.connection().await?;
+            conn.del::<_, ()>(store::key("preauth", "cookies")).await?;
+        }
+        info!("Flushed preauth cookies");
+        Ok(())
+    }
+
     /// Check if is invalid
     fn is_invalid(input: &str, max_age: Option<u32>) -> bool {
         let parts: Vec<&str> = input.split(':').collect();
@@ -0,0 +1,34 @@
+use redis::aio::ConnectionManager;
+use redis::RedisResult;
+use tokio::sync::OnceCell;
+
+/// Key prefix of the shared state
+const KEY_PREFIX: &str = "ninja";
+
+/// Build the shared state key, e.g. `ninja:puid:xxx`
+pub fn key(namespace: &str, id: &str) -> String {
+    format!("{KEY_PREFIX}:{namespace}:{id}")
+}
+
+/// Redis store shared by the instances behind a load balancer
+pub struct RedisStore {
+    client: redis::Client,
+    conn: OnceCell<ConnectionManager>,
+}
+
+impl RedisStore {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            conn: OnceCell::new(),
+        })
+    }
+
+    /// Get the connection, it is reconnected automatically when broken
+    pub async fn connection(&self) -> RedisResult<ConnectionManager> {
+        self.conn
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .cloned()
+    }
+}
@@ -0,0 +1,286 @@
+use crate::{info, warn, with_context};
+use hotwatch::{Event, EventKind, Hotwatch};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{RwLock, RwLockReadGuard};
+
+/// Visitor email pattern
+#[derive(Debug, Clone)]
+enum Pattern {
+    /// Exact email, case-insensitive
+    Exact(String),
+    /// `*@example.com`, `@example.com` or `/^admin.*@example\.com$/`
+    Regex(Regex),
+}
+
+/// Visitor email rule, a rule prefixed with `!` denies the matched emails
+#[derive(Debug, Clone)]
+pub struct EmailRule {
+    source: String,
+    deny: bool,
+    pattern: Pattern,
+}
+
+impl FromStr for EmailRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = s.trim();
+        let (deny, rule) = match source.strip_prefix('!') {
+            Some(rule) => (true, rule.trim()),
+            None => (false, source),
+        };
+
+        let pattern = if rule.len() > 1 && rule.starts_with('/') && rule.ends_with('/') {
+            Pattern::Regex(Regex::new(&format!("(?i){}", &rule[1..rule.len() - 1]))?)
+        } else if let Some(domain) = rule.strip_prefix('@') {
+            Pattern::Regex(glob(&format!("*@{domain}"))?)
+        } else if rule.contains(['*', '?']) {
+            Pattern::Regex(glob(rule)?)
+        } else if rule.contains('@') {
+            Pattern::Exact(rule.to_owned())
+        } else {
+            anyhow::bail!("Invalid email rule: {source}")
+        };
+
+        Ok(Self {
+            source: source.to_owned(),
+            deny,
+            pattern,
+        })
+    }
+}
+
+impl EmailRule {
+    /// The rule as configured
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    fn matches(&self, email: &str) -> bool {
+        match &self.pattern {
+            Pattern::Exact(exact) => exact.eq_ignore_ascii_case(email),
+            Pattern::Regex(regex) => regex.is_match(email),
+        }
+    }
+}
+
+/// Wildcard to regex, `*` matches any characters and `?` matches a single character
+fn glob(pattern: &str) -> anyhow::Result<Regex> {
+    let regex = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Ok(Regex::new(&format!("(?i)^{regex}$"))?)
+}
+
+/// Parse the allow and deny entries
+pub fn parse_rules(allow: &[String], deny: &[String]) -> anyhow::Result<Vec<EmailRule>> {
+    allow
+        .iter()
+        .map(|s| EmailRule::from_str(s))
+        .chain(deny.iter().map(|s| EmailRule::from_str(&format!("!{s}"))))
+        .collect()
+}
+
+/// Read the rules of the file, one rule per line, `#` starts a comment line
+fn read_rules(path: &Path) -> anyhow::Result<Vec<EmailRule>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(EmailRule::from_str)
+        .collect()
+}
+
+/// Visitor email whitelist.
+/// An email is rejected when any deny rule matches, or when there are allow rules and none of them matches.
+pub struct EmailWhitelist {
+    /// Rules of the config and the admin API
+    rules: RwLock<Vec<EmailRule>>,
+    /// Rules of the watched file
+    file_rules: RwLock<Vec<EmailRule>>,
+    file: Option<PathBuf>,
+    _hotwatch: Option<Hotwatch>,
+}
+
+impl EmailWhitelist {
+    pub fn new(rules: Vec<EmailRule>, file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let file_rules = match file.as_ref() {
+            Some(path) => read_rules(path)?,
+            None => vec![],
+        };
+        let hotwatch = match file.as_ref() {
+            Some(path) => Some(watch_file(path)?),
+            None => None,
+        };
+        Ok(Self {
+            rules: RwLock::new(rules),
+            file_rules: RwLock::new(file_rules),
+            file,
+            _hotwatch: hotwatch,
+        })
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Vec<EmailRule>> {
+        self.rules.read().expect("whitelist lock poisoned")
+    }
+
+    fn read_file(&self) -> RwLockReadGuard<'_, Vec<EmailRule>> {
+        self.file_rules.read().expect("whitelist lock poisoned")
+    }
+
+    /// Check if the email is allowed
+    pub fn is_allowed(&self, email: &str) -> bool {
+        let (rules, file_rules) = (self.read(), self.read_file());
+        let (deny, allow): (Vec<_>, Vec<_>) =
+            rules.iter().chain(file_rules.iter()).partition(|r| r.deny);
+
+        if deny.iter().any(|r| r.matches(email)) {
+            return false;
+        }
+
+        allow.is_empty() || allow.iter().any(|r| r.matches(email))
+    }
+
+    /// Get the rules of the config and the admin API
+    pub fn rules(&self) -> Vec<String> {
+        self.read().iter().map(|r| r.source.clone()).collect()
+    }
+
+    /// Get the rules of the watched file
+    pub fn file_rules(&self) -> Vec<String> {
+        self.read_file().iter().map(|r| r.source.clone()).collect()
+    }
+
+    /// Add a rule, returns false if it already exists
+    pub fn add(&self, rule: EmailRule) -> bool {
+        let mut rules = self.rules.write().expect("whitelist lock poisoned");
+        if rules.iter().any(|r| r.source.eq(&rule.source)) {
+            return false;
+        }
+        rules.push(rule);
+        true
+    }
+
+    /// Remove a rule, returns false if it does not exist.
+    /// The last allow rule can not be removed, an empty allow list would allow every email
+    pub fn remove(&self, source: &str) -> anyhow::Result<bool> {
+        let mut rules = self.rules.write().expect("whitelist lock poisoned");
+        let Some(index) = rules.iter().position(|r| r.source.eq(source)) else {
+            return Ok(false);
+        };
+
+        let allows = rules
+            .iter()
+            .chain(self.read_file().iter())
+            .filter(|r| !r.deny)
+            .count();
+        if !rules[index].deny && allows == 1 {
+            anyhow::bail!("Removing the last allow rule would allow every email")
+        }
+        rules.remove(index);
+        Ok(true)
+    }
+
+    /// Replace the rules of the config, the file rules are kept
+    pub fn replace(&self, rules: Vec<EmailRule>) {
+        *self.rules.write().expect("whitelist lock poisoned") = rules;
+    }
+
+    /// Reload the rules of the watched file, the previous rules are kept when the file is invalid
+    fn reload_file(&self) {
+        if let Some(path) = self.file.as_ref() {
+            match read_rules(path) {
+                Ok(rules) => {
+                    info!(
+                        "Reloaded {} rules of whitelist file: {}",
+                        rules.len(),
+                        path.display()
+                    );
+                    *self.file_rules.write().expect("whitelist lock poisoned") = rules;
+                }
+                Err(err) => warn!("Failed to reload whitelist file {}: {err}", path.display()),
+            }
+        }
+    }
+}
+
+/// Watch the parent directory of the whitelist file, editors usually replace the file instead of writing it in place
+fn watch_file(path: &Path) -> anyhow::Result<Hotwatch> {
+    let path = path.canonicalize()?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Invalid whitelist file: {}", path.display()))?
+        .to_owned();
+
+    let mut hotwatch = Hotwatch::new()?;
+    hotwatch.watch(dir, move |event: Event| match event.kind {
+        EventKind::Create(_) | EventKind::Modify(_) => {
+            if event.paths.iter().any(|p| p.eq(&path)) {
+                with_context!(visitor_email_whitelist).reload_file();
+            }
+        }
+        _ => {}
+    })?;
+    Ok(hotwatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whitelist(allow: &[&str], deny: &[&str]) -> EmailWhitelist {
+        let allow = allow.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let deny = deny.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        EmailWhitelist::new(parse_rules(&allow, &deny).unwrap(), None).unwrap()
+    }
+
+    #[test]
+    fn test_rules() {
+        let w = whitelist(
+            &[
+                "*@ourcompany.com",
+                "@partner.org",
+                "/^bot-[0-9]+@example\\.com$/",
+                "a@b.com",
+            ],
+            &["intern-*@ourcompany.com"],
+        );
+        assert!(w.is_allowed("Alice@OurCompany.com"));
+        assert!(w.is_allowed("bob@partner.org"));
+        assert!(w.is_allowed("bot-42@example.com"));
+        assert!(w.is_allowed("a@b.com"));
+        assert!(!w.is_allowed("intern-x@ourcompany.com"));
+        assert!(!w.is_allowed("bot-x@example.com"));
+        assert!(!w.is_allowed("eve@ourcompany.com.evil.io"));
+    }
+
+    #[test]
+    fn test_deny_only() {
+        let w = whitelist(&[], &["@spam.io"]);
+        assert!(w.is_allowed("anyone@example.com"));
+        assert!(!w.is_allowed("x@spam.io"));
+        assert!(whitelist(&[], &[]).is_allowed("anyone@example.com"));
+    }
+
+    #[test]
+    fn test_remove_last_allow_rule() {
+        let w = whitelist(&["a@b.com", "c@d.com"], &["@spam.io"]);
+        assert!(w.remove("a@b.com").unwrap());
+        assert!(!w.remove("a@b.com").unwrap());
+        assert!(w.remove("c@d.com").is_err());
+        assert!(!w.is_allowed("x@example.com"));
+        assert!(w.remove("!@spam.io").unwrap());
+    }
+
+    #[test]
+    fn test_invalid_rule() {
+        assert!(EmailRule::from_str("not-an-email").is_err());
+        assert!(EmailRule::from_str("/(/").is_err());
+        assert_eq!(
+            EmailRule::from_str("! x@y.com").unwrap().source(),
+            "! x@y.com"
+        );
+    }
+}
@@ -1,20 +1,30 @@
 use std::str::FromStr;
+use std::sync::RwLock;
 
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::arkose::Type;
+
+const GPT35_SLUG: &str = "text-davinci-002-render-sha";
+const GPT4_SLUG: &str = "gpt-4";
+const GPT4_MOBILE_SLUG: &str = "gpt-4-mobile";
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum GPTModel {
     Gpt35,
     Gpt4,
     Gpt4Mobile,
+    /// Upstream model slug from the model table
+    Custom(String),
 }
 
 impl Serialize for GPTModel {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         let model = match self {
-            GPTModel::Gpt35 => "text-davinci-002-render-sha",
-            GPTModel::Gpt4 => "gpt-4",
-            GPTModel::Gpt4Mobile => "gpt-4-mobile",
+            GPTModel::Gpt35 => GPT35_SLUG,
+            GPTModel::Gpt4 => GPT4_SLUG,
+            GPTModel::Gpt4Mobile => GPT4_MOBILE_SLUG,
+            GPTModel::Custom(slug) => slug.as_str(),
         };
         serializer.serialize_str(model)
     }
@@ -56,3 +66,164 @@ impl FromStr for GPTModel {
         }
     }
 }
+
+/// GPT model table entry
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ModelEntry {
+    /// Model alias requested by the client, also matches the models starting with it
+    pub alias: String,
+    /// Upstream ChatGPT model slug
+    pub slug: String,
+    /// Whether the Arkose token is required
+    #[serde(default)]
+    pub arkose: bool,
+    /// Arkose token type
+    #[serde(default = "default_arkose_type")]
+    pub arkose_type: Type,
+    /// Whether the PUID is required
+    #[serde(default)]
+    pub puid: bool,
+}
+
+fn default_arkose_type() -> Type {
+    Type::GPT4
+}
+
+impl ModelEntry {
+    fn new(alias: &str, slug: &str, arkose: bool, arkose_type: Type, puid: bool) -> Self {
+        Self {
+            alias: alias.to_owned(),
+            slug: slug.to_owned(),
+            arkose,
+            arkose_type,
+            puid,
+        }
+    }
+
+    /// Check if the Arkose token is required, GPT-3.5 models require it in the experiment
+    pub fn arkose_required(&self, gpt3_experiment: bool) -> bool {
+        self.arkose || (gpt3_experiment && self.arkose_type == Type::GPT3)
+    }
+}
+
+impl From<&ModelEntry> for GPTModel {
+    fn from(value: &ModelEntry) -> Self {
+        GPTModel::Custom(value.slug.clone())
+    }
+}
+
+/// The built-in model table, same as the GPTModel prefix matching
+pub fn default_models() -> Vec<ModelEntry> {
+    vec![
+        ModelEntry::new("gpt-3.5", GPT35_SLUG, false, Type::GPT3, false),
+        ModelEntry::new("text-davinci", GPT35_SLUG, false, Type::GPT3, false),
+        ModelEntry::new("code-davinci", GPT35_SLUG, false, Type::GPT3, false),
+        ModelEntry::new("gpt-4-mobile", GPT4_MOBILE_SLUG, true, Type::GPT4, true),
+        ModelEntry::new("gpt-4", GPT4_SLUG, true, Type::GPT4, true),
+    ]
+}
+
+/// Config-driven GPT model table
+pub struct ModelRegistry {
+    entries: RwLock<Vec<ModelEntry>>,
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        Self::new(default_models())
+    }
+}
+
+impl ModelRegistry {
+    pub fn new(entries: Vec<ModelEntry>) -> Self {
+        Self {
+            entries: RwLock::new(entries),
+        }
+    }
+
+    /// Get all the model entries
+    pub fn entries(&self) -> Vec<ModelEntry> {
+        self.entries
+            .read()
+            .map(|entries| entries.clone())
+            .unwrap_or_default()
+    }
+
+    /// Resolve the model, matches the alias, the slug, and then the longest alias prefix
+    pub fn resolve(&self, model: &str) -> Option<ModelEntry> {
+        let entries = self.entries.read().ok()?;
+        resolve(&entries, model).cloned()
+    }
+
+    /// Refresh the table with the upstream model slugs,
+    /// a new slug inherits the requirements of the entry it matches by prefix
+    pub fn refresh<'a>(&self, slugs: impl IntoIterator<Item = &'a str>) {
+        // The lookup and the insertion are under the same lock, concurrent refreshes can not insert twice
+        let Ok(mut entries) = self.entries.write() else {
+            return;
+        };
+        for slug in slugs {
+            let entry = match resolve(&entries, slug) {
+                Some(e) if e.alias.eq(slug) || e.slug.eq(slug) => continue,
+                Some(e) => ModelEntry {
+                    alias: slug.to_owned(),
+                    slug: slug.to_owned(),
+                    ..e.clone()
+                },
+                None => ModelEntry::new(slug, slug, false, default_arkose_type(), false),
+            };
+            entries.push(entry);
+        }
+    }
+}
+
+/// Resolve the model in the entries
+fn resolve<'a>(entries: &'a [ModelEntry], model: &str) -> Option<&'a ModelEntry> {
+    entries
+        .iter()
+        .find(|e| e.alias.eq(model))
+        .or_else(|| entries.iter().find(|e| e.slug.eq(model)))
+        .or_else(|| {
+            entries
+                .iter()
+                .filter(|e| model.starts_with(e.alias.as_str()))
+                .max_by_key(|e| e.alias.len())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let registry = ModelRegistry::default();
+        assert_eq!(
+            registry.resolve("gpt-3.5-turbo").map(|e| e.slug),
+            Some(GPT35_SLUG.to_owned())
+        );
+        assert_eq!(
+            registry.resolve("gpt-4-mobile").map(|e| e.slug),
+            Some(GPT4_MOBILE_SLUG.to_owned())
+        );
+        assert_eq!(
+            registry.resolve("gpt-4-32k").map(|e| e.slug),
+            Some(GPT4_SLUG.to_owned())
+        );
+        assert!(registry.resolve("dall-e-3").is_none());
+    }
+
+    #[test]
+    fn test_refresh() {
+        let registry = ModelRegistry::default();
+        registry.refresh(["text-davinci-002-render-sha", "gpt-4o", "gizmo"]);
+
+        let entry = registry.resolve("gpt-4o").unwrap();
+        assert_eq!(entry.slug, "gpt-4o");
+        assert!(entry.arkose && entry.puid);
+
+        let entry = registry.resolve("gizmo").unwrap();
+        assert!(!entry.arkose_required(false));
+        assert_eq!(registry.entries().len(), default_models().len() + 2);
+    }
+}
@@ -8,19 +8,33 @@ use url::Url;
 /// RandomIpv6 trait
 pub trait Ipv6CidrExt {
     fn random_ipv6(&self) -> IpAddr;
+
+    /// Stable address of the hash within the subnet
+    fn hashed_ipv6(&self, hash: u64) -> IpAddr;
 }
 
 impl Ipv6CidrExt for Ipv6Cidr {
     fn random_ipv6(&self) -> IpAddr {
-        let ipv6: u128 = self.first_address().into();
-        let prefix_len = self.network_length();
-        let rand: u128 = rand::thread_rng().gen();
-        let net_part = (ipv6 >> (128 - prefix_len)) << (128 - prefix_len);
-        let host_part = (rand << prefix_len) >> prefix_len;
-        IpAddr::V6((net_part | host_part).into())
+        ipv6_with_host(self, rand::thread_rng().gen())
+    }
+
+    fn hashed_ipv6(&self, hash: u64) -> IpAddr {
+        // Spread the hash over the 128 bits, the host part may be longer than 64 bits
+        ipv6_with_host(
+            self,
+            (hash as u128).wrapping_mul(0x9e3779b97f4a7c15_f39cc0605cedc835),
+        )
     }
 }
 
+fn ipv6_with_host(cidr: &Ipv6Cidr, host: u128) -> IpAddr {
+    let ipv6: u128 = cidr.first_address().into();
+    let prefix_len = cidr.network_length();
+    let net_part = (ipv6 >> (128 - prefix_len)) << (128 - prefix_len);
+    let host_part = (host << prefix_len) >> prefix_len;
+    IpAddr::V6((net_part | host_part).into())
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum InnerProxy {
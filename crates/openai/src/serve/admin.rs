@@ -0,0 +1,299 @@
+use axum::extract::{Path, Query};
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::{header, HeaderName, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router, TypedHeader};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Instant;
+
+use super::error::ResponseError;
+use super::puid;
+use super::realip::ClientIp;
+use crate::arkose::funcaptcha::solver::SolverStatus;
+use crate::client::{ClientAgent, ClientEntry, HealthStatus};
+use crate::context::arkose::har;
+use crate::context::authkey::{AuthKey, AuthKeyDenied, Scope};
+use crate::context::whitelist::EmailRule;
+use crate::{arkose, generate_random_string, info, warn, with_context, URL_CHATGPT_API};
+
+/// Length of the generated auth key
+const AUTH_KEY_LENGTH: usize = 32;
+
+/// The whitelist and auth key changes of the admin API are kept in memory only,
+/// they are lost on restart and replaced when the config is reloaded
+const EPHEMERAL_WARNING: &str =
+    r#"299 ninja "Not persisted, the change is lost on restart or config reload""#;
+
+/// Warning header of the changes that are not persisted
+fn ephemeral() -> [(HeaderName, &'static str); 1] {
+    warn!("The admin API change is not persisted, update the config to keep it");
+    [(header::WARNING, EPHEMERAL_WARNING)]
+}
+
+/// Admin API routes, mounted under /admin/api
+pub(super) fn config(router: Router) -> Router {
+    let admin = Router::new()
+        .route("/whitelist", get(get_whitelist).post(post_whitelist))
+        .route("/whitelist/:email", delete(delete_whitelist))
+        .route("/auth_keys", get(get_auth_keys).put(put_auth_key))
+        .route("/auth_keys/:name", delete(delete_auth_key))
+        .route("/auth_keys/:name/rotate", post(rotate_auth_key))
+        .route("/puid", get(get_puid).delete(delete_puid))
+        .route("/preauth", get(get_preauth).delete(delete_preauth))
+        .route("/har", get(get_har))
+        .route("/clients", get(get_clients))
+        .route("/arkose/upgrade", post(post_arkose_upgrade))
+        .route("/arkose/pool", get(get_arkose_pool))
+        .route("/arkose/solvers", get(get_arkose_solvers))
+        .route_layer(axum::middleware::from_fn(admin_middleware));
+    router.nest("/admin/api", admin)
+}
+
+/// Require the auth key of the admin scope, the API is closed when no key grants it
+async fn admin_middleware<B>(
+    ClientIp(ip): ClientIp,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    let key = with_context!(auth_keys)
+        .verify(bearer.as_ref().map(|b| b.token()), Scope::Admin, ip)?
+        .ok_or(AuthKeyDenied::Required)?;
+    info!(
+        "Admin API {} {} by `{}`",
+        request.method(),
+        request.uri().path(),
+        key.name
+    );
+    Ok(next.run(request).await)
+}
+
+/// Mask the key value, only the first 4 characters are kept
+fn mask(key: &str) -> String {
+    let prefix = key.chars().take(4).collect::<String>();
+    format!("{prefix}****")
+}
+
+/// Whitelist rule, e.g. `a@example.com`, `*@example.com`, `/^dev-.*@example\.com$/` or `!intern@example.com`
+#[derive(Deserialize)]
+struct WhitelistEntry {
+    email: String,
+}
+
+/// GET /admin/api/whitelist, the file rules are read-only
+async fn get_whitelist() -> Json<Value> {
+    let whitelist = with_context!(visitor_email_whitelist);
+    Json(json!({ "rules": whitelist.rules(), "file_rules": whitelist.file_rules() }))
+}
+
+/// POST /admin/api/whitelist, the rule is not persisted
+async fn post_whitelist(Json(entry): Json<WhitelistEntry>) -> Result<Response, ResponseError> {
+    let rule = EmailRule::from_str(&entry.email).map_err(ResponseError::BadRequest)?;
+    match with_context!(visitor_email_whitelist).add(rule) {
+        true => Ok((StatusCode::CREATED, ephemeral()).into_response()),
+        false => Ok(StatusCode::OK.into_response()),
+    }
+}
+
+/// DELETE /admin/api/whitelist/:email, the removal is not persisted.
+/// The last allow rule is kept, otherwise every email would be allowed
+async fn delete_whitelist(Path(email): Path<String>) -> Result<Response, ResponseError> {
+    match with_context!(visitor_email_whitelist)
+        .remove(&email)
+        .map_err(ResponseError::Conflict)?
+    {
+        true => Ok((StatusCode::NO_CONTENT, ephemeral()).into_response()),
+        false => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+/// GET /admin/api/auth_keys, the key values are masked
+async fn get_auth_keys() -> Json<Vec<AuthKey>> {
+    let keys = with_context!(auth_keys)
+        .list()
+        .into_iter()
+        .map(|k| AuthKey {
+            key: mask(&k.key),
+            ..k
+        })
+        .collect();
+    Json(keys)
+}
+
+/// PUT /admin/api/auth_keys, the key with the same name is replaced, the key is not persisted
+async fn put_auth_key(Json(key): Json<AuthKey>) -> Response {
+    with_context!(auth_keys).upsert(key);
+    (StatusCode::NO_CONTENT, ephemeral()).into_response()
+}
+
+/// DELETE /admin/api/auth_keys/:name, the removal is not persisted
+async fn delete_auth_key(Path(name): Path<String>) -> Response {
+    match with_context!(auth_keys).remove(&name) {
+        true => (StatusCode::NO_CONTENT, ephemeral()).into_response(),
+        false => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// POST /admin/api/auth_keys/:name/rotate, returns the new key value, the new value is not persisted
+async fn rotate_auth_key(Path(name): Path<String>) -> Response {
+    let key = generate_random_string(AUTH_KEY_LENGTH);
+    match with_context!(auth_keys).rotate(&name, key.clone()) {
+        true => (ephemeral(), Json(json!({ "name": name, "key": key }))).into_response(),
+        false => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// GET /admin/api/puid
+async fn get_puid() -> Result<Json<Value>, ResponseError> {
+    let keys = puid::cached_keys()
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+    Ok(Json(json!({ "count": keys.len(), "keys": keys })))
+}
+
+/// DELETE /admin/api/puid
+async fn delete_puid() -> Result<Json<Value>, ResponseError> {
+    let flushed = puid::flush()
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+    Ok(Json(json!({ "flushed": flushed })))
+}
+
+/// GET /admin/api/preauth
+async fn get_preauth() -> Json<Value> {
+    #[cfg(feature = "preauth")]
+    let cookies = with_context!(preauth_cookies);
+    #[cfg(not(feature = "preauth"))]
+    let cookies: Vec<String> = vec![];
+    Json(json!({ "count": cookies.len(), "cookies": cookies }))
+}
+
+/// DELETE /admin/api/preauth
+async fn delete_preauth() -> Result<StatusCode, ResponseError> {
+    #[cfg(feature = "preauth")]
+    with_context!(flush_preauth_cookies)
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /admin/api/har
+async fn get_har() -> Result<Json<HashMap<arkose::Type, Vec<String>>>, ResponseError> {
+    har::list_files()
+        .map(Json)
+        .map_err(ResponseError::InternalServerError)
+}
+
+#[derive(Deserialize)]
+struct ClientQuery {
+    /// Probe the upstream through each client
+    #[serde(default)]
+    probe: bool,
+}
+
+/// Upstream probe result
+#[derive(Serialize)]
+struct Probe {
+    status: Option<u16>,
+    latency_ms: u128,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ClientStatus {
+    pool: &'static str,
+    #[serde(flatten)]
+    entry: ClientEntry,
+    health: HealthStatus,
+    probe: Option<Probe>,
+}
+
+/// Probe the upstream through the client
+async fn probe(agent: &ClientAgent) -> Probe {
+    let client = match agent {
+        ClientAgent::Api(client) | ClientAgent::Arkose(client) => client,
+        ClientAgent::Auth(client) => client.http(),
+    };
+
+    let now = Instant::now();
+    let result = client.head(URL_CHATGPT_API).send().await;
+    let latency_ms = now.elapsed().as_millis();
+    match result {
+        Ok(resp) => Probe {
+            status: Some(resp.status().as_u16()),
+            latency_ms,
+            error: None,
+        },
+        Err(err) => Probe {
+            status: None,
+            latency_ms,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+/// GET /admin/api/clients?probe=true, the probe does not affect the member health
+async fn get_clients(Query(query): Query<ClientQuery>) -> Json<Value> {
+    let mut clients = vec![];
+    let mut ipv6_subnets = HashMap::new();
+
+    for (pool, balancer) in with_context!(client_pools) {
+        for (entry, agent, health) in balancer.entries() {
+            let probe = match query.probe {
+                true => Some(probe(agent).await),
+                false => None,
+            };
+            clients.push(ClientStatus {
+                pool,
+                entry: entry.clone(),
+                health: health.status(),
+                probe,
+            });
+        }
+        ipv6_subnets.insert(pool, balancer.ipv6_subnets().to_vec());
+    }
+
+    Json(json!({ "clients": clients, "ipv6_subnets": ipv6_subnets }))
+}
+
+/// GET /admin/api/arkose/pool, the fresh pre-fetched tokens by type
+async fn get_arkose_pool() -> Json<HashMap<arkose::Type, usize>> {
+    Json(
+        with_context!(arkose_token_pool)
+            .map(|pool| pool.sizes())
+            .unwrap_or_default(),
+    )
+}
+
+/// GET /admin/api/arkose/solvers, the solver statistics in the failover order of the config
+async fn get_arkose_solvers() -> Json<Vec<SolverStatus>> {
+    Json(
+        with_context!(arkose_solver)
+            .map(|chain| chain.status())
+            .unwrap_or_default(),
+    )
+}
+
+/// POST /admin/api/arkose/upgrade
+async fn post_arkose_upgrade() -> Json<HashMap<arkose::Type, String>> {
+    let context = with_context!(arkose_context);
+    context.upgrade().await;
+
+    let versions = [
+        arkose::Type::Auth,
+        arkose::Type::GPT3,
+        arkose::Type::GPT4,
+        arkose::Type::Platform,
+        arkose::Type::SignUp,
+    ]
+    .into_iter()
+    .filter_map(|t| context.version(t).map(|v| (t, v.version().to_owned())))
+    .collect();
+    Json(versions)
+}
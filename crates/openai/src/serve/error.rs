@@ -1,4 +1,5 @@
 use crate::auth::error::AuthError;
+use crate::context::authkey::AuthKeyDenied;
 use axum::http::header::{CONTENT_TYPE, LOCATION};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
@@ -10,8 +11,6 @@ use eventsource_stream::EventStreamError;
 pub enum ProxyError {
     #[error("Session not found")]
     SessionNotFound,
-    #[error("Authentication Key error")]
-    AuthKeyError,
     #[error("AccessToken is required")]
     AccessTokenRequired,
     #[error("Model required")]
@@ -36,14 +35,26 @@ pub enum ProxyError {
     TooManyRequests,
     #[error("Your access is not in the whitelist")]
     AccessNotInWhitelist,
-    #[error("Auth Key required!")]
-    AuthKeyRequired,
     #[error("Event-source stream error ({0})")]
     EventSourceStreamError(EventStreamError<reqwest::Error>),
     #[error("Deserialize error ({0})")]
     DeserializeError(serde_json::Error),
     #[error("Invalid access token")]
     InvalidAccessToken,
+    #[error("Invalid image url")]
+    InvalidImageUrl,
+    #[error("The image url must resolve to a public address")]
+    ImageUrlNotAllowed,
+    #[error("The image is larger than {0} bytes")]
+    ImageTooLarge(usize),
+    #[error("Too many choices, at most {0} are supported")]
+    TooManyChoices(usize),
+    #[error("Only a single prompt is supported")]
+    MultiplePromptsNotSupported,
+    #[error("The model `{0}` does not exist")]
+    ModelNotFound(String),
+    #[error("No account is available in the account pool")]
+    NoAvailableAccount,
 
     /// get access token profile error
     #[error("Get access token profile error")]
@@ -146,6 +157,18 @@ where
             };
         }
 
+        // Auth key rejection
+        if let Some(denied) = err.downcast_ref::<AuthKeyDenied>() {
+            return match denied {
+                // 401
+                AuthKeyDenied::Required => make_error(StatusCode::UNAUTHORIZED),
+                // 429
+                AuthKeyDenied::RateLimited(_) => make_error(StatusCode::TOO_MANY_REQUESTS),
+                // 403
+                _ => make_error(StatusCode::FORBIDDEN),
+            };
+        }
+
         // default 500
         make_error(StatusCode::INTERNAL_SERVER_ERROR)
     }
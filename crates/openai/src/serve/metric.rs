@@ -0,0 +1,151 @@
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::header;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::TypedHeader;
+use metrics::{describe_counter, describe_histogram, histogram, increment_counter, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use super::error::ResponseError;
+use super::realip::ClientIp;
+use crate::context::authkey::{AuthKeyDenied, Scope};
+use crate::with_context;
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Latency histogram buckets (seconds)
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Install the prometheus recorder
+pub(super) fn init() -> anyhow::Result<()> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), LATENCY_BUCKETS)?
+        .install_recorder()?;
+
+    describe_counter!("ninja_requests_total", "Requests by route group");
+    describe_histogram!(
+        "ninja_request_duration_seconds",
+        Unit::Seconds,
+        "Request latency by route group"
+    );
+    describe_counter!(
+        "ninja_toapi_requests_total",
+        "Requests translated to the ChatGPT API"
+    );
+    describe_histogram!(
+        "ninja_toapi_request_duration_seconds",
+        Unit::Seconds,
+        "Latency of the requests translated to the ChatGPT API"
+    );
+    describe_counter!(
+        "ninja_upstream_responses_total",
+        "Upstream responses by status code"
+    );
+    describe_counter!("ninja_arkose_tokens_total", "Arkose token outcomes");
+    describe_histogram!(
+        "ninja_arkose_solver_duration_seconds",
+        Unit::Seconds,
+        "Arkose solver latency"
+    );
+    describe_counter!(
+        "ninja_arkose_solver_errors_total",
+        "Arkose solver failures by error code"
+    );
+    describe_counter!(
+        "ninja_token_bucket_rejections_total",
+        "Requests rejected by the token bucket"
+    );
+    describe_counter!(
+        "ninja_limit_policy_rejections_total",
+        "Requests rejected by the route group limit policies"
+    );
+    describe_counter!("ninja_puid_cache_total", "PUID cache lookups");
+    describe_counter!(
+        "ninja_arkose_pool_total",
+        "Arkose token pre-fetch pool lookups"
+    );
+
+    HANDLE
+        .set(handle)
+        .map_err(|_| anyhow::anyhow!("Metrics recorder is already initialized"))
+}
+
+/// Get the route group of the request path
+pub(crate) fn route_group(path: &str) -> &'static str {
+    ["/backend-api", "/public-api", "/v1", "/auth", "/dashboard"]
+        .into_iter()
+        .find(|group| path.starts_with(group))
+        .unwrap_or("other")
+}
+
+/// Record the request served by the route group
+pub(crate) fn request(group: &'static str, method: String, status: StatusCode, elapsed: Duration) {
+    increment_counter!(
+        "ninja_requests_total",
+        "group" => group,
+        "method" => method,
+        "status" => status.as_u16().to_string()
+    );
+    histogram!(
+        "ninja_request_duration_seconds",
+        elapsed.as_secs_f64(),
+        "group" => group
+    );
+}
+
+/// Record the request translated to the ChatGPT API
+pub(crate) fn toapi_request(status: Option<StatusCode>, elapsed: Duration) {
+    let status = status
+        .map(|s| s.as_u16().to_string())
+        .unwrap_or_else(|| "error".to_owned());
+    increment_counter!("ninja_toapi_requests_total", "status" => status);
+    histogram!(
+        "ninja_toapi_request_duration_seconds",
+        elapsed.as_secs_f64()
+    );
+}
+
+/// Record the upstream response status code
+pub(crate) fn upstream_response(origin: &'static str, status: StatusCode) {
+    increment_counter!(
+        "ninja_upstream_responses_total",
+        "origin" => origin,
+        "status" => status.as_u16().to_string()
+    );
+}
+
+/// Record the request rejected by the token bucket
+pub(crate) fn token_bucket_rejected() {
+    increment_counter!("ninja_token_bucket_rejections_total");
+}
+
+/// Record the request rejected by the route group limit policy
+pub(crate) fn limit_policy_rejected(algorithm: &'static str) {
+    increment_counter!("ninja_limit_policy_rejections_total", "algorithm" => algorithm);
+}
+
+/// Record the PUID cache lookup
+pub(crate) fn puid_cache(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    increment_counter!("ninja_puid_cache_total", "result" => result);
+}
+
+/// GET /metrics
+pub(super) async fn get_metrics(
+    ClientIp(ip): ClientIp,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Response, ResponseError> {
+    // Require an auth key of the metrics scope, the endpoint is closed when no key grants it
+    with_context!(auth_keys)
+        .verify(bearer.as_ref().map(|b| b.token()), Scope::Metrics, ip)?
+        .ok_or(AuthKeyDenied::Required)?;
+
+    let body = HANDLE
+        .get()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default();
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response())
+}
@@ -1,10 +1,15 @@
+use crate::context::authkey::{Scope, AUTH_KEY_HEADER};
 use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::pool;
+use crate::serve::realip::ClientIp;
 use crate::serve::whitelist;
 use crate::token;
+use crate::with_context;
 use axum::http::header;
 use axum::{http::Request, middleware::Next, response::Response};
 
 pub(crate) async fn auth_middleware<B>(
+    ClientIp(ip): ClientIp,
     request: Request<B>,
     next: Next<B>,
 ) -> Result<Response, ResponseError> {
@@ -16,12 +21,26 @@ pub(crate) async fn auth_middleware<B>(
         return Ok(next.run(request).await);
     };
 
+    // Require the auth key of the route group, the authorization header carries the access token
+    if let Some(scope) = Scope::from_path(request.uri().path()) {
+        let key = request
+            .headers()
+            .get(AUTH_KEY_HEADER)
+            .and_then(|v| v.to_str().ok());
+        with_context!(auth_keys).verify(key, scope, ip)?;
+    }
+
     // Check if the request has an authorization header
     let token = match request.headers().get(header::AUTHORIZATION) {
         Some(token) => token,
         None => return Err(ResponseError::Unauthorized(ProxyError::AccessTokenRequired)),
     };
 
+    // The account pool API key is issued by ninja
+    if token.to_str().map(pool::is_pool_key).unwrap_or_default() {
+        return Ok(next.run(request).await);
+    }
+
     // Check if the token is valid
     match token::check_for_u8(token.as_bytes()) {
         Ok(Some(profile)) => {
@@ -1,25 +1,186 @@
+use crate::context::args::Args;
+use crate::context::authkey::AUTH_KEY_HEADER;
 use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::metric;
+use crate::serve::pool;
+use crate::serve::realip::ClientIp;
+use crate::{token, warn, with_context};
 use axum::{
-    extract::{ConnectInfo, State},
-    http::Request,
+    body::Bytes,
+    extract::{FromRequest, State},
+    http::{header, Method, Request},
     middleware::Next,
     response::Response,
 };
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
-use super::tokenbucket::{TokenBucket, TokenBucketProvider};
+use super::policy::PolicyLimiter;
+use super::tokenbucket::{BucketKey, LimitKey, Limits, Strategy, TokenBucket, TokenBucketProvider};
+
+/// Token bucket and route group policies with the limit key
+pub(crate) struct Limiter {
+    key: LimitKey,
+    bucket: TokenBucketProvider,
+    policy: PolicyLimiter,
+}
+
+impl Limiter {
+    pub fn new(key: LimitKey, bucket: TokenBucketProvider, policy: PolicyLimiter) -> Self {
+        Self {
+            key,
+            bucket,
+            policy,
+        }
+    }
+
+    /// Build the limiter from the serve args
+    pub fn from_args(args: &Args) -> anyhow::Result<Self> {
+        let strategy = Strategy::from_str(args.tb_strategy.as_str())?;
+        if matches!(strategy, Strategy::Redis) && args.redis_url.is_none() {
+            anyhow::bail!("The redis token bucket strategy requires the redis url")
+        }
+        let bucket = TokenBucketProvider::from((
+            strategy,
+            args.tb_enable,
+            Limits::new(args.tb_capacity, args.tb_fill_rate, &args.tb_rules)?,
+            args.tb_expired,
+        ));
+        Ok(Self::new(
+            LimitKey::from_str(&args.tb_key)?,
+            bucket,
+            PolicyLimiter::new(args.limit_policies.clone()),
+        ))
+    }
+
+    /// Check if the limit settings are changed, the in-memory buckets are reset when the limiter is rebuilt
+    pub fn changed(old: &Args, new: &Args) -> bool {
+        old.tb_enable != new.tb_enable
+            || old.tb_strategy != new.tb_strategy
+            || old.tb_capacity != new.tb_capacity
+            || old.tb_fill_rate != new.tb_fill_rate
+            || old.tb_expired != new.tb_expired
+            || old.tb_key != new.tb_key
+            || old.tb_rules != new.tb_rules
+            || old.limit_policies != new.limit_policies
+    }
+
+    /// Get the bucket key of the request, fallback to the client ip.
+    /// Only a configured auth key keys the bucket, otherwise rotating random keys would get fresh buckets
+    fn bucket_key<B>(&self, request: &Request<B>, ip: IpAddr) -> BucketKey {
+        let value = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim_start_matches("Bearer "))
+                .filter(|v| !v.is_empty())
+        };
+        let bearer = value(header::AUTHORIZATION.as_str());
+
+        let key = match self.key {
+            LimitKey::Email => bearer
+                .and_then(|bearer| token::check(bearer).ok().flatten())
+                .map(|profile| BucketKey::Email(profile.email().to_owned())),
+            LimitKey::AuthKey => value(AUTH_KEY_HEADER)
+                .or(bearer)
+                .filter(|key| {
+                    pool::is_pool_key(key) || with_context!(auth_keys).find(key).is_some()
+                })
+                .map(|key| BucketKey::AuthKey(key.to_owned())),
+            LimitKey::Ip => None,
+        };
+
+        key.unwrap_or(BucketKey::Ip(ip))
+    }
+}
+
+/// The limiter, replaced when the config is reloaded
+pub(crate) struct LimitContext(RwLock<Arc<Limiter>>);
+
+impl LimitContext {
+    pub fn new(limiter: Limiter) -> Self {
+        Self(RwLock::new(Arc::new(limiter)))
+    }
+
+    /// Get the current limiter
+    fn load(&self) -> Arc<Limiter> {
+        self.0.read().expect("limiter lock poisoned").clone()
+    }
+
+    /// Replace the limiter
+    pub fn swap(&self, limiter: Limiter) {
+        *self.0.write().expect("limiter lock poisoned") = Arc::new(limiter);
+    }
+}
+
+/// Extract the model of the request body, the body is restored
+async fn extract_model<B>(
+    request: Request<B>,
+) -> Result<(Request<B>, Option<String>), ResponseError>
+where
+    Bytes: FromRequest<(), B>,
+    <Bytes as FromRequest<(), B>>::Rejection: std::error::Error + Send + Sync + 'static,
+    B: From<Bytes> + Send + 'static,
+{
+    #[derive(Deserialize)]
+    struct ModelBody {
+        model: Option<String>,
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = Bytes::from_request(Request::new(body), &())
+        .await
+        .map_err(ResponseError::BadRequest)?;
+    let model = serde_json::from_slice::<ModelBody>(&bytes)
+        .ok()
+        .and_then(|body| body.model);
+    Ok((Request::from_parts(parts, B::from(bytes)), model))
+}
 
 pub(crate) async fn limit_middleware<B>(
-    State(limit): State<std::sync::Arc<TokenBucketProvider>>,
-    ConnectInfo(socket_addr): ConnectInfo<std::net::SocketAddr>,
+    State(limit): State<std::sync::Arc<LimitContext>>,
+    ClientIp(ip): ClientIp,
     request: Request<B>,
     next: Next<B>,
-) -> Result<Response, ResponseError> {
-    let addr = socket_addr.ip();
-    match limit.acquire(addr) {
-        Ok(condition) => match condition {
-            true => Ok(next.run(request).await),
-            false => Err(ResponseError::TooManyRequests(ProxyError::TooManyRequests)),
-        },
-        Err(err) => Err(ResponseError::BadGateway(err)),
+) -> Result<Response, ResponseError>
+where
+    Bytes: FromRequest<(), B>,
+    <Bytes as FromRequest<(), B>>::Rejection: std::error::Error + Send + Sync + 'static,
+    B: From<Bytes> + Send + 'static,
+{
+    let limit = limit.load();
+    let key = limit.bucket_key(&request, ip);
+    // A failing limiter store must not take the service down, the request is let through
+    match limit.bucket.acquire(&key).await {
+        Ok(true) => {}
+        Ok(false) => {
+            metric::token_bucket_rejected();
+            return Err(ResponseError::TooManyRequests(ProxyError::TooManyRequests));
+        }
+        Err(err) => warn!("Token bucket error, the request is not limited: {err}"),
+    }
+
+    // Route group policies, the model cap requires the model of the request body
+    let group = metric::route_group(request.uri().path());
+    let (request, model) = if request.method() == Method::POST && limit.policy.requires_model(group)
+    {
+        extract_model(request).await?
+    } else {
+        (request, None)
+    };
+
+    match limit.policy.acquire(group, &key, model.as_deref()) {
+        Ok(None) => Ok(next.run(request).await),
+        Ok(Some(algorithm)) => {
+            metric::limit_policy_rejected(algorithm);
+            Err(ResponseError::TooManyRequests(ProxyError::TooManyRequests))
+        }
+        Err(err) => {
+            warn!("Limit policy error, the request is not limited: {err}");
+            Ok(next.run(request).await)
+        }
     }
 }
@@ -0,0 +1,12 @@
+use crate::serve::metric;
+use axum::{http::Request, middleware::Next, response::Response};
+use std::time::Instant;
+
+pub(crate) async fn metric_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let group = metric::route_group(request.uri().path());
+    let method = request.method().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metric::request(group, method, response.status(), start.elapsed());
+    response
+}
@@ -2,5 +2,8 @@ pub mod auth;
 pub mod csrf;
 #[cfg(feature = "limit")]
 pub mod limit;
+pub mod metric;
+#[cfg(feature = "limit")]
+pub mod policy;
 #[cfg(feature = "limit")]
 pub mod tokenbucket;
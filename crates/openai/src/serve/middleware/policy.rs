@@ -0,0 +1,432 @@
+use native_db::*;
+use native_model::{native_model, Model};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use super::tokenbucket::{database, BucketKey};
+use crate::{debug, error, now_duration};
+
+/// Interval to flush the counted requests to the database
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Flushes between the sweeps of the expired states
+const SWEEP_EVERY: u32 = 12;
+
+/// Limit policy of the route group
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LimitPolicy {
+    /// Route group, e.g. /backend-api, /v1, /dashboard
+    pub group: String,
+    #[serde(flatten)]
+    pub algorithm: Algorithm,
+}
+
+/// Limit algorithm
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum Algorithm {
+    /// At most `limit` requests in any `window` seconds
+    SlidingWindow { limit: u32, window: u64 },
+    /// At most `limit` requests per calendar day or month (UTC)
+    Quota { limit: u32, period: Period },
+    /// At most `limit` messages to the models in any `window` seconds, e.g. 40 GPT-4 messages per 3 hours
+    ModelCap {
+        limit: u32,
+        window: u64,
+        models: Vec<String>,
+    },
+}
+
+/// Quota period
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    /// Get the period number of the timestamp
+    fn number(&self, timestamp: u64) -> anyhow::Result<u64> {
+        match self {
+            Period::Daily => Ok(timestamp / 86400),
+            Period::Monthly => {
+                let date = time::OffsetDateTime::from_unix_timestamp(timestamp as i64)?;
+                Ok(date.year() as u64 * 12 + u8::from(date.month()) as u64)
+            }
+        }
+    }
+
+    /// Get the end timestamp of the period of the timestamp
+    fn end(&self, timestamp: u64) -> anyhow::Result<u64> {
+        match self {
+            Period::Daily => Ok((timestamp / 86400 + 1) * 86400),
+            Period::Monthly => {
+                let date = time::OffsetDateTime::from_unix_timestamp(timestamp as i64)?;
+                let (year, month) = match date.month() {
+                    time::Month::December => (date.year() + 1, time::Month::January),
+                    month => (date.year(), month.next()),
+                };
+                let start = time::Date::from_calendar_date(year, month, 1)?
+                    .midnight()
+                    .assume_utc();
+                Ok(start.unix_timestamp() as u64)
+            }
+        }
+    }
+}
+
+impl Algorithm {
+    /// Algorithm name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::SlidingWindow { .. } => "sliding_window",
+            Algorithm::Quota { .. } => "quota",
+            Algorithm::ModelCap { .. } => "model_cap",
+        }
+    }
+}
+
+impl LimitPolicy {
+    /// The state id prefix, stable across restarts as long as the policy is unchanged
+    fn id(&self) -> String {
+        match &self.algorithm {
+            Algorithm::SlidingWindow { window, .. } => {
+                format!("{}:sliding_window:{window}", self.group)
+            }
+            Algorithm::Quota { period, .. } => format!("{}:quota:{period:?}", self.group),
+            Algorithm::ModelCap { window, models, .. } => {
+                format!("{}:model_cap:{window}:{}", self.group, models.join(","))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[native_model(id = 3, version = 1)]
+#[native_db]
+pub(super) struct ReDBWindowState {
+    #[primary_key]
+    key: String,
+    /// request timestamps in the window
+    hits: Vec<u64>,
+    /// Unix timestamp (seconds) when the last hit leaves the window
+    expires_at: u64,
+}
+
+impl ReDBWindowState {
+    fn new(key: String) -> Self {
+        Self {
+            key,
+            hits: vec![],
+            expires_at: 0,
+        }
+    }
+
+    /// Sliding window log, the hits out of the window are dropped
+    fn slide(&mut self, now: u64, limit: u32, window: u64) -> bool {
+        self.hits.retain(|hit| now.saturating_sub(*hit) < window);
+        let allowed = (self.hits.len() as u32) < limit;
+        if allowed {
+            self.hits.push(now);
+            self.expires_at = now + window;
+        }
+        allowed
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[native_model(id = 4, version = 1)]
+#[native_db]
+pub(super) struct ReDBQuotaState {
+    #[primary_key]
+    key: String,
+    period: u64,
+    count: u32,
+    /// Unix timestamp (seconds) of the period end
+    expires_at: u64,
+}
+
+/// Hot policy states, shared by the limiters across reloads.
+/// The counted requests are flushed to the database in batches, a crash loses at most a flush interval
+#[derive(Default)]
+struct Store {
+    windows: HashMap<String, ReDBWindowState>,
+    quotas: HashMap<String, ReDBQuotaState>,
+    /// keys counted since the last flush
+    dirty: HashSet<String>,
+}
+
+static STORE: OnceLock<Mutex<Store>> = OnceLock::new();
+
+/// Lock the policy states, the flusher is started on first use
+fn store() -> MutexGuard<'static, Store> {
+    STORE
+        .get_or_init(|| {
+            thread::spawn(flush_every);
+            Mutex::new(Store::default())
+        })
+        .lock()
+        .expect("policy store lock poisoned")
+}
+
+impl Store {
+    /// Get the window state, a missing state is loaded from the database
+    fn window(&mut self, db: &Database<'_>, key: String) -> anyhow::Result<ReDBWindowState> {
+        if let Some(state) = self.windows.get(&key) {
+            return Ok(state.clone());
+        }
+        let state = db
+            .r_transaction()?
+            .get()
+            .primary::<ReDBWindowState>(key.as_str())?
+            .unwrap_or_else(|| ReDBWindowState::new(key.clone()));
+        self.windows.insert(key, state.clone());
+        Ok(state)
+    }
+
+    /// Get the quota state, a missing state is loaded from the database
+    fn quota(&mut self, db: &Database<'_>, key: String) -> anyhow::Result<Option<ReDBQuotaState>> {
+        if let Some(state) = self.quotas.get(&key) {
+            return Ok(Some(state.clone()));
+        }
+        let state = db
+            .r_transaction()?
+            .get()
+            .primary::<ReDBQuotaState>(key.as_str())?;
+        if let Some(state) = &state {
+            self.quotas.insert(key, state.clone());
+        }
+        Ok(state)
+    }
+}
+
+fn flush_every() {
+    let mut flushes = 0u32;
+    loop {
+        thread::sleep(FLUSH_INTERVAL);
+        flushes = flushes.wrapping_add(1);
+        if let Err(err) = flush(flushes % SWEEP_EVERY == 0) {
+            error!("Error flushing the limit policy states: {err}");
+        }
+    }
+}
+
+/// Write the counted states in one transaction, the expired states are removed on sweeps
+fn flush(sweep: bool) -> anyhow::Result<()> {
+    let now = now_duration()?.as_secs();
+    let (windows, quotas) = {
+        let mut store = store();
+        if sweep {
+            store.windows.retain(|_, state| state.expires_at > now);
+            store.quotas.retain(|_, state| state.expires_at > now);
+        }
+        let dirty = std::mem::take(&mut store.dirty);
+        let windows = dirty
+            .iter()
+            .filter_map(|key| store.windows.get(key).cloned())
+            .collect::<Vec<_>>();
+        let quotas = dirty
+            .iter()
+            .filter_map(|key| store.quotas.get(key).cloned())
+            .collect::<Vec<_>>();
+        (windows, quotas)
+    };
+
+    if !sweep && windows.is_empty() && quotas.is_empty() {
+        return Ok(());
+    }
+
+    let db = database();
+    let (expired_windows, expired_quotas) = if sweep {
+        let r = db.r_transaction()?;
+        let expired_windows = r
+            .scan()
+            .primary::<ReDBWindowState>()?
+            .all()
+            .filter(|state| state.expires_at <= now)
+            .collect::<Vec<_>>();
+        let expired_quotas = r
+            .scan()
+            .primary::<ReDBQuotaState>()?
+            .all()
+            .filter(|state| state.expires_at <= now)
+            .collect::<Vec<_>>();
+        (expired_windows, expired_quotas)
+    } else {
+        Default::default()
+    };
+
+    debug!(
+        "ReDB flushing {} limit policy states, removing {} expired",
+        windows.len() + quotas.len(),
+        expired_windows.len() + expired_quotas.len()
+    );
+
+    // Remove before insert, a counted state replaces its expired row
+    let rw = db.rw_transaction()?;
+    for state in expired_windows {
+        rw.remove(state)?;
+    }
+    for state in expired_quotas {
+        rw.remove(state)?;
+    }
+    for state in windows {
+        rw.insert(state)?;
+    }
+    for state in quotas {
+        rw.insert(state)?;
+    }
+    rw.commit()?;
+    Ok(())
+}
+
+/// Route group limiter, the states are kept in memory and persisted in the limiter database
+pub struct PolicyLimiter {
+    policies: Vec<LimitPolicy>,
+}
+
+impl PolicyLimiter {
+    pub fn new(policies: Vec<LimitPolicy>) -> Self {
+        Self { policies }
+    }
+
+    /// Check if the route group has a model cap policy
+    pub fn requires_model(&self, group: &str) -> bool {
+        self.policies
+            .iter()
+            .any(|p| p.group.eq(group) && matches!(p.algorithm, Algorithm::ModelCap { .. }))
+    }
+
+    /// Acquire a request of the route group, the request is only counted when all policies allow it.
+    /// Returns the name of the rejecting algorithm
+    pub fn acquire(
+        &self,
+        group: &str,
+        key: &BucketKey,
+        model: Option<&str>,
+    ) -> anyhow::Result<Option<&'static str>> {
+        if !self.policies.iter().any(|p| p.group.eq(group)) {
+            return Ok(None);
+        }
+
+        let now = now_duration()?.as_secs();
+        let db = database();
+        let mut store = store();
+        let mut windows = Vec::new();
+        let mut quotas = Vec::new();
+
+        for policy in self.policies.iter().filter(|p| p.group.eq(group)) {
+            let pk = format!("{}:{}", policy.id(), key.id());
+            let allowed = match &policy.algorithm {
+                Algorithm::SlidingWindow { limit, window } => {
+                    let mut state = store.window(&db, pk)?;
+                    let allowed = state.slide(now, *limit, *window);
+                    windows.push(state);
+                    allowed
+                }
+                Algorithm::ModelCap {
+                    limit,
+                    window,
+                    models,
+                } => match model {
+                    Some(model) if models.iter().any(|m| model.starts_with(m.as_str())) => {
+                        let mut state = store.window(&db, pk)?;
+                        let allowed = state.slide(now, *limit, *window);
+                        windows.push(state);
+                        allowed
+                    }
+                    _ => true,
+                },
+                Algorithm::Quota { limit, period } => {
+                    let number = period.number(now)?;
+                    let mut state = match store.quota(&db, pk.clone())? {
+                        Some(state) if state.period == number => state,
+                        _ => ReDBQuotaState {
+                            key: pk,
+                            period: number,
+                            count: 0,
+                            expires_at: period.end(now)?,
+                        },
+                    };
+                    let allowed = state.count < *limit;
+                    state.count += 1;
+                    quotas.push(state);
+                    allowed
+                }
+            };
+
+            // The request is not counted by any policy
+            if !allowed {
+                return Ok(Some(policy.algorithm.name()));
+            }
+        }
+
+        for state in windows {
+            store.dirty.insert(state.key.clone());
+            store.windows.insert(state.key.clone(), state);
+        }
+        for state in quotas {
+            store.dirty.insert(state.key.clone());
+            store.quotas.insert(state.key.clone(), state);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_policy() {
+        let policies: Vec<LimitPolicy> = serde_json::from_str(
+            r#"[
+                {"group": "/v1", "algorithm": "quota", "limit": 1000, "period": "daily"},
+                {"group": "/backend-api", "algorithm": "model_cap", "limit": 40, "window": 10800, "models": ["gpt-4"]}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(policies[0].algorithm.name(), "quota");
+        assert_eq!(policies[1].id(), "/backend-api:model_cap:10800:gpt-4");
+        assert!(PolicyLimiter::new(policies).requires_model("/backend-api"));
+    }
+
+    #[test]
+    fn test_slide() {
+        let mut state = ReDBWindowState::new("key".to_owned());
+        assert!(state.slide(100, 2, 60));
+        assert!(state.slide(110, 2, 60));
+        assert!(!state.slide(150, 2, 60));
+        // The first hit is out of the window
+        assert!(state.slide(160, 2, 60));
+        assert_eq!(state.hits, vec![110, 160]);
+        assert_eq!(state.expires_at, 220);
+    }
+
+    #[test]
+    fn test_period() {
+        // 2023-12-31T23:59:59Z and 2024-01-01T00:00:00Z
+        assert_ne!(
+            Period::Monthly.number(1704067199).unwrap(),
+            Period::Monthly.number(1704067200).unwrap()
+        );
+        assert_eq!(
+            Period::Daily.number(1704067200).unwrap(),
+            Period::Daily.number(1704153599).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_period_end() {
+        // 2023-12-31T23:59:59Z ends at 2024-01-01T00:00:00Z
+        assert_eq!(Period::Daily.end(1704067199).unwrap(), 1704067200);
+        assert_eq!(Period::Monthly.end(1704067199).unwrap(), 1704067200);
+        // 2024-02-10T00:00:00Z ends at 2024-03-01T00:00:00Z
+        assert_eq!(Period::Monthly.end(1707523200).unwrap(), 1709251200);
+    }
+}
@@ -1,14 +1,128 @@
 use moka::sync::Cache;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Once, OnceLock};
 use std::time::Duration;
 
+use crate::context::store;
 use crate::homedir::home_dir;
-use crate::{context, debug, error, now_duration};
+use crate::{context, debug, error, info, now_duration, with_context};
 
-pub trait TokenBucket: Send + Sync {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool>;
+#[trait_variant::make(TokenBucket: Send)]
+pub trait LocalTokenBucket: Sync {
+    async fn acquire(&self, key: &BucketKey) -> anyhow::Result<bool>;
+}
+
+/// Token bucket limit key strategy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LimitKey {
+    #[default]
+    Ip,
+    Email,
+    AuthKey,
+}
+
+impl std::str::FromStr for LimitKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(LimitKey::Ip),
+            "email" => Ok(LimitKey::Email),
+            "auth_key" => Ok(LimitKey::AuthKey),
+            _ => anyhow::bail!("limit key: {} is not supported", s),
+        }
+    }
+}
+
+/// Token bucket key
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+pub enum BucketKey {
+    Ip(IpAddr),
+    Email(String),
+    AuthKey(String),
+}
+
+impl BucketKey {
+    /// The value matched by the bucket rules
+    fn value(&self) -> String {
+        match self {
+            BucketKey::Ip(ip) => ip.to_string(),
+            BucketKey::Email(email) => email.to_owned(),
+            BucketKey::AuthKey(key) => key.to_owned(),
+        }
+    }
+
+    /// The bucket id, the auth key is not stored in plain text
+    pub(super) fn id(&self) -> String {
+        match self {
+            BucketKey::Ip(ip) => format!("ip:{ip}"),
+            BucketKey::Email(email) => format!("email:{email}"),
+            BucketKey::AuthKey(key) => {
+                let digest = Sha256::digest(key.as_bytes());
+                let hex = digest
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>();
+                format!("key:{hex}")
+            }
+        }
+    }
+}
+
+/// Capacity and fill rate of the bucket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BucketRule {
+    capacity: u32,
+    fill_rate: u32,
+}
+
+/// Bucket limits, a specific key can carry its own capacity and fill rate
+#[derive(Clone, Debug)]
+pub struct Limits {
+    default: BucketRule,
+    rules: HashMap<String, BucketRule>,
+}
+
+impl Limits {
+    /// Parse the rules, format: key=capacity/fill_rate
+    pub fn new(capacity: u32, fill_rate: u32, rules: &[String]) -> anyhow::Result<Self> {
+        let mut parsed = HashMap::with_capacity(rules.len());
+        for rule in rules {
+            let (key, limit) = rule
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Invalid token bucket rule: {rule}"))?;
+            let (capacity, fill_rate) = limit
+                .split_once('/')
+                .ok_or_else(|| anyhow::anyhow!("Invalid token bucket rule: {rule}"))?;
+            parsed.insert(
+                key.trim().to_owned(),
+                BucketRule {
+                    capacity: capacity.trim().parse()?,
+                    fill_rate: fill_rate.trim().parse()?,
+                },
+            );
+        }
+
+        Ok(Self {
+            default: BucketRule {
+                capacity,
+                fill_rate,
+            },
+            rules: parsed,
+        })
+    }
+
+    /// Get the rule of the key
+    fn get(&self, key: &BucketKey) -> BucketRule {
+        self.rules
+            .get(&key.value())
+            .copied()
+            .unwrap_or(self.default)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -16,6 +130,7 @@ pub trait TokenBucket: Send + Sync {
 pub enum Strategy {
     Mem,
     ReDB,
+    Redis,
 }
 
 impl Default for Strategy {
@@ -31,6 +146,7 @@ impl std::str::FromStr for Strategy {
         match s {
             "mem" => Ok(Strategy::Mem),
             "redb" => Ok(Strategy::ReDB),
+            "redis" => Ok(Strategy::Redis),
             _ => anyhow::bail!("storage policy: {} is not supported", s),
         }
     }
@@ -44,54 +160,53 @@ struct BucketState {
 
 pub struct MemTokenBucket {
     enable: bool,
-    /// token bucket capacity `capacity`
-    capacity: u32,
-    /// token bucket fill rate `fill_rate`
-    fill_rate: u32,
-    /// ip -> token backet
-    buckets: moka::sync::Cache<IpAddr, BucketState>,
+    /// token bucket capacity and fill rate of the keys
+    limits: Limits,
+    /// key -> token backet
+    buckets: moka::sync::Cache<String, BucketState>,
 }
 
 impl MemTokenBucket {
-    pub fn new(enable: bool, capacity: u32, fill_rate: u32, expired: u32) -> Self {
-        let buckets: Cache<IpAddr, BucketState> = Cache::builder()
+    pub fn new(enable: bool, limits: Limits, expired: u32) -> Self {
+        let buckets: Cache<String, BucketState> = Cache::builder()
             .max_capacity(65535)
             .time_to_idle(Duration::from_secs(expired as u64))
             .build();
         Self {
             enable,
-            capacity,
-            fill_rate,
+            limits,
             buckets,
         }
     }
 }
 
 impl TokenBucket for MemTokenBucket {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
+    async fn acquire(&self, key: &BucketKey) -> anyhow::Result<bool> {
         if !self.enable {
             return Ok(true);
         }
 
         let now_timestamp = now_duration()?.as_secs();
+        let rule = self.limits.get(key);
+        let id = key.id();
 
         let mut bucket = self
             .buckets
-            .entry(ip)
+            .entry(id.clone())
             .or_insert(BucketState {
-                tokens: self.capacity,
+                tokens: rule.capacity,
                 last_time: now_timestamp,
             })
             .into_value();
 
         let elapsed = now_timestamp - bucket.last_time;
-        let tokens_to_add = (elapsed as u32) * self.fill_rate;
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(self.capacity);
+        let tokens_to_add = (elapsed as u32) * rule.fill_rate;
+        bucket.tokens = (bucket.tokens + tokens_to_add).min(rule.capacity);
         bucket.last_time = now_timestamp;
 
         if bucket.tokens > 0 {
             bucket.tokens -= 1;
-            self.buckets.insert(ip, bucket);
+            self.buckets.insert(id, bucket);
             Ok(true)
         } else {
             Ok(false)
@@ -103,63 +218,95 @@ use anyhow::Result;
 use native_db::*;
 use native_model::{native_model, Model};
 
+use super::policy::{ReDBQuotaState, ReDBWindowState};
+
+/// The limiter database file
+const DATABASE_NAME: &str = "limit.db";
+
+/// The token bucket database file before the bucket keys were generalized
+const LEGACY_DATABASE: &str = "token_bucket.db";
+
 static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
+static DATABASE: OnceLock<Arc<Database<'static>>> = OnceLock::new();
+
+/// The limiter database, shared by the token bucket and the limit policies
+pub(super) fn database() -> Arc<Database<'static>> {
+    DATABASE
+        .get_or_init(|| {
+            let builder = DATABASE_BUILDER.get_or_init(|| {
+                let mut builder = DatabaseBuilder::new();
+                builder
+                    .define::<ReDBBucketState>()
+                    .expect("define table failed");
+                builder
+                    .define::<ReDBWindowState>()
+                    .expect("define table failed");
+                builder
+                    .define::<ReDBQuotaState>()
+                    .expect("define table failed");
+                builder
+            });
+
+            let dir = home_dir()
+                .expect("Failed to get home directory")
+                .join(context::WORKER_DIR);
+
+            // The legacy bucket database is no longer opened, it is left to the user
+            let legacy = dir.join(LEGACY_DATABASE);
+            if legacy.is_file() {
+                info!(
+                    "The legacy token bucket database is unused, it can be removed: {}",
+                    legacy.display()
+                );
+            }
+
+            let db = builder
+                .create(dir.join(DATABASE_NAME))
+                .expect("create database failed");
+            Arc::new(db)
+        })
+        .clone()
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[native_model(id = 1, version = 1)]
 #[native_db]
 struct ReDBBucketState {
     #[primary_key]
-    ip: u128,
+    key: String,
     tokens: u32,
     last_time: u64,
 }
 
 #[derive(typed_builder::TypedBuilder)]
-pub struct RedisTokenBucket<'a> {
+pub struct ReDBTokenBucket<'a> {
     enable: bool,
-    /// token bucket capacity `capacity`
-    capacity: u32,
-    /// token bucket fill rate `fill_rate`
-    fill_rate: u32,
+    /// token bucket capacity and fill rate of the keys
+    limits: Limits,
     /// native db
     db: Arc<native_db::Database<'a>>,
 }
 
-impl<'a> RedisTokenBucket<'a> {
-    pub fn new(enable: bool, capacity: u32, fill_rate: u32, expired: u32) -> Self {
-        // create database
-        let builder = DATABASE_BUILDER.get_or_init(|| {
-            let mut builder = DatabaseBuilder::new();
-            builder
-                .define::<ReDBBucketState>()
-                .expect("define table failed");
-            builder
-        });
-
-        let db = builder
-            .create(
-                home_dir()
-                    .expect("Failed to get home directory")
-                    .join(context::WORKER_DIR)
-                    .join("token_bucket.db"),
-            )
-            .expect("create database failed");
-        let db = Arc::new(db);
+/// Bucket expiry (seconds) of the current limiter, updated when the limiter is rebuilt on reload
+static BUCKET_EXPIRED: AtomicU32 = AtomicU32::new(0);
+
+/// The bucket cleaner is shared by the limiters, it is started by the first one
+static CLEANER: Once = Once::new();
+
+impl ReDBTokenBucket<'static> {
+    pub fn new(enable: bool, limits: Limits, expired: u32) -> Self {
+        let db = database();
+        BUCKET_EXPIRED.store(expired.max(1), Ordering::Relaxed);
         // clear expired buckets every expired seconds
-        clear_expired_buckets_every(db.clone(), expired);
-        Self {
-            enable,
-            capacity,
-            fill_rate,
-            db,
-        }
+        CLEANER.call_once(|| clear_expired_buckets_every(db.clone()));
+        Self { enable, limits, db }
     }
 }
 
-fn clear_expired_buckets_every(db: Arc<Database<'static>>, expired: u32) {
+fn clear_expired_buckets_every(db: Arc<Database<'static>>) {
     use std::thread;
     thread::spawn(move || loop {
+        let expired = BUCKET_EXPIRED.load(Ordering::Relaxed);
         thread::sleep(Duration::from_secs(expired.into()));
 
         debug!("ReDB Clearing expired buckets...");
@@ -210,27 +357,28 @@ fn clear_expired_buckets_every(db: Arc<Database<'static>>, expired: u32) {
     });
 }
 
-impl TokenBucket for RedisTokenBucket<'_> {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
+impl TokenBucket for ReDBTokenBucket<'_> {
+    async fn acquire(&self, key: &BucketKey) -> anyhow::Result<bool> {
         if !self.enable {
             return Ok(true);
         }
 
         let rw = self.db.rw_transaction()?;
-        let pk = ip_to_number(ip);
+        let rule = self.limits.get(key);
+        let pk = key.id();
         let now_timestamp = now_duration()?.as_secs();
-        let mut bucket: ReDBBucketState = match rw.get().primary(pk)? {
+        let mut bucket: ReDBBucketState = match rw.get().primary(pk.as_str())? {
             Some(bucket) => bucket,
             None => ReDBBucketState {
-                ip: pk,
-                tokens: self.capacity,
+                key: pk,
+                tokens: rule.capacity,
                 last_time: now_timestamp,
             },
         };
 
         let elapsed = now_timestamp - bucket.last_time;
-        let tokens_to_add = (elapsed as u32) * self.fill_rate;
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(self.capacity);
+        let tokens_to_add = (elapsed as u32) * rule.fill_rate;
+        bucket.tokens = (bucket.tokens + tokens_to_add).min(rule.capacity);
         bucket.last_time = now_timestamp;
 
         if bucket.tokens > 0 {
@@ -244,58 +392,120 @@ impl TokenBucket for RedisTokenBucket<'_> {
     }
 }
 
-fn ip_to_number(ip: IpAddr) -> u128 {
-    match ip {
-        IpAddr::V4(ipv4) => {
-            let octets = ipv4.octets();
-            ((octets[0] as u128) << 24)
-                + ((octets[1] as u128) << 16)
-                + ((octets[2] as u128) << 8)
-                + (octets[3] as u128)
+/// Token bucket script, the bucket is refilled and acquired atomically
+const REDIS_TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local fill_rate = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'last_time')
+local tokens = tonumber(bucket[1]) or capacity
+local last_time = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - last_time) * fill_rate)
+local allowed = 0
+if tokens > 0 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tokens, 'last_time', now)
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+return allowed
+"#;
+
+/// Token bucket shared by the instances through redis
+pub struct RedisTokenBucket {
+    enable: bool,
+    /// token bucket capacity and fill rate of the keys
+    limits: Limits,
+    /// bucket expired (second)
+    expired: u32,
+    script: redis::Script,
+}
+
+impl RedisTokenBucket {
+    pub fn new(enable: bool, limits: Limits, expired: u32) -> Self {
+        Self {
+            enable,
+            limits,
+            expired,
+            script: redis::Script::new(REDIS_TOKEN_BUCKET_SCRIPT),
         }
-        IpAddr::V6(ipv6) => {
-            let octets = ipv6.octets();
-            ((octets[0] as u128) << 120)
-                + ((octets[1] as u128) << 112)
-                + ((octets[2] as u128) << 104)
-                + ((octets[3] as u128) << 96)
-                + ((octets[4] as u128) << 88)
-                + ((octets[5] as u128) << 80)
-                + ((octets[6] as u128) << 72)
-                + ((octets[7] as u128) << 64)
-                + ((octets[8] as u128) << 56)
-                + ((octets[9] as u128) << 48)
-                + ((octets[10] as u128) << 40)
-                + ((octets[11] as u128) << 32)
-                + ((octets[12] as u128) << 24)
-                + ((octets[13] as u128) << 16)
-                + ((octets[14] as u128) << 8)
-                + (octets[15] as u128)
+    }
+}
+
+impl TokenBucket for RedisTokenBucket {
+    async fn acquire(&self, key: &BucketKey) -> anyhow::Result<bool> {
+        if !self.enable {
+            return Ok(true);
         }
+
+        let redis = with_context!(redis)
+            .ok_or_else(|| anyhow::anyhow!("The redis token bucket requires the redis url"))?;
+        let rule = self.limits.get(key);
+        let mut conn = redis.connection().await?;
+        let allowed: u8 = self
+            .script
+            .key(store::key("bucket", &key.id()))
+            .arg(rule.capacity)
+            .arg(rule.fill_rate)
+            .arg(now_duration()?.as_secs())
+            .arg(self.expired)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(allowed == 1)
     }
 }
 
 pub enum TokenBucketProvider {
     Mem(MemTokenBucket),
-    ReDB(RedisTokenBucket<'static>),
+    ReDB(ReDBTokenBucket<'static>),
+    Redis(RedisTokenBucket),
 }
 
-impl From<(Strategy, bool, u32, u32, u32)> for TokenBucketProvider {
-    fn from(value: (Strategy, bool, u32, u32, u32)) -> Self {
+impl From<(Strategy, bool, Limits, u32)> for TokenBucketProvider {
+    fn from(value: (Strategy, bool, Limits, u32)) -> Self {
         let strategy = match value.0 {
-            Strategy::Mem => Self::Mem(MemTokenBucket::new(value.1, value.2, value.3, value.4)),
-            Strategy::ReDB => Self::ReDB(RedisTokenBucket::new(value.1, value.2, value.3, value.4)),
+            Strategy::Mem => Self::Mem(MemTokenBucket::new(value.1, value.2, value.3)),
+            Strategy::ReDB => Self::ReDB(ReDBTokenBucket::new(value.1, value.2, value.3)),
+            Strategy::Redis => Self::Redis(RedisTokenBucket::new(value.1, value.2, value.3)),
         };
         strategy
     }
 }
 
 impl TokenBucket for TokenBucketProvider {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
+    async fn acquire(&self, key: &BucketKey) -> anyhow::Result<bool> {
         let condition = match self {
-            Self::Mem(t) => t.acquire(ip),
-            Self::ReDB(t) => t.acquire(ip),
+            Self::Mem(t) => t.acquire(key).await,
+            Self::ReDB(t) => t.acquire(key).await,
+            Self::Redis(t) => t.acquire(key).await,
         };
         Ok(condition?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let rules = vec![
+            "a@example.com=120/2".to_owned(),
+            "127.0.0.1=10/1".to_owned(),
+        ];
+        let limits = Limits::new(60, 1, &rules).unwrap();
+
+        let email = BucketKey::Email("a@example.com".to_owned());
+        assert_eq!(limits.get(&email).capacity, 120);
+        assert_eq!(limits.get(&email).fill_rate, 2);
+
+        let ip = BucketKey::Ip("127.0.0.1".parse().unwrap());
+        assert_eq!(limits.get(&ip).capacity, 10);
+
+        let other = BucketKey::AuthKey("sk-xxx".to_owned());
+        assert_eq!(limits.get(&other).capacity, 60);
+        assert!(!other.id().contains("sk-xxx"));
+
+        assert!(Limits::new(60, 1, &["a@example.com=120".to_owned()]).is_err());
+    }
+}
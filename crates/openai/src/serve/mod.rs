@@ -1,15 +1,25 @@
+mod admin;
 mod error;
+mod metric;
 mod middleware;
+mod pool;
 #[cfg(feature = "preauth")]
 mod preauth;
 mod proxy;
 mod puid;
+mod realip;
+mod refresher;
+mod reload;
 #[cfg(feature = "template")]
 mod router;
 mod signal;
 mod turnstile;
 mod whitelist;
 
+#[cfg(feature = "limit")]
+pub use self::middleware::policy::{Algorithm, LimitPolicy, Period};
+pub use self::reload::Reloader;
+
 use self::proxy::ext::RequestExt;
 use self::proxy::ext::SendRequestExt;
 use self::proxy::resp::response_convert;
@@ -21,11 +31,13 @@ use crate::auth::provide::AuthProvider;
 use crate::constant::API_AUTH_SESSION_COOKIE_KEY;
 use crate::context;
 use crate::context::args::Args;
+use crate::context::authkey::Scope;
 use crate::dns;
 use crate::proxy::{InnerProxy, Proxy};
 use crate::serve::error::ProxyError;
 use crate::serve::error::ResponseError;
-use crate::serve::middleware::tokenbucket::{Strategy, TokenBucketProvider};
+use crate::serve::middleware::limit::{LimitContext, Limiter};
+use crate::serve::realip::ClientIp;
 use crate::{info, warn, with_context};
 use crate::{URL_CHATGPT_API, URL_PLATFORM_API};
 use axum::body::Body;
@@ -47,7 +59,6 @@ use axum_server::HttpConfig;
 use axum_server::{AddrIncomingConfig, Handle};
 use std::net::SocketAddr;
 use std::ops::Not;
-use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tower_http::trace;
@@ -69,6 +80,18 @@ fn print_boot_message(inner: &Args) {
     info!("Enable direct connection: {}", inner.enable_direct);
     info!("Enable WebUI: {}", inner.enable_webui);
     info!("Enable File endpoint: {}", inner.enable_file_proxy);
+    info!(
+        "Enable conversation continuity: {}",
+        inner.enable_conversation_continuity
+    );
+    info!("Enable account pool: {}", inner.account_pool_key.is_some());
+    info!("Enable token refresh: {}", inner.enable_token_refresh);
+    info!("Enable metrics endpoint: {}", inner.enable_metrics);
+    info!("Enable PROXY protocol: {}", inner.proxy_protocol);
+    info!("Enable redis shared state: {}", inner.redis_url.is_some());
+    inner.trusted_proxies.iter().for_each(|cidr| {
+        info!("Trusted proxy: {cidr}");
+    });
     info!(
         "Enable Arkose token endpoint: {}",
         inner.enable_arkose_proxy
@@ -81,7 +104,7 @@ fn print_boot_message(inner: &Args) {
         "ArkoseLabs GPT-3.5 experiment solver: {}",
         inner.arkose_gpt3_experiment_solver
     );
-    inner.arkose_solver.as_ref().map(|solver| {
+    inner.arkose_solvers.iter().for_each(|solver| {
         info!("ArkoseLabs solver: {:?}", solver.solver);
     });
     inner.arkose_endpoint.as_ref().map(|endpoint| {
@@ -105,11 +128,17 @@ fn print_boot_message(inner: &Args) {
     });
 }
 
-pub struct Serve(Args);
+pub struct Serve(Args, Option<Reloader>);
 
 impl Serve {
     pub fn new(inner: Args) -> Self {
-        Self(inner)
+        Self(inner, None)
+    }
+
+    /// Enable the config reloading
+    pub fn reloader(mut self, reloader: Reloader) -> Self {
+        self.1 = Some(reloader);
+        self
     }
 
     /// from issue: https://github.com/hyperium/hyper/issues/3140
@@ -129,15 +158,28 @@ impl Serve {
         // init context
         context::init(self.0.clone());
 
+        // init metrics recorder
+        if self.0.enable_metrics {
+            metric::init()?;
+        }
+
+        // import account pool
+        if let Some(ref path) = self.0.account_pool_file {
+            pool::import(path)?;
+        }
+
         // init global layer provider
         let global_layer = tower::ServiceBuilder::new()
             .layer(
                 tower_http::trace::TraceLayer::new_for_http()
-                    .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                    .make_span_with(realip::MakeClientSpan)
                     .on_response(trace::DefaultOnResponse::new().level(Level::INFO))
                     .on_request(trace::DefaultOnRequest::new().level(Level::INFO))
                     .on_failure(trace::DefaultOnFailure::new().level(Level::WARN)),
             )
+            .layer(axum::middleware::from_fn(
+                middleware::metric::metric_middleware,
+            ))
             .layer(tower::limit::ConcurrencyLimitLayer::new(
                 self.0.concurrent_limit,
             ))
@@ -157,23 +199,14 @@ impl Serve {
             .layer(axum::extract::DefaultBodyLimit::max(200 * 1024 * 1024));
 
         // init auth layer provider
-        let app_layer = {
-            let limit_context = TokenBucketProvider::from((
-                Strategy::from_str(self.0.tb_strategy.as_str())?,
-                self.0.tb_enable,
-                self.0.tb_capacity,
-                self.0.tb_fill_rate,
-                self.0.tb_expired,
+        let limit_context = Arc::new(LimitContext::new(Limiter::from_args(&self.0)?));
+        let app_layer = tower::ServiceBuilder::new()
+            .layer(axum::middleware::from_fn(middleware::auth::auth_middleware))
+            .layer(axum::middleware::from_fn_with_state(
+                limit_context.clone(),
+                middleware::limit::limit_middleware,
             ));
 
-            tower::ServiceBuilder::new()
-                .layer(axum::middleware::from_fn(middleware::auth::auth_middleware))
-                .layer(axum::middleware::from_fn_with_state(
-                    Arc::new(limit_context),
-                    middleware::limit::limit_middleware,
-                ))
-        };
-
         let router = Router::new()
             .route("/dashboard/*path", any(official_proxy))
             .route("/v1/*path", any(official_proxy))
@@ -187,6 +220,18 @@ impl Serve {
             .route("/auth/sess_token", post(post_sess_token))
             .route("/auth/billing", post(post_billing));
 
+        // Enable metrics endpoint
+        let router = if self.0.enable_metrics {
+            router.route("/metrics", get(metric::get_metrics))
+        } else {
+            router
+        };
+
+        // Admin API, it stays closed until an auth key granted the admin scope is configured,
+        // so an admin key added by a config reload opens it without a restart
+        info!("Admin API: /admin/api");
+        let router = admin::config(router);
+
         let router = router::config(
             // Enable arkose token endpoint proxy
             if self.0.enable_arkose_proxy {
@@ -201,8 +246,13 @@ impl Serve {
         // Signal the server to shutdown using Handle.
         let handle = Handle::new();
 
-        // Spawn a task to gracefully shutdown server.
-        tokio::spawn(signal::graceful_shutdown(handle.clone()));
+        // Spawn a task to gracefully shutdown server, SIGHUP reloads the config when reloading is enabled.
+        tokio::spawn(signal::graceful_shutdown(handle.clone(), self.1.is_some()));
+
+        // Spawn a task to reload the config.
+        if let Some(reloader) = self.1 {
+            tokio::spawn(reloader.watch(self.0.clone(), limit_context));
+        }
 
         // Fast dns test
         dns::fast::load_fastest_dns(self.0.fastest_dns).await?;
@@ -210,9 +260,33 @@ impl Serve {
         // check wan address.
         check_wan_address().await;
 
+        // probe the client pool members.
+        if self.0.proxy_health_interval > 0 {
+            tokio::spawn(periodic_health_check(self.0.proxy_health_interval));
+        }
+
         // upgrade arkose version.
         tokio::spawn(with_context!(arkose_context).periodic_upgrade());
 
+        // pre-fetch arkose tokens.
+        if let Some(pool) = with_context!(arkose_token_pool) {
+            tokio::spawn(pool.periodic_refill());
+        }
+
+        // sync the preauth cookies shared by the instances.
+        #[cfg(feature = "preauth")]
+        if self.0.redis_url.is_some() {
+            tokio::spawn(with_context!(periodic_sync_preauth_cookie));
+        }
+
+        // refresh the pool account tokens.
+        if self.0.enable_token_refresh {
+            tokio::spawn(refresher::periodic_refresh(
+                self.0.token_refresh_interval,
+                self.0.token_refresh_window,
+            ));
+        }
+
         // http server tcp keepalive
         let tcp_keepalive = Duration::from_secs(self.0.tcp_keepalive as u64 + 1);
 
@@ -263,6 +337,9 @@ impl Serve {
                     .expect("Failed to load TLS keypair");
 
                 axum_server::bind_rustls(self.0.bind.unwrap(), tls_config)
+                    .map(|acceptor| {
+                        acceptor.acceptor(realip::ProxyProtocolAcceptor::new(self.0.proxy_protocol))
+                    })
                     .handle(handle)
                     .addr_incoming_config(incoming_config)
                     .http_config(http_config)
@@ -271,6 +348,7 @@ impl Serve {
             }
             _ => {
                 axum_server::bind(self.0.bind.unwrap())
+                    .acceptor(realip::ProxyProtocolAcceptor::new(self.0.proxy_protocol))
                     .handle(handle)
                     .addr_incoming_config(incoming_config)
                     .http_config(http_config)
@@ -340,22 +418,20 @@ async fn post_sess_token(
 
 /// POST /auth/token
 async fn post_access_token(
+    ClientIp(ip): ClientIp,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
     account: axum::Form<AuthAccount>,
 ) -> Result<impl IntoResponse, ResponseError> {
     // check username/email in whitelist
     whitelist::check_whitelist(&account.username).map_err(ResponseError::Forbidden)?;
 
-    if let Some(auth_key) = with_context!(auth_key) {
-        // check bearer token exist
-        let bearer =
-            bearer.ok_or_else(|| ResponseError::Unauthorized(ProxyError::AuthKeyRequired))?;
-        if auth_key.ne(bearer.token()) {
-            return Err(ResponseError::Forbidden(ProxyError::AuthKeyError));
-        }
-    }
+    // Require auth key
+    with_context!(auth_keys).verify(bearer.as_ref().map(|b| b.token()), Scope::Login, ip)?;
 
-    match with_context!(auth_client).do_access_token(&account).await? {
+    let (client, health) = with_context!(checkout_auth_client, Some(account.username.as_str()));
+    let result = client.do_access_token(&account).await;
+    health.observe_auth(&result);
+    match result? {
         AccessToken::Session(session_token) => {
             let resp: Response<Body> = session_token.try_into()?;
             Ok(resp.into_response())
@@ -398,26 +474,22 @@ struct Blob {
 }
 
 async fn get_arkose_token(
+    ClientIp(ip): ClientIp,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
     pk: Path<String>,
     blob: Option<Query<Blob>>,
 ) -> Result<Json<ArkoseToken>, ResponseError> {
     // Require auth key
-    if let Some(auth_key) = with_context!(auth_key) {
-        // check bearer token exist
-        let bearer =
-            bearer.ok_or_else(|| ResponseError::Unauthorized(ProxyError::AuthKeyRequired))?;
-        if auth_key.ne(bearer.token()) {
-            return Err(ResponseError::Forbidden(ProxyError::AuthKeyError));
-        }
-    }
+    with_context!(auth_keys).verify(bearer.as_ref().map(|b| b.token()), Scope::Arkose, ip)?;
 
     // Require arkose token endpoint public key
     let typed = arkose::Type::from_pk(pk.as_str()).map_err(ResponseError::BadRequest)?;
 
+    let (client, health) = with_context!(checkout_arkose_client, None);
     ArkoseToken::new_from_context(
         ArkoseContext::builder()
-            .client(with_context!(arkose_client))
+            .client(client)
+            .health(health)
             .typed(typed)
             .identifier(blob.map(|v| v.0.blob).flatten())
             .build(),
@@ -437,7 +509,8 @@ async fn get_arkose_token(
 /// platform API match path /v1/{tail.*}
 /// reference: https://platform.openai.com/docs/api-reference
 async fn official_proxy(req: RequestExt) -> Result<impl IntoResponse, ResponseError> {
-    let resp = with_context!(api_client)
+    let account = puid::affinity(req.bearer_auth());
+    let resp = with_context!(checkout_api_client, account.as_deref())
         .send_request(URL_PLATFORM_API, req)
         .await?;
     response_convert(resp).await
@@ -445,7 +518,8 @@ async fn official_proxy(req: RequestExt) -> Result<impl IntoResponse, ResponseEr
 
 /// reference: doc/http.rest
 async fn unofficial_proxy(req: RequestExt) -> Result<impl IntoResponse, ResponseError> {
-    let resp = with_context!(api_client)
+    let account = puid::affinity(req.bearer_auth());
+    let resp = with_context!(checkout_api_client, account.as_deref())
         .send_request(URL_CHATGPT_API, req)
         .await?;
     response_convert(resp).await
@@ -488,6 +562,22 @@ impl TryInto<Response<Body>> for SessionAccessToken {
     }
 }
 
+/// Periodically probe the client pool members and log the unhealthy pools
+async fn periodic_health_check(interval_secs: u32) {
+    info!("Client pool health check periodic task is running");
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.into()));
+    loop {
+        interval.tick().await;
+        for (pool, balancer) in with_context!(client_pools) {
+            balancer.probe().await;
+            let (healthy, total) = balancer.healthy();
+            if healthy < total {
+                warn!("Client pool `{pool}`: {healthy}/{total} members healthy");
+            }
+        }
+    }
+}
+
 async fn check_wan_address() {
     match with_context!(api_client)
         .get("https://ifconfig.me")
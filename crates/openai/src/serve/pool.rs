@@ -0,0 +1,205 @@
+use native_db::*;
+use native_model::{native_model, Model};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use crate::context::authkey::constant_time_eq;
+use crate::context::WORKER_DIR;
+use crate::homedir::home_dir;
+use crate::token::model::Token;
+use crate::{info, now_duration, warn, with_context};
+
+static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
+static DATABASE: OnceLock<Database<'static>> = OnceLock::new();
+
+/// Round-robin cursor of the healthy accounts
+static CURSOR: AtomicUsize = AtomicUsize::new(0);
+
+#[native_db]
+#[native_model(id = 1, version = 1)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PoolAccount {
+    #[primary_key]
+    email: String,
+    token: Token,
+    /// Rate limited until (timestamp seconds)
+    limited_until: u64,
+    /// Last rate limited time (timestamp seconds)
+    limited_at: u64,
+}
+
+impl PoolAccount {
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+
+    pub fn access_token(&self) -> &str {
+        self.token.access_token()
+    }
+
+    /// Check if the account can serve the request now
+    pub fn is_healthy(&self, now: u64) -> bool {
+        self.limited_until <= now && !self.token.is_expired()
+    }
+}
+
+fn database() -> &'static Database<'static> {
+    DATABASE.get_or_init(|| {
+        let builder = DATABASE_BUILDER.get_or_init(|| {
+            let mut builder = DatabaseBuilder::new();
+            builder
+                .define::<PoolAccount>()
+                .expect("define table failed");
+            builder
+        });
+
+        let path = home_dir()
+            .expect("Failed to get home directory")
+            .join(WORKER_DIR)
+            .join("account_pool.db");
+
+        if let Some(p) = path.parent() {
+            // If parent directory does not exist, create it
+            if !p.exists() {
+                std::fs::create_dir_all(p)
+                    .expect(&format!("Failed to create directory: {}", p.display()));
+            }
+        }
+
+        builder
+            .create(path)
+            .expect("Failed to create account pool database")
+    })
+}
+
+fn now() -> u64 {
+    now_duration().map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Check if the bearer token is the API key of the account pool
+pub(crate) fn is_pool_key(token: &str) -> bool {
+    let token = token.trim_start_matches("Bearer ");
+    with_context!(account_pool_key)
+        .map(|key| constant_time_eq(key.as_bytes(), token.as_bytes()))
+        .unwrap_or_default()
+}
+
+/// Get all the accounts of the pool, ordered by email
+pub(crate) fn list() -> Vec<PoolAccount> {
+    let r = match database().r_transaction() {
+        Ok(r) => r,
+        Err(err) => {
+            warn!("Failed to start read transaction: {}", err);
+            return vec![];
+        }
+    };
+
+    let accounts = match r.scan().primary::<PoolAccount>() {
+        Ok(scan) => scan.all().collect::<Vec<_>>(),
+        Err(err) => {
+            warn!("Failed to scan pool accounts: {}", err);
+            vec![]
+        }
+    };
+    accounts
+}
+
+/// Get the account if it can serve the request now
+pub(crate) fn get(email: &str) -> Option<PoolAccount> {
+    database()
+        .r_transaction()
+        .ok()
+        .and_then(|r| r.get().primary::<PoolAccount>(email).ok())
+        .flatten()
+        .filter(|a| a.is_healthy(now()))
+}
+
+/// Store the account token, the rate limited state is kept if the account exists
+pub(crate) fn put(token: Token) {
+    let db = database();
+    let exists = db
+        .r_transaction()
+        .ok()
+        .and_then(|r| r.get().primary::<PoolAccount>(token.email()).ok())
+        .flatten();
+
+    let account = PoolAccount {
+        email: token.email().to_owned(),
+        token,
+        limited_until: exists.as_ref().map(|a| a.limited_until).unwrap_or_default(),
+        limited_at: exists.as_ref().map(|a| a.limited_at).unwrap_or_default(),
+    };
+
+    if let Ok(rw) = db.rw_transaction() {
+        if let Some(err) = rw.insert(account).err() {
+            warn!("Failed to insert pool account: {}", err)
+        }
+        if let Some(err) = rw.commit().err() {
+            warn!("Failed to commit transaction: {}", err)
+        }
+    }
+}
+
+/// Import the account tokens from a JSON array file
+pub(crate) fn import(path: &Path) -> anyhow::Result<()> {
+    let bytes = std::fs::read(path)?;
+    let tokens = serde_json::from_slice::<Vec<Token>>(&bytes)?;
+    let count = tokens.len();
+    tokens.into_iter().for_each(put);
+    info!(
+        "Account pool: imported {count} accounts from {}",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Pick a healthy account in round-robin order,
+/// if all accounts are rate limited, the least recently rate limited one is picked
+pub(crate) fn next() -> Option<PoolAccount> {
+    let now = now();
+    let mut accounts = list();
+    accounts.retain(|a| !a.token.is_expired());
+
+    let healthy = accounts
+        .iter()
+        .filter(|a| a.is_healthy(now))
+        .collect::<Vec<_>>();
+    if !healthy.is_empty() {
+        let index = CURSOR.fetch_add(1, Ordering::Relaxed) % healthy.len();
+        return Some(healthy[index].clone());
+    }
+
+    accounts.into_iter().min_by_key(|a| a.limited_at)
+}
+
+/// Mark the account as rate limited, it is skipped until the cooldown is over
+pub(crate) fn rate_limited(email: &str) {
+    let db = database();
+    let account = db
+        .r_transaction()
+        .ok()
+        .and_then(|r| r.get().primary::<PoolAccount>(email).ok())
+        .flatten();
+
+    if let Some(mut account) = account {
+        let now = now();
+        account.limited_at = now;
+        account.limited_until = now + u64::from(with_context!(account_pool_cooldown));
+        warn!("Account pool: {} is rate limited", account.email);
+
+        if let Ok(rw) = db.rw_transaction() {
+            if let Some(err) = rw.insert(account).err() {
+                warn!("Failed to update pool account: {}", err)
+            }
+            if let Some(err) = rw.commit().err() {
+                warn!("Failed to commit transaction: {}", err)
+            }
+        }
+    }
+}
@@ -14,13 +14,55 @@ use typed_builder::TypedBuilder;
 
 use crate::serve::error::ResponseError;
 
+/// OpenAI API endpoint translated to ChatGPT API.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    #[default]
+    ChatCompletions,
+    Completions,
+    Models,
+}
+
 /// Context extension.
 #[derive(TypedBuilder)]
 pub struct Context {
+    // Translated endpoint
+    #[builder(default)]
+    pub endpoint: Endpoint,
     // Enable stream
     pub stream: bool,
     // Mapper model
     pub model: String,
+    // Prompt tokens
+    #[builder(default)]
+    pub prompt_tokens: usize,
+    // Include usage in the stream
+    #[builder(default)]
+    pub include_usage: bool,
+    // Conversation continuity key
+    #[builder(default)]
+    pub conversation_key: Option<String>,
+    // Account of the continued conversation
+    #[builder(default)]
+    pub conversation_account: Option<String>,
+    // Emulated tool names
+    #[builder(default)]
+    pub tools: Vec<String>,
+    // Reply with legacy function call
+    #[builder(default)]
+    pub legacy_function_call: bool,
+    // Stop sequences
+    #[builder(default)]
+    pub stop: Vec<String>,
+    // Max completion tokens
+    #[builder(default)]
+    pub max_tokens: Option<usize>,
+    // Unsupported parameters
+    #[builder(default)]
+    pub unsupported: Vec<String>,
+    // Responses of the additional choices (n > 1)
+    #[builder(default)]
+    pub choices: Vec<reqwest::Response>,
 }
 
 /// Response extension.
@@ -1,5 +1,3 @@
-use std::str::FromStr;
-
 use axum::body::Bytes;
 use axum::{
     async_trait,
@@ -8,20 +6,24 @@ use axum::{
 use http::header;
 use http::{HeaderMap, Method};
 use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Instant;
 
 use crate::arkose::{ArkoseContext, ArkoseToken, Type};
+use crate::client::MemberHealth;
 use crate::constant::{ARKOSE_TOKEN, EMPTY, MODEL, NULL, PUID};
-use crate::gpt_model::GPTModel;
-use crate::{arkose, with_context};
+use crate::{arkose, with_context, URL_CHATGPT_API};
 
 use super::ext::{RequestExt, ResponseExt, SendRequestExt};
 use super::header_convert;
 use super::toapi;
 use crate::serve::error::{ProxyError, ResponseError};
-use crate::serve::puid::{get_or_init, reduce_key};
+use crate::serve::metric;
+use crate::serve::puid::{affinity, get_or_init, reduce_key};
 
+/// The client with the health of its pool member, the outcome of the request is reported
 #[async_trait]
-impl SendRequestExt for reqwest::Client {
+impl SendRequestExt for (reqwest::Client, Arc<MemberHealth>) {
     async fn send_request(
         &self,
         origin: &'static str,
@@ -29,7 +31,14 @@ impl SendRequestExt for reqwest::Client {
     ) -> Result<ResponseExt, ResponseError> {
         // If to_api is true, then send request to api
         if toapi::support(&req) {
-            return toapi::send_request(req).await;
+            let start = Instant::now();
+            let result = toapi::send_request(req).await;
+            let status = result.as_ref().map(|resp| resp.inner.status()).ok();
+            if let Some(status) = status {
+                metric::upstream_response(URL_CHATGPT_API, status);
+            }
+            metric::toapi_request(status, start.elapsed());
+            return result;
         }
 
         // Build rqeuest path and query
@@ -49,15 +58,21 @@ impl SendRequestExt for reqwest::Client {
         handle_dashboard_request(&mut req).await?;
 
         // Build request
-        let mut builder =
-            self.request(req.method, url)
-                .headers(header_convert(&req.headers, &req.jar, origin)?);
+        let mut builder = self.0.request(req.method, url).headers(header_convert(
+            &req.headers,
+            &req.jar,
+            origin,
+        )?);
         if let Some(body) = req.body {
             builder = builder.body(body);
         }
 
         // Send request
-        Ok(ResponseExt::builder().inner(builder.send().await?).build())
+        let result = builder.send().await;
+        self.1.observe(&result);
+        let resp = result?;
+        metric::upstream_response(origin, resp.status());
+        Ok(ResponseExt::builder().inner(resp).build())
     }
 }
 
@@ -96,6 +111,21 @@ async fn handle_conv_request(req: &mut RequestExt) -> Result<(), ResponseError>
         .and_then(|m| m.as_str())
         .ok_or(ResponseError::BadRequest(ProxyError::ModelRequired))?;
 
+    // Resolve model from the model table
+    let model = with_context!(gpt_models)
+        .resolve(model)
+        .ok_or_else(|| ResponseError::BadRequest(ProxyError::ModelNotFound(model.to_owned())))?;
+
+    // If the model is requested by alias, then replace it with the upstream slug
+    let mut modified = body
+        .get(MODEL)
+        .and_then(|m| m.as_str())
+        .map(|m| m.eq(&model.alias) && m.ne(&model.slug))
+        .unwrap_or_default();
+    if modified {
+        body.insert(MODEL.to_owned(), json!(model.slug));
+    }
+
     // extract token from Authorization header
     let token = req
         .bearer_auth()
@@ -108,7 +138,7 @@ async fn handle_conv_request(req: &mut RequestExt) -> Result<(), ResponseError>
         let cache_id = reduce_key(&token)?;
 
         // Get or init puid
-        let puid = get_or_init(&token, model, cache_id).await?;
+        let puid = get_or_init(&token, &model, cache_id).await?;
 
         if let Some(puid) = puid {
             req.headers.insert(
@@ -119,11 +149,8 @@ async fn handle_conv_request(req: &mut RequestExt) -> Result<(), ResponseError>
         }
     }
 
-    // Parse model
-    let model = GPTModel::from_str(model).map_err(ResponseError::BadRequest)?;
-
-    // If model is gpt3 or gpt4, then add arkose_token
-    if (with_context!(arkose_gpt3_experiment) && model.is_gpt3()) || model.is_gpt4() {
+    // If the model requires arkose token, then add arkose_token
+    if model.arkose_required(with_context!(arkose_gpt3_experiment)) {
         let condition = match body.get(ARKOSE_TOKEN) {
             Some(s) => {
                 let s = s.as_str().unwrap_or(EMPTY);
@@ -133,22 +160,29 @@ async fn handle_conv_request(req: &mut RequestExt) -> Result<(), ResponseError>
         };
 
         if condition {
-            let arkose_token = ArkoseToken::new_from_context(
+            let (client, health) =
+                with_context!(checkout_arkose_client, affinity(Some(&token)).as_deref());
+            let arkose_token = ArkoseToken::acquire(
                 ArkoseContext::builder()
-                    .client(with_context!(arkose_client))
-                    .typed(model.into())
+                    .client(client)
+                    .health(health)
+                    .typed(model.arkose_type)
                     .identifier(Some(token))
                     .build(),
             )
             .await?;
             body.insert(ARKOSE_TOKEN.to_owned(), json!(arkose_token.value()));
-            // Updaye Modify bytes
-            req.body = Some(Bytes::from(
-                serde_json::to_vec(&json).map_err(ResponseError::BadRequest)?,
-            ));
+            modified = true;
         }
     }
 
+    // Updaye Modify bytes
+    if modified {
+        req.body = Some(Bytes::from(
+            serde_json::to_vec(&json).map_err(ResponseError::BadRequest)?,
+        ));
+    }
+
     drop(json);
 
     Ok(())
@@ -175,9 +209,11 @@ async fn handle_dashboard_request(req: &mut RequestExt) -> Result<(), ResponseEr
 
     // If arkose_token is not exist, then add it
     if body.get(ARKOSE_TOKEN).is_none() {
-        let arkose_token = arkose::ArkoseToken::new_from_context(
+        let (client, health) = with_context!(checkout_arkose_client, None);
+        let arkose_token = arkose::ArkoseToken::acquire(
             arkose::ArkoseContext::builder()
-                .client(with_context!(arkose_client))
+                .client(client)
+                .health(health)
                 .typed(Type::Platform)
                 .identifier(None)
                 .build(),
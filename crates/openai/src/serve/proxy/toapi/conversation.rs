@@ -0,0 +1,157 @@
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use native_db::*;
+use native_model::{native_model, Model};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+use crate::context::WORKER_DIR;
+use crate::homedir::home_dir;
+use crate::{now_duration, warn, with_context};
+
+/// Client-supplied conversation key header
+pub(super) const CONVERSATION_KEY: &str = "X-Conversation-Key";
+
+static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
+static DATABASE: OnceLock<Database<'static>> = OnceLock::new();
+
+#[native_db]
+#[native_model(id = 1, version = 1)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub(super) struct ConversationState {
+    #[primary_key]
+    key: String,
+    /// Account of the conversation, a conversation can only be continued by its account
+    account: String,
+    conversation_id: String,
+    parent_message_id: String,
+    last_time: u64,
+}
+
+impl ConversationState {
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    pub fn parent_message_id(&self) -> &str {
+        &self.parent_message_id
+    }
+}
+
+#[derive(Deserialize)]
+struct User {
+    #[serde(default)]
+    user: Option<String>,
+}
+
+/// Get the `user` field of the request body
+pub(super) fn user(body: Option<&Bytes>) -> Option<String> {
+    body.and_then(|b| serde_json::from_slice::<User>(b).ok())
+        .and_then(|u| u.user)
+}
+
+/// Get the conversation continuity key of the client, the header is preferred over the `user` field.
+/// The key is scoped by the client API key rather than the serving account, so the account pool can rotate.
+pub(super) fn key(headers: &HeaderMap, user: Option<&str>, scope: &str) -> Option<String> {
+    if !with_context!(enable_conversation_continuity) {
+        return None;
+    }
+    headers
+        .get(CONVERSATION_KEY)
+        .and_then(|v| v.to_str().ok())
+        .or(user)
+        .filter(|k| !k.is_empty())
+        .map(|k| format!("{scope}:{k}"))
+}
+
+fn database() -> &'static Database<'static> {
+    DATABASE.get_or_init(|| {
+        let builder = DATABASE_BUILDER.get_or_init(|| {
+            let mut builder = DatabaseBuilder::new();
+            builder
+                .define::<ConversationState>()
+                .expect("define table failed");
+            builder
+        });
+
+        let path = home_dir()
+            .expect("Failed to get home directory")
+            .join(WORKER_DIR)
+            .join("conversation.db");
+
+        if let Some(p) = path.parent() {
+            // If parent directory does not exist, create it
+            if !p.exists() {
+                std::fs::create_dir_all(p)
+                    .expect(&format!("Failed to create directory: {}", p.display()));
+            }
+        }
+
+        builder
+            .create(path)
+            .expect("Failed to create conversation database")
+    })
+}
+
+/// Get the stored conversation state, expired state will be removed
+pub(super) fn get(key: &str) -> Option<ConversationState> {
+    let db = database();
+    let state = db
+        .r_transaction()
+        .ok()?
+        .get()
+        .primary::<ConversationState>(key)
+        .ok()??;
+
+    let now_timestamp = now_duration().ok()?.as_secs();
+    if now_timestamp.saturating_sub(state.last_time) < with_context!(conversation_expired).into() {
+        return Some(state);
+    }
+
+    // Remove expired conversation
+    if let Ok(rw) = db.rw_transaction() {
+        if let Some(err) = rw.remove(state).err() {
+            warn!("Failed to remove conversation: {}", err)
+        }
+        if let Some(err) = rw.commit().err() {
+            warn!("Failed to commit transaction: {}", err)
+        }
+    }
+
+    None
+}
+
+/// Store the latest conversation state
+pub(super) fn put(key: String, account: String, conversation_id: &str, parent_message_id: &str) {
+    if conversation_id.is_empty() || parent_message_id.is_empty() {
+        return;
+    }
+
+    let last_time = match now_duration() {
+        Ok(t) => t.as_secs(),
+        Err(err) => {
+            warn!("Failed to get now duration: {}", err);
+            return;
+        }
+    };
+
+    if let Ok(rw) = database().rw_transaction() {
+        let state = ConversationState {
+            key,
+            account,
+            conversation_id: conversation_id.to_owned(),
+            parent_message_id: parent_message_id.to_owned(),
+            last_time,
+        };
+        if let Some(err) = rw.insert(state).err() {
+            warn!("Failed to insert conversation: {}", err)
+        }
+        if let Some(err) = rw.commit().err() {
+            warn!("Failed to commit transaction: {}", err)
+        }
+    }
+}
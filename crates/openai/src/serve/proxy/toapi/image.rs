@@ -0,0 +1,303 @@
+use axum::http::{header, HeaderMap};
+use base64::engine::general_purpose;
+use base64::Engine;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Client;
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use url::{Host, Url};
+
+use crate::chatgpt::model::req::{Attachment, ImagePart};
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::uuid::uuid;
+use crate::URL_CHATGPT_API;
+
+/// Default image mime type
+const DEFAULT_MIME_TYPE: &str = "image/png";
+
+/// Max image size (bytes)
+const MAX_IMAGE_SIZE: usize = 20 * 1024 * 1024;
+
+/// Max redirects of the remote image url
+const MAX_REDIRECTS: usize = 5;
+
+/// Remote image fetch timeout (seconds)
+const FETCH_TIMEOUT: u64 = 30;
+
+static FETCH_CLIENT: OnceLock<Client> = OnceLock::new();
+
+#[derive(Deserialize)]
+struct FileUploadResponse {
+    file_id: String,
+    upload_url: String,
+}
+
+/// Uploaded image
+#[derive(Clone)]
+pub(super) struct Image {
+    pub part: ImagePart,
+    pub attachment: Attachment,
+}
+
+/// Resolver of the remote image hosts, the hosts resolving to a non-public address are rejected
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            if addrs.is_empty() || addrs.iter().any(|addr| !is_public(addr.ip())) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    ProxyError::ImageUrlNotAllowed.to_string(),
+                )
+                .into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Check if the address is publicly routable
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8
+        || a == 0
+        // Shared address space, 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // Benchmarking, 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // Link local, fe80::/10
+        || (first & 0xffc0) == 0xfe80)
+}
+
+/// Check if the remote image url is allowed, the domain hosts are checked by the resolver
+fn is_allowed(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(_)) => matches!(url.scheme(), "http" | "https"),
+        Some(Host::Ipv4(ip)) => matches!(url.scheme(), "http" | "https") && is_public_v4(ip),
+        Some(Host::Ipv6(ip)) => {
+            matches!(url.scheme(), "http" | "https") && is_public(IpAddr::V6(ip))
+        }
+        None => false,
+    }
+}
+
+/// Remote image client, it connects directly and only to the public addresses
+fn fetch_client() -> &'static Client {
+    FETCH_CLIENT.get_or_init(|| {
+        Client::builder()
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(Policy::custom(|attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if !is_allowed(attempt.url()) {
+                    attempt.error(ProxyError::ImageUrlNotAllowed.to_string())
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .timeout(Duration::from_secs(FETCH_TIMEOUT))
+            .build()
+            .expect("Failed to build image client")
+    })
+}
+
+/// Load the image from a data url or a remote url
+async fn load(url: &str) -> Result<(Vec<u8>, String), ResponseError> {
+    // data:image/png;base64,xxx
+    if let Some(data) = url.strip_prefix("data:") {
+        let (meta, payload) = data
+            .split_once(',')
+            .ok_or(ResponseError::BadRequest(ProxyError::InvalidImageUrl))?;
+        let mime_type = meta
+            .strip_suffix(";base64")
+            .ok_or(ResponseError::BadRequest(ProxyError::InvalidImageUrl))?;
+        // The decoded size is 3/4 of the base64 payload
+        if payload.len() / 4 * 3 > MAX_IMAGE_SIZE {
+            return Err(ResponseError::BadRequest(ProxyError::ImageTooLarge(
+                MAX_IMAGE_SIZE,
+            )));
+        }
+        let bytes = general_purpose::STANDARD
+            .decode(payload)
+            .map_err(ResponseError::BadRequest)?;
+        return Ok((bytes, mime_type.to_owned()));
+    }
+
+    let url =
+        Url::parse(url).map_err(|_| ResponseError::BadRequest(ProxyError::InvalidImageUrl))?;
+    if !is_allowed(&url) {
+        return Err(ResponseError::BadRequest(ProxyError::ImageUrlNotAllowed));
+    }
+
+    let mut resp = fetch_client()
+        .get(url)
+        .send()
+        .await
+        .map_err(ResponseError::BadRequest)?
+        .error_for_status()
+        .map_err(ResponseError::BadRequest)?;
+
+    if resp
+        .content_length()
+        .is_some_and(|len| len > MAX_IMAGE_SIZE as u64)
+    {
+        return Err(ResponseError::BadRequest(ProxyError::ImageTooLarge(
+            MAX_IMAGE_SIZE,
+        )));
+    }
+
+    let mime_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with("image/"))
+        .unwrap_or(DEFAULT_MIME_TYPE)
+        .to_owned();
+
+    // The content length may be absent or wrong, the body is read up to the max size
+    let mut bytes = Vec::new();
+    while let Some(chunk) = resp.chunk().await.map_err(ResponseError::BadRequest)? {
+        if bytes.len() + chunk.len() > MAX_IMAGE_SIZE {
+            return Err(ResponseError::BadRequest(ProxyError::ImageTooLarge(
+                MAX_IMAGE_SIZE,
+            )));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok((bytes, mime_type))
+}
+
+/// Upload the image through the ChatGPT files flow
+pub(super) async fn upload(
+    client: &Client,
+    headers: &HeaderMap,
+    url: &str,
+) -> Result<Image, ResponseError> {
+    let (bytes, mime_type) = load(url).await?;
+
+    let size = imagesize::blob_size(&bytes)
+        .map_err(|_| ResponseError::BadRequest(ProxyError::InvalidImageUrl))?;
+    let size_bytes = bytes.len();
+    let name = format!("{}.{}", uuid(), mime_type.trim_start_matches("image/"));
+
+    // Create the file
+    let file = client
+        .post(format!("{URL_CHATGPT_API}/backend-api/files"))
+        .headers(headers.clone())
+        .json(&serde_json::json!({
+            "file_name": name,
+            "file_size": size_bytes,
+            "use_case": "multimodal",
+        }))
+        .send()
+        .await
+        .map_err(ResponseError::InternalServerError)?
+        .error_for_status()
+        .map_err(ResponseError::BadGateway)?
+        .json::<FileUploadResponse>()
+        .await
+        .map_err(ResponseError::BadGateway)?;
+
+    // Upload the file content to the blob storage
+    client
+        .put(&file.upload_url)
+        .header("X-Ms-Blob-Type", "BlockBlob")
+        .header("X-Ms-Version", "2020-04-08")
+        .header(header::CONTENT_TYPE, &mime_type)
+        .body(bytes)
+        .send()
+        .await
+        .map_err(ResponseError::InternalServerError)?
+        .error_for_status()
+        .map_err(ResponseError::BadGateway)?;
+
+    // Mark the file as uploaded
+    client
+        .post(format!(
+            "{URL_CHATGPT_API}/backend-api/files/{}/uploaded",
+            file.file_id
+        ))
+        .headers(headers.clone())
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .map_err(ResponseError::InternalServerError)?
+        .error_for_status()
+        .map_err(ResponseError::BadGateway)?;
+
+    Ok(Image {
+        part: ImagePart::builder()
+            .asset_pointer(format!("file-service://{}", file.file_id))
+            .size_bytes(size_bytes)
+            .width(size.width)
+            .height(size.height)
+            .build(),
+        attachment: Attachment::builder()
+            .id(file.file_id)
+            .name(name)
+            .size(size_bytes)
+            .mime_type(mime_type)
+            .width(size.width)
+            .height(size.height)
+            .build(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let allowed = |url: &str| is_allowed(&Url::parse(url).unwrap());
+        assert!(allowed("https://example.com/a.png"));
+        assert!(allowed("http://8.8.8.8/a.png"));
+        assert!(!allowed("ftp://example.com/a.png"));
+        assert!(!allowed("http://127.0.0.1/a.png"));
+        assert!(!allowed("http://169.254.169.254/latest/meta-data"));
+        assert!(!allowed("http://10.0.0.1/a.png"));
+        assert!(!allowed("http://100.64.0.1/a.png"));
+        assert!(!allowed("http://[::1]/a.png"));
+        assert!(!allowed("http://[fd00::1]/a.png"));
+        assert!(!allowed("http://[::ffff:192.168.1.1]/a.png"));
+    }
+}
@@ -1,27 +1,34 @@
+mod conversation;
+mod image;
 mod model;
 mod stream;
+mod tokenizer;
+mod tools;
+mod truncate;
 
 use axum::http::header;
-use axum::http::Method;
+use axum::http::{HeaderMap, HeaderValue, Method};
 use axum::{
-    response::{IntoResponse, Sse},
+    response::{IntoResponse, Response, Sse},
     Json,
 };
 use eventsource_stream::Eventsource;
-use reqwest::StatusCode;
-use std::str::FromStr;
+use reqwest::{Client, StatusCode};
 
 use crate::arkose::ArkoseContext;
 use crate::chatgpt::model::req::Metadata;
+use crate::chatgpt::model::resp::GetModelsResponse;
 use crate::chatgpt::model::Role;
-use crate::gpt_model::GPTModel;
+use crate::client::MemberHealth;
+use crate::gpt_model::ModelEntry;
 use crate::now_duration;
 use crate::serve::error::ProxyError;
+use crate::serve::pool;
 use crate::serve::ProxyResult;
 use crate::token;
 use crate::{
     arkose::ArkoseToken,
-    chatgpt::model::req::{Content, ConversationMode, Messages, PostConvoRequest},
+    chatgpt::model::req::{Content, ConversationMode, Messages, Part, PostConvoRequest},
     serve::{
         error::ResponseError,
         puid::{get_or_init, reduce_key},
@@ -36,10 +43,20 @@ use crate::{
     uuid::uuid,
 };
 
-use super::ext::{Context, RequestExt, ResponseExt};
+use self::conversation::ConversationState;
+use super::ext::{Context, Endpoint, RequestExt, ResponseExt};
 use super::header_convert;
 use crate::URL_CHATGPT_API;
 
+/// Max number of choices
+const MAX_CHOICES: usize = 8;
+
+/// Usage cap errors of the upstream, matched in the lowercase error body
+const USAGE_CAP_ERRORS: [&str; 3] = ["model_cap_exceeded", "rate_limit_exceeded", "usage cap"];
+
+/// Conversation continuity scope of the account pool API key
+const POOL_SCOPE: &str = "pool";
+
 const SUGGESTIONS: [&'static str; 4] = [
   "Write a script to automate sending daily email reports in Python, and walk me through how I would set it up.",
   "Design a database schema for an online merch store.",
@@ -47,18 +64,133 @@ const SUGGESTIONS: [&'static str; 4] = [
   "I'm going to cook for my date who claims to be a picky eater. Can you recommend me a dish that's easy to cook?"
 ];
 
+/// Get the translated endpoint of the request
+fn endpoint(req: &RequestExt) -> Option<Endpoint> {
+    let path = req.uri.path();
+    match req.method {
+        Method::POST if path.eq("/v1/chat/completions") => Some(Endpoint::ChatCompletions),
+        Method::POST if path.eq("/v1/completions") => Some(Endpoint::Completions),
+        Method::GET if path.eq("/v1/models") || path.starts_with("/v1/models/") => {
+            Some(Endpoint::Models)
+        }
+        _ => None,
+    }
+}
+
 /// Check if the request is supported
 pub(super) fn support(req: &RequestExt) -> bool {
-    if req.uri.path().eq("/v1/chat/completions") && req.method.eq(&Method::POST) {
+    if endpoint(req).is_some() {
         if let Some(ref token) = req.bearer_auth() {
-            return !token::check_sk_or_sess(token);
+            return pool::is_pool_key(token) || !token::check_sk_or_sess(token);
         }
     }
     false
 }
 
-/// Send request to ChatGPT API
+/// Send request to ChatGPT API, the account pool API key is served by the pool accounts
 pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, ResponseError> {
+    let pool_key = req.bearer_auth().map(pool::is_pool_key).unwrap_or_default();
+    if !pool_key {
+        return send(req, None).await;
+    }
+
+    // The account of the continued conversation is tried first
+    let owner = conversation::key(
+        &req.headers,
+        conversation::user(req.body.as_ref()).as_deref(),
+        POOL_SCOPE,
+    )
+    .as_deref()
+    .and_then(conversation::get)
+    .and_then(|state| pool::get(state.account()));
+
+    // Try the accounts in turn, the rate limited account is skipped
+    let attempts = pool::list().len().max(1);
+    let mut last = None;
+    for attempt in 0..attempts {
+        let account = match owner.clone().filter(|_| attempt == 0) {
+            Some(account) => account,
+            None => pool::next().ok_or(ResponseError::ServiceUnavailable(
+                ProxyError::NoAvailableAccount,
+            ))?,
+        };
+
+        let mut headers = req.headers.clone();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", account.access_token()))
+                .map_err(ResponseError::InternalServerError)?,
+        );
+
+        let mut resp = send(
+            RequestExt {
+                uri: req.uri.clone(),
+                method: req.method.clone(),
+                headers,
+                jar: req.jar.clone(),
+                body: req.body.clone(),
+            },
+            Some(POOL_SCOPE),
+        )
+        .await?;
+
+        if !is_rate_limited(&mut resp).await {
+            return Ok(resp);
+        }
+
+        pool::rate_limited(account.email());
+        last = Some(resp);
+    }
+
+    last.ok_or(ResponseError::ServiceUnavailable(
+        ProxyError::NoAvailableAccount,
+    ))
+}
+
+/// Check if the account is rate limited, including the usage cap errors that are not reported with 429
+async fn is_rate_limited(resp: &mut ResponseExt) -> bool {
+    let mut limited = is_usage_cap(&mut resp.inner).await;
+    if let Some(context) = resp.context.as_mut() {
+        for choice in context.choices.iter_mut() {
+            limited |= is_usage_cap(choice).await;
+        }
+    }
+    limited
+}
+
+/// Check if the response is a usage cap error, the error body is read and restored
+async fn is_usage_cap(resp: &mut reqwest::Response) -> bool {
+    let status = resp.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return true;
+    }
+    if !status.is_client_error() {
+        return false;
+    }
+
+    let mut builder = axum::http::Response::builder()
+        .status(status)
+        .version(resp.version());
+    if let Some(headers) = builder.headers_mut() {
+        headers.extend(resp.headers().clone());
+    }
+    let placeholder = axum::http::Response::new(Vec::new());
+    let bytes = std::mem::replace(resp, placeholder.into())
+        .bytes()
+        .await
+        .unwrap_or_default();
+
+    let body = String::from_utf8_lossy(&bytes).to_lowercase();
+    let limited = USAGE_CAP_ERRORS.iter().any(|e| body.contains(e));
+    if let Ok(restored) = builder.body(bytes) {
+        *resp = restored.into();
+    }
+    limited
+}
+
+/// Send request to ChatGPT API with the access token,
+/// the conversation continuity is scoped by the access token account unless the scope is given
+async fn send(req: RequestExt, scope: Option<&str>) -> Result<ResponseExt, ResponseError> {
     // Exstract the token from the Authorization header
     let baerer = req
         .bearer_auth()
@@ -67,49 +199,230 @@ pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, Respons
     // Exstract the token from the Authorization header
     let cache_id = reduce_key(baerer)?;
 
+    // The models are listed from the ChatGPT API
+    let endpoint = endpoint(&req).unwrap_or_default();
+    if endpoint == Endpoint::Models {
+        return send_models_request(&req).await;
+    }
+
     // Exstract the body
     let bytes = req
         .body
         .as_ref()
         .ok_or_else(|| ResponseError::BadRequest(ProxyError::BodyRequired))?;
-    let body = serde_json::from_slice::<model::Req>(bytes)?;
+    let body = match endpoint {
+        Endpoint::Completions => {
+            model::Req::try_from(serde_json::from_slice::<model::CompletionReq>(bytes)?)
+                .map_err(ResponseError::BadRequest)?
+        }
+        _ => serde_json::from_slice::<model::Req>(bytes)?,
+    };
 
-    // Convert to ChatGPT API Message
-    let mut messages = Vec::with_capacity(body.messages.len());
-    for body_msg in body.messages.iter() {
-        let role = if body_msg.role.eq(&Role::System) {
-            Role::Critic
-        } else {
-            body_msg.role
-        };
-        let message = Messages::builder()
-            .id(uuid())
-            .author(Author { role })
-            .content(
-                Content::builder()
-                    .content_type(ContentText::Text)
-                    .parts(vec![&body_msg.content])
-                    .build(),
-            )
-            .metadata(Metadata {})
-            .build();
-        messages.push(message)
+    // Tool calling emulation prompt
+    let tool_names = tools::names(&body);
+    let legacy_function_call = tools::is_legacy(&body);
+    let tool_prompt = tools::prompt(&body);
+
+    // Count the prompt tokens
+    let prompt_tokens = tokenizer::num_tokens_from_messages(&body.messages)
+        + tool_prompt
+            .as_deref()
+            .map(tokenizer::num_tokens)
+            .unwrap_or_default();
+
+    // Number of choices, each choice is a separate conversation
+    let n = body.n.unwrap_or(1).max(1);
+    if n > MAX_CHOICES {
+        return Err(ResponseError::BadRequest(ProxyError::TooManyChoices(
+            MAX_CHOICES,
+        )));
     }
 
+    // Parameters that can not be honored
+    let unsupported = body
+        .unsupported()
+        .into_iter()
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+
+    // Conversation continuity key, scoped by the client, only a single choice can be continued
+    let conversation_key = (n == 1)
+        .then(|| {
+            conversation::key(
+                &req.headers,
+                body.user.as_deref(),
+                scope.unwrap_or(&cache_id),
+            )
+        })
+        .flatten();
+
+    // Try to get the stored conversation, the conversation of another account can not be continued
+    let conversation = conversation_key
+        .as_deref()
+        .and_then(conversation::get)
+        .filter(|state| state.account().eq(&cache_id));
+
+    // If the conversation is continued, only send the messages after the last assistant reply
+    let skip = conversation
+        .as_ref()
+        .and_then(|_| {
+            body.messages
+                .iter()
+                .rposition(|m| m.role.eq(&Role::Assistant))
+        })
+        .map(|i| (i + 1).min(body.messages.len() - 1))
+        .unwrap_or(0);
+
+    // Render the message contents, tool calls and tool results are rendered as text
+    let contents = body
+        .messages
+        .iter()
+        .skip(skip)
+        .map(|m| (m.role, tools::render(m)))
+        .collect::<Vec<_>>();
+
     // Request client
-    let client = with_context!(api_client);
+    let (client, health) = with_context!(checkout_api_client, Some(cache_id.as_str()));
+
+    // Request headers
+    let headers = header_convert(&req.headers, &req.jar, URL_CHATGPT_API)?;
+
+    // Upload the images of the messages
+    let mut images = Vec::with_capacity(contents.len());
+    for message in body.messages.iter().skip(skip) {
+        let urls = message
+            .content
+            .as_ref()
+            .map(model::MessageContent::images)
+            .unwrap_or_default();
+        let uploads = urls
+            .into_iter()
+            .map(|url| image::upload(&client, &headers, url));
+        images.push(futures::future::try_join_all(uploads).await?);
+    }
+
+    // Convert to ChatGPT API Message, each choice needs its own messages
+    let new_messages = || {
+        let mut messages = Vec::with_capacity(contents.len() + 1);
+        if let Some(ref prompt) = tool_prompt {
+            messages.push(new_message(Role::Critic, prompt, vec![]));
+        }
+        for ((role, content), images) in contents.iter().zip(images.iter()) {
+            let role = match role {
+                Role::System => Role::Critic,
+                Role::Tool => Role::User,
+                role => *role,
+            };
+            messages.push(new_message(role, content, images.clone()))
+        }
+        messages
+    };
 
     // OpenAI API to ChatGPT API model mapper
-    let gpt_model = GPTModel::from_str(&body.model)?;
+    let gpt_model = with_context!(gpt_models)
+        .resolve(&body.model)
+        .ok_or_else(|| ResponseError::BadRequest(ProxyError::ModelNotFound(body.model.clone())))?;
+
+    // Try to get puid from cache
+    let puid = get_or_init(baerer, &gpt_model, cache_id.clone()).await?;
+
+    // Send the conversations of all choices
+    let convo = ConvoContext {
+        client: &client,
+        health: &health,
+        headers: &headers,
+        baerer,
+        puid: puid.as_deref(),
+        gpt_model: &gpt_model,
+        conversation: conversation.as_ref(),
+        history_and_training_disabled: conversation_key.is_none(),
+    };
+    let mut responses =
+        futures::future::try_join_all((0..n).map(|_| send_conversation(&convo, new_messages())))
+            .await?;
+    let resp = responses.remove(0);
+
+    Ok(ResponseExt::builder()
+        .inner(resp)
+        .context(
+            Context::builder()
+                .endpoint(endpoint)
+                .model(body.model)
+                .stream(body.stream)
+                .prompt_tokens(prompt_tokens)
+                .include_usage(
+                    body.stream_options
+                        .map(|o| o.include_usage)
+                        .unwrap_or_default(),
+                )
+                .conversation_account(conversation_key.as_ref().map(|_| cache_id))
+                .conversation_key(conversation_key)
+                .tools(tool_names)
+                .legacy_function_call(legacy_function_call)
+                .stop(body.stop.map(Vec::from).unwrap_or_default())
+                .max_tokens(body.max_tokens)
+                .unsupported(unsupported)
+                .choices(responses)
+                .build(),
+        )
+        .build())
+}
+
+/// Send the ChatGPT models request
+async fn send_models_request(req: &RequestExt) -> Result<ResponseExt, ResponseError> {
+    let resp = with_context!(api_client)
+        .get(format!("{URL_CHATGPT_API}/backend-api/models"))
+        .headers(header_convert(&req.headers, &req.jar, URL_CHATGPT_API)?)
+        .send()
+        .await
+        .map_err(ResponseError::InternalServerError)?;
+
+    // Retrieve a model if the model id is present
+    let model = req
+        .uri
+        .path()
+        .trim_start_matches("/v1/models")
+        .trim_start_matches('/');
+
+    Ok(ResponseExt::builder()
+        .inner(resp)
+        .context(
+            Context::builder()
+                .endpoint(Endpoint::Models)
+                .model(model.to_owned())
+                .stream(false)
+                .build(),
+        )
+        .build())
+}
+
+/// Conversation request context
+struct ConvoContext<'a> {
+    client: &'a Client,
+    health: &'a MemberHealth,
+    headers: &'a HeaderMap,
+    baerer: &'a str,
+    puid: Option<&'a str>,
+    gpt_model: &'a ModelEntry,
+    conversation: Option<&'a ConversationState>,
+    history_and_training_disabled: bool,
+}
+
+/// Send a conversation request, each request needs its own arkose token
+async fn send_conversation(
+    convo: &ConvoContext<'_>,
+    messages: Vec<Messages<'_>>,
+) -> Result<reqwest::Response, ResponseError> {
+    let gpt_model = convo.gpt_model;
 
     // check if arkose token is required
     let arkose_token: Option<String> =
-        if (with_context!(arkose_gpt3_experiment) && gpt_model.is_gpt3()) || gpt_model.is_gpt4() {
-            let arkose_token = ArkoseToken::new_from_context(
+        if gpt_model.arkose_required(with_context!(arkose_gpt3_experiment)) {
+            let arkose_token = ArkoseToken::acquire(
                 ArkoseContext::builder()
-                    .client(client.clone())
-                    .typed(gpt_model.clone().into())
-                    .identifier(Some(baerer.to_owned()))
+                    .client(convo.client.clone())
+                    .typed(gpt_model.arkose_type)
+                    .identifier(Some(convo.baerer.to_owned()))
                     .build(),
             )
             .await?;
@@ -119,7 +432,10 @@ pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, Respons
         };
 
     // Create request
-    let parent_message_id = uuid();
+    let parent_message_id = convo
+        .conversation
+        .map(|c| c.parent_message_id().to_owned())
+        .unwrap_or_else(uuid);
     let req_body = PostConvoRequest::builder()
         .action(Action::Next)
         .arkose_token(arkose_token.as_deref())
@@ -128,40 +444,28 @@ pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, Respons
         })
         .force_paragen(false)
         .force_rate_limit(false)
-        .history_and_training_disabled(true)
+        .history_and_training_disabled(convo.history_and_training_disabled)
+        .conversation_id(convo.conversation.map(|c| c.conversation_id()))
         .messages(messages)
-        .model(gpt_model)
+        .model(gpt_model.into())
         .parent_message_id(&parent_message_id)
         .suggestions(SUGGESTIONS.to_vec())
         .timezone_offset_min(-480)
         .build();
 
-    let mut builder = client
+    let mut builder = convo
+        .client
         .post(format!("{URL_CHATGPT_API}/backend-api/conversation"))
-        .headers(header_convert(&req.headers, &req.jar, URL_CHATGPT_API)?);
+        .headers(convo.headers.clone());
 
-    // Try to get puid from cache
-    let puid = get_or_init(baerer, &body.model, cache_id).await?;
-    if let Some(puid) = puid {
+    if let Some(puid) = convo.puid {
         builder = builder.header(header::COOKIE, format!("_puid={puid};"))
     }
 
     // Send request
-    let resp = builder
-        .json(&req_body)
-        .send()
-        .await
-        .map_err(ResponseError::InternalServerError)?;
-
-    Ok(ResponseExt::builder()
-        .inner(resp)
-        .context(
-            Context::builder()
-                .model(body.model)
-                .stream(body.stream)
-                .build(),
-        )
-        .build())
+    let result = builder.json(&req_body).send().await;
+    convo.health.observe(&result);
+    result.map_err(ResponseError::InternalServerError)
 }
 
 /// Convert response to ChatGPT API
@@ -171,27 +475,82 @@ pub(super) async fn response_convert(
     match resp_ext.inner.error_for_status() {
         Ok(resp) => {
             // Get config from request context
-            let config = resp_ext.context.ok_or(ResponseError::InternalServerError(
+            let mut config = resp_ext.context.ok_or(ResponseError::InternalServerError(
                 ProxyError::RequestContentIsEmpty,
             ))?;
 
-            // Get response body event source
-            let event_source = resp.bytes_stream().eventsource();
+            // Convert the ChatGPT models to OpenAI models
+            if config.endpoint == Endpoint::Models {
+                return models_convert(resp, &config.model).await;
+            }
+
+            // Get response body event sources of all choices
+            let mut event_sources = vec![resp.bytes_stream().eventsource()];
+            for resp in std::mem::take(&mut config.choices) {
+                match resp.error_for_status() {
+                    Ok(resp) => event_sources.push(resp.bytes_stream().eventsource()),
+                    Err(err) => return Ok(handle_error_response(err)?.into_response()),
+                }
+            }
+
+            // Warn about the parameters that can not be honored
+            let warning = (!config.unsupported.is_empty())
+                .then(|| {
+                    HeaderValue::from_str(&format!(
+                        "299 - \"Unsupported parameters: {}\"",
+                        config.unsupported.join(", ")
+                    ))
+                    .ok()
+                })
+                .flatten();
 
-            if config.stream {
+            let mut resp = if config.stream {
                 // Create a  stream response
-                let stream = stream::stream_handler(event_source, config.model)?;
-                Ok(Sse::new(stream).into_response())
+                let stream = stream::stream_handler(event_sources, config)?;
+                Sse::new(stream).into_response()
             } else {
                 // Create a not stream response
-                let no_stream = stream::not_stream_handler(event_source, config.model).await?;
-                Ok(no_stream.into_response())
+                let no_stream = stream::not_stream_handler(event_sources, config).await?;
+                no_stream.into_response()
+            };
+
+            if let Some(warning) = warning {
+                resp.headers_mut().insert(header::WARNING, warning);
             }
+
+            Ok(resp)
         }
         Err(err) => Ok(handle_error_response(err)?.into_response()),
     }
 }
 
+/// Convert ChatGPT models response to OpenAI models response
+async fn models_convert(resp: reqwest::Response, model: &str) -> Result<Response, ResponseError> {
+    let models = resp
+        .json::<GetModelsResponse>()
+        .await
+        .map_err(ResponseError::BadGateway)?;
+
+    // Refresh the model table, only the models that can be mapped are listed
+    let registry = with_context!(gpt_models);
+    registry.refresh(models.real_models());
+    let data = models
+        .real_models()
+        .into_iter()
+        .filter(|id| registry.resolve(id).is_some())
+        .map(|id| model::Model::builder().id(id).build())
+        .collect::<Vec<_>>();
+
+    if model.is_empty() {
+        return Ok(Json(model::ModelList::builder().data(data).build()).into_response());
+    }
+
+    data.into_iter()
+        .find(|m| m.id.eq(model))
+        .map(|m| Json(m).into_response())
+        .ok_or_else(|| ResponseError::NotFound(ProxyError::ModelNotFound(model.to_owned())))
+}
+
 /// Handle error response
 fn handle_error_response(err: reqwest::Error) -> Result<impl IntoResponse, ResponseError> {
     if let Some(status_code) = err.status() {
@@ -214,6 +573,43 @@ fn handle_error_response(err: reqwest::Error) -> Result<impl IntoResponse, Respo
     }
 }
 
+/// Create a ChatGPT API message, the message with images is sent as multimodal text
+fn new_message(role: Role, content: &str, images: Vec<image::Image>) -> Messages<'_> {
+    if images.is_empty() {
+        return Messages::builder()
+            .id(uuid())
+            .author(Author { role })
+            .content(
+                Content::builder()
+                    .content_type(ContentText::Text)
+                    .parts(vec![content.into()])
+                    .build(),
+            )
+            .metadata(Metadata::default())
+            .build();
+    }
+
+    let mut parts = Vec::with_capacity(images.len() + 1);
+    let mut attachments = Vec::with_capacity(images.len());
+    for image in images {
+        parts.push(Part::Image(image.part));
+        attachments.push(image.attachment);
+    }
+    parts.push(content.into());
+
+    Messages::builder()
+        .id(uuid())
+        .author(Author { role })
+        .content(
+            Content::builder()
+                .content_type(ContentText::MultimodalText)
+                .parts(parts)
+                .build(),
+        )
+        .metadata(Metadata { attachments })
+        .build()
+}
+
 fn generate_id(length: usize) -> String {
     let rand_str = crate::generate_random_string(length);
     format!("chatcmpl-{rand_str}")
@@ -1,7 +1,11 @@
 use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
 
 use crate::chatgpt::model::Role;
+use crate::serve::error::ProxyError;
 use serde::Serialize;
+use serde_json::Value;
 use typed_builder::TypedBuilder;
 
 #[derive(Deserialize)]
@@ -10,6 +14,170 @@ pub struct Req {
     pub messages: Vec<Message>,
     #[serde(default)]
     pub stream: bool,
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    #[serde(default)]
+    pub functions: Vec<Function>,
+    #[serde(default)]
+    pub function_call: Option<ToolChoice>,
+    #[serde(default)]
+    pub n: Option<usize>,
+    #[serde(default)]
+    pub stop: Option<Stop>,
+    #[serde(default, alias = "max_completion_tokens")]
+    pub max_tokens: Option<usize>,
+    /// Parameters that can not be honored, e.g. `temperature`
+    #[serde(flatten)]
+    pub unsupported: HashMap<String, Value>,
+}
+
+impl Req {
+    /// Get the names of the unsupported parameters
+    pub fn unsupported(&self) -> Vec<&str> {
+        let mut names = self
+            .unsupported
+            .iter()
+            .filter(|(_, v)| !v.is_null() && v.as_bool() != Some(false))
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+}
+
+/// Legacy completions request
+#[derive(Deserialize)]
+pub struct CompletionReq {
+    pub model: String,
+    pub prompt: Prompt,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub n: Option<usize>,
+    #[serde(default)]
+    pub stop: Option<Stop>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(flatten)]
+    pub unsupported: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TryFrom<CompletionReq> for Req {
+    type Error = ProxyError;
+
+    /// The prompt is sent as a single user message
+    fn try_from(value: CompletionReq) -> Result<Self, Self::Error> {
+        let prompt = match value.prompt {
+            Prompt::One(prompt) => prompt,
+            Prompt::Many(mut prompts) if prompts.len() == 1 => prompts.remove(0),
+            Prompt::Many(_) => return Err(ProxyError::MultiplePromptsNotSupported),
+        };
+
+        Ok(Req {
+            model: value.model,
+            messages: vec![Message::builder().role(Role::User).content(prompt).build()],
+            stream: value.stream,
+            stream_options: value.stream_options,
+            user: value.user,
+            tools: vec![],
+            tool_choice: None,
+            functions: vec![],
+            function_call: None,
+            n: value.n,
+            stop: value.stop,
+            max_tokens: value.max_tokens,
+            unsupported: value.unsupported,
+        })
+    }
+}
+
+/// Up to 4 sequences where the reply will stop
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Stop> for Vec<String> {
+    fn from(value: Stop) -> Self {
+        match value {
+            Stop::One(s) => vec![s],
+            Stop::Many(v) => v,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct StreamOptions {
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+#[derive(Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: Function,
+}
+
+#[derive(Deserialize)]
+pub struct Function {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub parameters: Option<Value>,
+}
+
+/// `tool_choice` / legacy `function_call`, can be:
+/// - `"none"`, `"auto"` or `"required"`
+/// - `{"type": "function", "function": {"name": "..."}}`
+/// - `{"name": "..."}` (legacy)
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function { function: FunctionName },
+    Name(FunctionName),
+}
+
+#[derive(Deserialize)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+impl ToolChoice {
+    /// The function name that must be called
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            ToolChoice::Mode(_) => None,
+            ToolChoice::Function { function } => Some(&function.name),
+            ToolChoice::Name(function) => Some(&function.name),
+        }
+    }
+
+    /// Check if the tool choice is the mode
+    pub fn is(&self, mode: &str) -> bool {
+        matches!(self, ToolChoice::Mode(m) if m.eq(mode))
+    }
 }
 
 #[derive(Serialize, TypedBuilder, Clone)]
@@ -27,7 +195,108 @@ pub struct Resp<'a> {
 #[derive(Serialize, Deserialize, TypedBuilder, Clone)]
 pub struct Message {
     pub role: Role,
-    pub content: String,
+    #[builder(default, setter(into, strip_option))]
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+}
+
+/// Message content, a plain text or an array of content parts
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl From<String> for MessageContent {
+    fn from(value: String) -> Self {
+        MessageContent::Text(value)
+    }
+}
+
+impl MessageContent {
+    /// Get the plain text content
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MessageContent::Text(text) => Some(text),
+            MessageContent::Parts(_) => None,
+        }
+    }
+
+    /// Get the text of the content, text parts are joined by newline
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            MessageContent::Text(text) => Cow::Borrowed(text),
+            MessageContent::Parts(parts) => Cow::Owned(
+                parts
+                    .iter()
+                    .filter_map(|p| match p {
+                        ContentPart::Text { text } => Some(text.as_str()),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+
+    /// Get the image urls of the content
+    pub fn images(&self) -> Vec<&str> {
+        match self {
+            MessageContent::Text(_) => vec![],
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|p| match p {
+                    ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+                    ContentPart::Text { .. } => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, TypedBuilder, Clone)]
+pub struct ToolCall {
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub id: String,
+    #[builder(default = String::from("function"))]
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, TypedBuilder, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
 }
 
 #[derive(Serialize, TypedBuilder, Clone)]
@@ -47,9 +316,30 @@ pub struct Choice<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub delta: Option<Delta<'a>>,
     #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<&'a str>,
+    #[builder(default)]
     pub finish_reason: Option<&'a str>,
 }
 
+#[derive(Serialize, TypedBuilder)]
+pub struct ModelList<'a> {
+    #[builder(default = "list")]
+    object: &'a str,
+    data: Vec<Model<'a>>,
+}
+
+#[derive(Serialize, TypedBuilder, Clone)]
+pub struct Model<'a> {
+    pub id: &'a str,
+    #[builder(default = "model")]
+    object: &'a str,
+    #[builder(default)]
+    created: i64,
+    #[builder(default = "openai")]
+    owned_by: &'a str,
+}
+
 #[derive(Serialize, TypedBuilder, Clone)]
 pub struct Delta<'a> {
     #[builder(default)]
@@ -58,4 +348,10 @@ pub struct Delta<'a> {
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<&'a str>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
 }
@@ -3,25 +3,56 @@ use axum::Json;
 use eventsource_stream::EventStream;
 use futures_core::Stream;
 use serde_json::Value;
+use std::borrow::Cow;
 use std::convert::Infallible;
 use tokio_stream::StreamExt;
 
 use crate::chatgpt::model::resp::{ConvoResponse, PostConvoResponse};
 use crate::chatgpt::model::Role;
 use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::proxy::ext::{Context, Endpoint};
 use crate::serve::ProxyResult;
 use crate::warn;
 
-use super::model;
+use super::{conversation, model, tokenizer, tools, truncate};
+
+/// State of a choice
+struct ChoiceState {
+    stop: u8,
+    previous_message: String,
+    pin_message_id: String,
+    conversation_id: String,
+    set_role: bool,
+    // Hold back the reply while it may be a tool invocation
+    buffering: bool,
+    // Finished by the proxy, e.g. stop sequence, max tokens or tool calls
+    finished: bool,
+    done: bool,
+    truncator: truncate::Truncator,
+}
+
+impl ChoiceState {
+    fn new(buffering: bool) -> Self {
+        Self {
+            stop: 0,
+            previous_message: String::new(),
+            pin_message_id: String::new(),
+            conversation_id: String::new(),
+            set_role: true,
+            buffering,
+            finished: false,
+            done: false,
+            truncator: truncate::Truncator::default(),
+        }
+    }
+}
 
 struct HandlerContext<'a> {
-    stop: &'a mut u8,
+    index: i64,
     id: &'a str,
     timestamp: &'a i64,
-    model: &'a str,
-    previous_message: &'a mut String,
-    pin_message_id: &'a mut String,
-    set_role: &'a mut bool,
+    config: &'a Context,
+    state: &'a mut ChoiceState,
 }
 
 /// Check if should skip conversion
@@ -43,52 +74,119 @@ fn should_skip_conversion(convo: &ConvoResponse, pin_message_id: &str) -> bool {
     role_check || metadata_check
 }
 
+/// Generate the response id of the endpoint
+fn generate_id(config: &Context) -> String {
+    match config.endpoint {
+        Endpoint::Completions => format!("cmpl-{}", crate::generate_random_string(29)),
+        _ => super::generate_id(29),
+    }
+}
+
+/// Get the response object of the endpoint
+fn object(config: &Context, stream: bool) -> &'static str {
+    match (config.endpoint, stream) {
+        (Endpoint::Completions, _) => "text_completion",
+        (_, true) => "chat.completion.chunk",
+        (_, false) => "chat.completion",
+    }
+}
+
+/// Convert the ChatGPT finish details type to the OpenAI finish reason
+fn finish_reason(finish_details_type: &str) -> &str {
+    match finish_details_type {
+        "max_tokens" => "length",
+        other => other,
+    }
+}
+
 pub(super) fn stream_handler(
-    mut event_soure: EventStream<
-        impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
+    event_sources: Vec<
+        EventStream<impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin>,
     >,
-    model: String,
+    config: Context,
 ) -> Result<impl Stream<Item = Result<Event, Infallible>>, ResponseError> {
-    let id = super::generate_id(29);
+    let id = generate_id(&config);
     let timestamp = super::current_timestamp()?;
     let stream = async_stream::stream! {
-        let mut previous_message = String::new();
-        let mut pin_message_id = String::new();
-        let mut set_role = true;
-        let mut stop: u8 = 0;
+        let mut states = event_sources
+            .iter()
+            .map(|_| ChoiceState::new(!config.tools.is_empty()))
+            .collect::<Vec<_>>();
 
-        while let Some(event_result) = event_soure.next().await {
+        // The event source of a choice is cancelled once the choice is finished by the proxy
+        let (cancels, cancelled): (Vec<_>, Vec<_>) = event_sources
+            .iter()
+            .map(|_| tokio::sync::oneshot::channel::<()>())
+            .unzip();
+        let mut cancels = cancels.into_iter().map(Some).collect::<Vec<_>>();
+
+        // Merge the event sources of all choices
+        let mut event_soure = futures::stream::select_all(
+            event_sources
+                .into_iter()
+                .zip(cancelled)
+                .enumerate()
+                .map(|(index, (event_source, cancelled))| {
+                    futures::StreamExt::take_until(event_source, cancelled)
+                        .map(move |event| (index, event))
+                }),
+        );
+
+        while let Some((index, event_result)) = event_soure.next().await {
             match event_result {
                 Ok(message) =>  {
                     if message.data.eq("[DONE]") {
-                        yield Ok(Event::default().data(message.data));
-                        break;
-                    }
-                    if let Ok(res) = serde_json::from_str::<PostConvoResponse>(&message.data) {
+                        states[index].done = true;
+                    } else if let Ok(res) = serde_json::from_str::<PostConvoResponse>(&message.data) {
                         if let PostConvoResponse::Conversation(convo) = res {
+                            let state = &mut states[index];
 
                             // Skip if role is not assistant
-                            if should_skip_conversion(&convo, &pin_message_id) {
+                            if should_skip_conversion(&convo, &state.pin_message_id) {
                                 continue;
                             }
 
+                            if state.conversation_id.is_empty() {
+                                state.conversation_id.push_str(convo.conversation_id());
+                            }
+
                             let mut context = HandlerContext {
-                                stop: &mut stop,
+                                index: index as i64,
                                 id: &id,
                                 timestamp: &timestamp,
-                                model: &model,
-                                previous_message: &mut previous_message,
-                                pin_message_id: &mut pin_message_id,
-                                set_role: &mut set_role,
+                                config: &config,
+                                state,
                             };
 
-                            if let Ok(event) = event_convert_handler(&mut context, convo).await {
-                                if stop == 0 || stop <= 1 {
+                            if let Ok(Some(event)) = event_convert_handler(&mut context, convo).await {
+                                if context.state.stop <= 1 {
                                     yield Ok(event);
                                 }
                             }
+
+                            // Stop polling the upstream of the finished choice
+                            if states[index].finished {
+                                states[index].done = true;
+                                if let Some(cancel) = cancels[index].take() {
+                                    let _ = cancel.send(());
+                                }
+                            }
                         }
                     }
+
+                    // Wait for all choices to be done
+                    if !states.iter().all(|s| s.done) {
+                        continue;
+                    }
+
+                    // Send usage chunk before the stream is done
+                    if config.include_usage {
+                        if let Ok(event) = usage_event_handler(&id, &timestamp, &config, &states) {
+                            yield Ok(event);
+                        }
+                    }
+                    yield Ok(Event::default().data("[DONE]"));
+                    break;
                 },
                 Err(err) => {
                     warn!("event-source stream error: {}", err);
@@ -98,18 +196,71 @@ pub(super) fn stream_handler(
 
         }
 
+        // Store the conversation state for continuity
+        if let (Some(key), Some(account)) =
+            (config.conversation_key, config.conversation_account)
+        {
+            conversation::put(
+                key,
+                account,
+                &states[0].conversation_id,
+                &states[0].pin_message_id,
+            );
+        }
+
         drop(event_soure)
     };
     Ok(stream)
 }
 
+fn usage_event_handler(
+    id: &str,
+    timestamp: &i64,
+    config: &Context,
+    states: &[ChoiceState],
+) -> ProxyResult<Event> {
+    let completions = states.iter().map(|s| s.previous_message.as_str());
+    let resp = model::Resp::builder()
+        .id(id)
+        .object(object(config, true))
+        .created(timestamp)
+        .model(&config.model)
+        .choices(vec![])
+        .usage(Some(usage(config.prompt_tokens, completions)))
+        .build();
+
+    let data = format!(
+        " {}",
+        serde_json::to_string(&resp).map_err(ProxyError::DeserializeError)?
+    );
+    Ok(Event::default().data(data))
+}
+
+/// Build usage from prompt tokens and completion texts of all choices
+fn usage<'a>(prompt_tokens: usize, completions: impl Iterator<Item = &'a str>) -> model::Usage {
+    let completion_tokens = completions.map(tokenizer::num_tokens).sum::<usize>();
+    model::Usage::builder()
+        .prompt_tokens(prompt_tokens as i64)
+        .completion_tokens(completion_tokens as i64)
+        .total_tokens((prompt_tokens + completion_tokens) as i64)
+        .build()
+}
+
 async fn event_convert_handler(
     context: &mut HandlerContext<'_>,
     convo: ConvoResponse,
-) -> ProxyResult<Event> {
+) -> ProxyResult<Option<Event>> {
+    let config = context.config;
+    let state = &mut *context.state;
+
+    // Skip if the choice has been finished by the proxy
+    if state.finished {
+        return Ok(None);
+    }
+
     // Set pin message id
-    if context.pin_message_id.is_empty() {
-        context.pin_message_id.push_str(convo.message_id())
+    if state.pin_message_id.is_empty() {
+        state.pin_message_id.push_str(convo.message_id())
     }
 
     let message = convo
@@ -117,62 +268,137 @@ async fn event_convert_handler(
         .first()
         .ok_or_else(|| ProxyError::BodyMessageIsEmpty)?;
 
-    let finish_reason = convo
+    let mut finish_reason = convo
         .end_turn()
         .filter(|&end| end)
-        .map(|_| convo.metadata_finish_details_type());
+        .map(|_| finish_reason(convo.metadata_finish_details_type()));
+
+    // Enforce the stop sequences and max tokens
+    let message = match state
+        .truncator
+        .check(message, &config.stop, config.max_tokens)
+    {
+        Some((truncated, reason)) => {
+            state.finished = true;
+            finish_reason = Some(reason);
+            Cow::Owned(truncated)
+        }
+        None => Cow::Borrowed(message.as_str()),
+    };
 
-    let role = if *context.set_role {
-        *context.set_role = false;
+    // Hold back the reply until it is known whether it is a tool invocation
+    let mut tool_calls = None;
+    if state.buffering {
+        if finish_reason.is_none() && tools::maybe_tool_call(&message) {
+            return Ok(None);
+        }
+        state.buffering = false;
+        if finish_reason.is_some() {
+            tool_calls = tools::parse(&message, &config.tools);
+        }
+    }
+
+    let role = if state.set_role {
+        state.set_role = false;
         Some(convo.role())
     } else {
         None
     };
 
-    let return_message = if let Some("stop") = finish_reason.as_deref() {
-        *context.stop += 1;
-        None
+    // Hold back the tail that may be the beginning of a stop sequence
+    let pending = if finish_reason.is_none() {
+        truncate::pending_stop(&message, &config.stop)
     } else {
-        Some(message.trim_start_matches(context.previous_message.as_str()))
+        0
     };
+    let message = &message[..message.len() - pending];
 
-    context.previous_message.clear();
-    context.previous_message.push_str(message);
+    let (delta, finish_reason) = if let Some(calls) = tool_calls {
+        state.stop += 1;
+        state.finished = true;
+        let (tool_calls, function_call) = tools::split(calls, config.legacy_function_call);
+        let delta = model::Delta::builder()
+            .role(role)
+            .tool_calls(tool_calls)
+            .function_call(function_call)
+            .build();
+        (
+            delta,
+            Some(tools::finish_reason(config.legacy_function_call)),
+        )
+    } else {
+        let return_message = message
+            .strip_prefix(state.previous_message.as_str())
+            .unwrap_or(message);
+        let return_message = if let Some(reason) = finish_reason {
+            if reason.eq("stop") {
+                state.stop += 1;
+            }
+            // Flush the held back reply
+            Some(return_message).filter(|m| !m.is_empty())
+        } else {
+            Some(return_message)
+        };
+        let delta = model::Delta::builder()
+            .role(role)
+            .content(return_message)
+            .build();
+        (delta, finish_reason)
+    };
 
-    let delta = model::Delta::builder()
-        .role(role)
-        .content(return_message)
-        .build();
+    let choice = if config.endpoint == Endpoint::Completions {
+        model::Choice::builder()
+            .index(context.index)
+            .text(Some(delta.content.unwrap_or_default()))
+            .finish_reason(finish_reason)
+            .build()
+    } else {
+        model::Choice::builder()
+            .index(context.index)
+            .delta(Some(delta))
+            .finish_reason(finish_reason)
+            .build()
+    };
 
     let resp = model::Resp::builder()
         .id(context.id)
-        .object("chat.completion.chunk")
+        .object(object(config, true))
         .created(context.timestamp)
-        .model(context.model)
-        .choices(vec![model::Choice::builder()
-            .index(0)
-            .delta(Some(delta))
-            .finish_reason(finish_reason)
-            .build()])
+        .model(&config.model)
+        .choices(vec![choice])
         .build();
 
     let data = format!(
         " {}",
         serde_json::to_string(&resp).map_err(ProxyError::DeserializeError)?
     );
-    Ok(Event::default().data(data))
+
+    state.previous_message.clear();
+    state.previous_message.push_str(message);
+
+    Ok(Some(Event::default().data(data)))
 }
 
-pub(super) async fn not_stream_handler(
+/// Collected reply of a choice
+struct ChoiceReply {
+    message: String,
+    finish_reason: Option<String>,
+    conversation_id: String,
+    message_id: String,
+}
+
+/// Collect the reply of a choice, the upstream is no longer polled once a limit is reached
+async fn collect_reply(
     mut event_soure: EventStream<
         impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
     >,
-    model: String,
-) -> ProxyResult<Json<Value>> {
-    let id = super::generate_id(29);
-    let timestamp = super::current_timestamp()?;
+    config: &Context,
+) -> ProxyResult<ChoiceReply> {
+    let mut truncator = truncate::Truncator::default();
     let mut previous_message = String::new();
     let mut finish_reason = None;
+    let mut conversation_id = String::new();
+    let mut message_id = String::new();
 
     while let Some(event_result) = event_soure.next().await {
         match event_result {
@@ -187,7 +413,15 @@ pub(super) async fn not_stream_handler(
                     if let PostConvoResponse::Conversation(convo) = res {
                         let finish = convo.metadata_finish_details_type();
                         if !finish.is_empty() {
-                            finish_reason = Some(finish.to_owned())
+                            finish_reason = Some(self::finish_reason(finish).to_owned())
+                        }
+
+                        // Record the assistant message for continuity
+                        if convo.role().eq(&Role::Assistant) {
+                            conversation_id.clear();
+                            conversation_id.push_str(convo.conversation_id());
+                            message_id.clear();
+                            message_id.push_str(convo.message_id());
                         }
 
                         // If message is not empty, set previous message
@@ -196,7 +430,15 @@ pub(super) async fn not_stream_handler(
                             previous_message.push_str(message);
                         }
 
-                        drop(convo)
+                        drop(convo);
+
+                        // The stop sequences and max tokens are enforced by the caller
+                        if truncator
+                            .check(&previous_message, &config.stop, config.max_tokens)
+                            .is_some()
+                        {
+                            break;
+                        }
                     }
                 }
             }
@@ -210,28 +452,115 @@ pub(super) async fn not_stream_handler(
 
     drop(event_soure);
 
-    let message = model::Message::builder()
-        .role(Role::Assistant)
-        .content(previous_message)
-        .build();
+    Ok(ChoiceReply {
+        message: previous_message,
+        finish_reason,
+        conversation_id,
+        message_id,
+    })
+}
+
+pub(super) async fn not_stream_handler(
+    event_sources: Vec<
+        EventStream<impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin>,
+    >,
+    config: Context,
+) -> ProxyResult<Json<Value>> {
+    let id = generate_id(&config);
+    let timestamp = super::current_timestamp()?;
+
+    // Collect the replies of all choices
+    let mut replies = futures::future::try_join_all(
+        event_sources
+            .into_iter()
+            .map(|event_source| collect_reply(event_source, &config)),
+    )
+    .await?;
+
+    // Store the conversation state for continuity
+    if let (Some(key), Some(account)) = (config.conversation_key, config.conversation_account) {
+        conversation::put(
+            key,
+            account,
+            &replies[0].conversation_id,
+            &replies[0].message_id,
+        );
+    }
+
+    // Enforce the stop sequences and max tokens
+    for reply in replies.iter_mut() {
+        if let Some((truncated, reason)) =
+            truncate::truncate(&reply.message, &config.stop, config.max_tokens)
+        {
+            reply.message = truncated;
+            reply.finish_reason = Some(reason.to_owned());
+        }
+    }
+
+    let usage = usage(
+        config.prompt_tokens,
+        replies.iter().map(|r| r.message.as_str()),
+    );
+
+    let mut choices = Vec::with_capacity(replies.len());
+    for (index, reply) in replies.into_iter().enumerate() {
+        // Parse the emulated tool calls
+        let tool_calls = if config.tools.is_empty() {
+            None
+        } else {
+            tools::parse(&reply.message, &config.tools)
+        };
+
+        let (message, finish_reason) = match tool_calls {
+            Some(calls) => {
+                let (tool_calls, function_call) = tools::split(calls, config.legacy_function_call);
+                let message = model::Message::builder()
+                    .role(Role::Assistant)
+                    .tool_calls(tool_calls)
+                    .function_call(function_call)
+                    .build();
+                (
+                    message,
+                    Some(tools::finish_reason(config.legacy_function_call).to_owned()),
+                )
+            }
+            None => {
+                let message = model::Message::builder()
+                    .role(Role::Assistant)
+                    .content(reply.message)
+                    .build();
+                (message, reply.finish_reason)
+            }
+        };
+        choices.push((index, message, finish_reason));
+    }
 
     let resp = model::Resp::builder()
         .id(&id)
-        .object("chat.completion.chunk")
+        .object(object(&config, false))
         .created(&timestamp)
-        .model(&model)
-        .choices(vec![model::Choice::builder()
-            .index(0)
-            .message(Some(message))
-            .finish_reason(finish_reason.as_deref())
-            .build()])
-        .usage(Some(
-            model::Usage::builder()
-                .prompt_tokens(0)
-                .completion_tokens(0)
-                .total_tokens(0)
-                .build(),
-        ))
+        .model(&config.model)
+        .choices(
+            choices
+                .iter()
+                .map(|(index, message, finish_reason)| {
+                    if config.endpoint == Endpoint::Completions {
+                        model::Choice::builder()
+                            .index(*index as i64)
+                            .text(message.content.as_ref().and_then(|c| c.as_str()))
+                            .finish_reason(finish_reason.as_deref())
+                            .build()
+                    } else {
+                        model::Choice::builder()
+                            .index(*index as i64)
+                            .message(Some(message.clone()))
+                            .finish_reason(finish_reason.as_deref())
+                            .build()
+                    }
+                })
+                .collect(),
+        )
+        .usage(Some(usage))
         .build();
     let value = serde_json::to_value(&resp).map_err(ProxyError::DeserializeError)?;
     Ok(Json(value))
@@ -0,0 +1,127 @@
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+use super::model;
+
+/// Every message follows <|start|>{role/name}\n{content}<|end|>\n
+const TOKENS_PER_MESSAGE: usize = 3;
+/// Every reply is primed with <|start|>assistant<|message|>
+const TOKENS_PER_REPLY: usize = 3;
+/// Every image costs at least the low detail tokens
+const TOKENS_PER_IMAGE: usize = 85;
+
+static BPE: OnceLock<CoreBPE> = OnceLock::new();
+
+fn bpe() -> &'static CoreBPE {
+    BPE.get_or_init(|| tiktoken_rs::cl100k_base().expect("Failed to load cl100k_base tokenizer"))
+}
+
+/// Count the tokens of a text
+pub(super) fn num_tokens(text: &str) -> usize {
+    if text.is_empty() {
+        return 0;
+    }
+    bpe().encode_with_special_tokens(text).len()
+}
+
+/// Running token count of a growing text, e.g. a streamed reply.
+/// The text before the last word boundary never changes its tokens, only the tail after it is re-tokenized.
+#[derive(Default)]
+pub(super) struct TokenCounter {
+    /// Length of the counted text (bytes)
+    counted: usize,
+    tokens: usize,
+}
+
+impl TokenCounter {
+    /// Count the tokens of the text, the text must extend the previously counted text
+    pub fn count(&mut self, text: &str) -> usize {
+        if text.len() < self.counted || !text.is_char_boundary(self.counted) {
+            *self = Self::default();
+        }
+
+        let tail = &text[self.counted..];
+        if let Some(boundary) = word_boundary(tail) {
+            self.tokens += num_tokens(&tail[..boundary]);
+            self.counted += boundary;
+        }
+        self.tokens + num_tokens(&text[self.counted..])
+    }
+}
+
+/// Get the last word boundary of the text, a space between two non-whitespace characters
+/// always starts a new pre-tokenized piece, so the tokens before it are final
+fn word_boundary(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    (1..bytes.len().saturating_sub(1)).rev().find(|&i| {
+        bytes[i] == b' '
+            && !bytes[i - 1].is_ascii_whitespace()
+            && !bytes[i + 1].is_ascii_whitespace()
+    })
+}
+
+/// Truncate the text to the max tokens, `None` if the text does not exceed
+pub(super) fn truncate(text: &str, max_tokens: usize) -> Option<String> {
+    let tokens = bpe().encode_with_special_tokens(text);
+    if tokens.len() <= max_tokens {
+        return None;
+    }
+    // Drop the trailing tokens that split a multi-byte character
+    (0..=max_tokens)
+        .rev()
+        .find_map(|n| bpe().decode(tokens[..n].to_vec()).ok())
+}
+
+/// Count the prompt tokens of the chat messages
+pub(super) fn num_tokens_from_messages(messages: &[model::Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| {
+            let content = m
+                .content
+                .as_ref()
+                .map(|c| num_tokens(&c.text()) + c.images().len() * TOKENS_PER_IMAGE)
+                .unwrap_or_default();
+            TOKENS_PER_MESSAGE + num_tokens(&m.role.to_string()) + content
+        })
+        .sum::<usize>()
+        + TOKENS_PER_REPLY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatgpt::model::Role;
+
+    #[test]
+    fn test_num_tokens() {
+        assert_eq!(num_tokens(""), 0);
+        assert_eq!(num_tokens("hello world"), 2);
+    }
+
+    #[test]
+    fn test_token_counter() {
+        let text = "Hello world, the  quick brown fox.\nJumps over 1234 lazy dogs!";
+        let mut counter = TokenCounter::default();
+        for end in (1..=text.len()).filter(|&i| text.is_char_boundary(i)) {
+            assert_eq!(counter.count(&text[..end]), num_tokens(&text[..end]));
+        }
+        assert_eq!(counter.count("Hello"), 1);
+    }
+
+    #[test]
+    fn test_num_tokens_from_messages() {
+        let messages = vec![
+            model::Message::builder()
+                .role(Role::System)
+                .content("You are a helpful assistant.".to_owned())
+                .build(),
+            model::Message::builder()
+                .role(Role::User)
+                .content("hello world".to_owned())
+                .build(),
+        ];
+        // 3 + 1 + 6 (system) + 3 + 1 + 2 (user) + 3 (reply)
+        assert_eq!(num_tokens_from_messages(&messages), 19);
+    }
+}
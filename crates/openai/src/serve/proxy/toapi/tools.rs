@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+
+use crate::chatgpt::model::Role;
+
+use super::model;
+
+/// Code fence that may wrap the tool invocation
+const FENCE: &str = "```json";
+
+/// Tool invocation the model is asked to reply with
+#[derive(Serialize, Deserialize)]
+struct Invocation {
+    tool_calls: Vec<Call>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Call {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+/// Get the tool choice, `tool_choice` takes precedence over legacy `function_call`
+fn choice(body: &model::Req) -> Option<&model::ToolChoice> {
+    body.tool_choice.as_ref().or(body.function_call.as_ref())
+}
+
+/// Get the functions of the tools and legacy functions
+fn functions(body: &model::Req) -> impl Iterator<Item = &model::Function> {
+    body.tools
+        .iter()
+        .filter(|t| t.kind.eq("function"))
+        .map(|t| &t.function)
+        .chain(body.functions.iter())
+}
+
+/// Get the callable tool names, empty if tool calling is disabled
+pub(super) fn names(body: &model::Req) -> Vec<String> {
+    if choice(body).map(|c| c.is("none")).unwrap_or_default() {
+        return vec![];
+    }
+    functions(body).map(|f| f.name.clone()).collect()
+}
+
+/// Check if the client uses legacy `functions` instead of `tools`
+pub(super) fn is_legacy(body: &model::Req) -> bool {
+    body.tools.is_empty() && !body.functions.is_empty()
+}
+
+/// Build the prompt that describes the tools to the model
+pub(super) fn prompt(body: &model::Req) -> Option<String> {
+    if names(body).is_empty() {
+        return None;
+    }
+
+    let functions = functions(body)
+        .map(|f| {
+            json!({
+                "name": f.name,
+                "description": f.description,
+                "parameters": f.parameters,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut prompt = format!(
+        "You have access to the following tools, described in JSON Schema:\n{}\n\n\
+        When you decide to call one or more tools, reply with only a JSON object in the following format and nothing else:\n\
+        {{\"tool_calls\": [{{\"name\": \"<tool name>\", \"arguments\": {{<arguments matching the tool parameters>}}}}]}}\n\
+        The results of the tool calls will be sent back to you in the following messages. \
+        If no tool is needed, reply to the user normally.",
+        serde_json::to_string_pretty(&functions).ok()?
+    );
+
+    match choice(body) {
+        Some(c) if c.name().is_some() => {
+            prompt.push_str(&format!(
+                "\nYou must call the tool `{}`.",
+                c.name().unwrap_or_default()
+            ));
+        }
+        Some(c) if c.is("required") => {
+            prompt.push_str("\nYou must call at least one tool.");
+        }
+        _ => {}
+    }
+
+    Some(prompt)
+}
+
+/// Render the message content, tool calls and tool results are rendered as text
+pub(super) fn render(message: &model::Message) -> Cow<'_, str> {
+    let content = message
+        .content
+        .as_ref()
+        .map(model::MessageContent::text)
+        .unwrap_or_default();
+    match message.role {
+        Role::Tool => {
+            let name = message
+                .tool_call_id
+                .as_deref()
+                .or(message.name.as_deref())
+                .unwrap_or_default();
+            Cow::Owned(format!("Result of the tool call `{name}`:\n{content}"))
+        }
+        Role::Assistant if message.tool_calls.is_some() || message.function_call.is_some() => {
+            let calls = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|c| &c.function)
+                .chain(message.function_call.iter())
+                .map(|f| Call {
+                    name: f.name.clone(),
+                    arguments: serde_json::from_str(&f.arguments)
+                        .unwrap_or_else(|_| Value::String(f.arguments.clone())),
+                })
+                .collect();
+            serde_json::to_string(&Invocation { tool_calls: calls })
+                .map(Cow::Owned)
+                .unwrap_or(content)
+        }
+        _ => content,
+    }
+}
+
+/// Check if the (partial) reply may still turn out to be a tool invocation
+pub(super) fn maybe_tool_call(text: &str) -> bool {
+    let text = text.trim_start();
+    if text.is_empty() || text.starts_with('{') || FENCE.starts_with(text) {
+        return true;
+    }
+
+    match text.strip_prefix("```") {
+        Some(rest) => {
+            let rest = rest.strip_prefix("json").unwrap_or(rest).trim_start();
+            rest.is_empty() || rest.starts_with('{')
+        }
+        None => false,
+    }
+}
+
+/// Parse the tool calls out of the reply, only the known tools are accepted
+pub(super) fn parse(text: &str, names: &[String]) -> Option<Vec<model::ToolCall>> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix(FENCE)
+        .or_else(|| text.strip_prefix("```"))
+        .and_then(|t| t.strip_suffix("```"))
+        .unwrap_or(text)
+        .trim();
+
+    if !text.starts_with('{') || !text.ends_with('}') {
+        return None;
+    }
+
+    let invocation = serde_json::from_str::<Invocation>(text).ok()?;
+    if invocation.tool_calls.is_empty()
+        || invocation
+            .tool_calls
+            .iter()
+            .any(|c| !names.contains(&c.name))
+    {
+        return None;
+    }
+
+    let calls = invocation
+        .tool_calls
+        .into_iter()
+        .enumerate()
+        .map(|(index, call)| {
+            let arguments = match call.arguments {
+                Value::String(s) => s,
+                Value::Null => String::from("{}"),
+                v => v.to_string(),
+            };
+            model::ToolCall::builder()
+                .index(Some(index))
+                .id(format!("call_{}", crate::generate_random_string(24)))
+                .function(
+                    model::FunctionCall::builder()
+                        .name(call.name)
+                        .arguments(arguments)
+                        .build(),
+                )
+                .build()
+        })
+        .collect();
+
+    Some(calls)
+}
+
+/// Split the tool calls into `tool_calls` or legacy `function_call`
+pub(super) fn split(
+    calls: Vec<model::ToolCall>,
+    legacy: bool,
+) -> (Option<Vec<model::ToolCall>>, Option<model::FunctionCall>) {
+    if legacy {
+        (None, calls.into_iter().next().map(|c| c.function))
+    } else {
+        (Some(calls), None)
+    }
+}
+
+/// Finish reason of the tool calls
+pub(super) fn finish_reason(legacy: bool) -> &'static str {
+    if legacy {
+        "function_call"
+    } else {
+        "tool_calls"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maybe_tool_call() {
+        assert!(maybe_tool_call(""));
+        assert!(maybe_tool_call("  {\"tool"));
+        assert!(maybe_tool_call("``"));
+        assert!(maybe_tool_call("```json\n{"));
+        assert!(!maybe_tool_call("Hello"));
+        assert!(!maybe_tool_call("```python"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let names = vec!["get_weather".to_owned()];
+        let text = "```json\n{\"tool_calls\": [{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}]}\n```";
+        let calls = parse(text, &names).unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, "{\"city\":\"Paris\"}");
+
+        let text = "{\"tool_calls\": [{\"name\": \"unknown\", \"arguments\": {}}]}";
+        assert!(parse(text, &names).is_none());
+        assert!(parse("The weather is sunny", &names).is_none());
+    }
+}
@@ -0,0 +1,124 @@
+use super::tokenizer;
+
+/// Truncate the reply by the stop sequences and max tokens,
+/// returns the truncated reply and the finish reason if a limit is reached
+pub(super) fn truncate(
+    text: &str,
+    stop: &[String],
+    max_tokens: Option<usize>,
+) -> Option<(String, &'static str)> {
+    // The reply ends before the first stop sequence
+    let stop_at = stop
+        .iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min();
+    let stopped = stop_at.map(|i| &text[..i]).unwrap_or(text);
+
+    if let Some(max_tokens) = max_tokens {
+        if let Some(truncated) = tokenizer::truncate(stopped, max_tokens) {
+            return Some((truncated, "length"));
+        }
+    }
+
+    stop_at.map(|_| (stopped.to_owned(), "stop"))
+}
+
+/// Checks a growing reply against the stop sequences and max tokens,
+/// only the new text is searched and tokenized on every check
+#[derive(Default)]
+pub(super) struct Truncator {
+    /// Length of the searched reply (bytes)
+    searched: usize,
+    counter: tokenizer::TokenCounter,
+}
+
+impl Truncator {
+    /// Check the reply, returns the truncated reply and the finish reason once a limit is reached
+    pub fn check(
+        &mut self,
+        text: &str,
+        stop: &[String],
+        max_tokens: Option<usize>,
+    ) -> Option<(String, &'static str)> {
+        // A stop sequence may start in the previously searched text
+        let max_len = stop.iter().map(String::len).max().unwrap_or_default();
+        let mut from = self.searched.saturating_sub(max_len).min(text.len());
+        while !text.is_char_boundary(from) {
+            from -= 1;
+        }
+        self.searched = text.len();
+
+        let stopped = stop
+            .iter()
+            .filter(|s| !s.is_empty())
+            .any(|s| text[from..].contains(s.as_str()));
+        let exceeded = max_tokens.is_some_and(|max| self.counter.count(text) > max);
+        if !stopped && !exceeded {
+            return None;
+        }
+        truncate(text, stop, max_tokens)
+    }
+}
+
+/// Get the length of the reply tail that may be the beginning of a stop sequence,
+/// the tail must be held back until the stop sequence is complete or ruled out
+pub(super) fn pending_stop(text: &str, stop: &[String]) -> usize {
+    let max_len = stop.iter().map(String::len).max().unwrap_or_default();
+    (text.len().saturating_sub(max_len)..text.len())
+        .filter(|&i| text.is_char_boundary(i))
+        .map(|i| &text[i..])
+        .find(|tail| {
+            stop.iter()
+                .any(|s| s.len() > tail.len() && s.starts_with(tail))
+        })
+        .map(str::len)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        let stop = vec!["\n\n".to_owned(), "END".to_owned()];
+        assert_eq!(
+            truncate("Hello world\n\nfoo END", &stop, None),
+            Some(("Hello world".to_owned(), "stop"))
+        );
+        assert_eq!(truncate("Hello world", &stop, None), None);
+        assert_eq!(
+            truncate("Hello world, foo bar", &[], Some(2)),
+            Some(("Hello world".to_owned(), "length"))
+        );
+        assert_eq!(truncate("Hello world", &[], Some(2)), None);
+    }
+
+    #[test]
+    fn test_truncator() {
+        let stop = vec!["END".to_owned()];
+        let mut truncator = Truncator::default();
+        assert_eq!(truncator.check("Hello E", &stop, Some(3)), None);
+        assert_eq!(
+            truncator.check("Hello END", &stop, Some(3)),
+            Some(("Hello ".to_owned(), "stop"))
+        );
+
+        let mut truncator = Truncator::default();
+        assert_eq!(truncator.check("Hello world", &[], Some(2)), None);
+        assert_eq!(
+            truncator.check("Hello world, foo", &[], Some(2)),
+            Some(("Hello world".to_owned(), "length"))
+        );
+    }
+
+    #[test]
+    fn test_pending_stop() {
+        let stop = vec!["END".to_owned()];
+        assert_eq!(pending_stop("Hello E", &stop), 1);
+        assert_eq!(pending_stop("Hello EN", &stop), 2);
+        assert_eq!(pending_stop("Hello", &stop), 0);
+        assert_eq!(pending_stop("Hello", &[]), 0);
+    }
+}
@@ -1,9 +1,15 @@
 use super::error::{ProxyError, ResponseError};
-use crate::{gpt_model::GPTModel, with_context, URL_CHATGPT_API};
+use super::metric;
+use crate::chatgpt::model::resp::GetModelsResponse;
+use crate::context::store;
+use crate::{gpt_model::ModelEntry, warn, with_context, URL_CHATGPT_API};
 use moka::sync::Cache;
-use std::str::FromStr;
+use redis::AsyncCommands;
 use tokio::sync::OnceCell;
 
+/// PUID cache time to live (second)
+const PUID_TTL: u64 = 3600 * 24;
+
 static PUID_CACHE: OnceCell<Cache<String, String>> = OnceCell::const_new();
 
 pub(super) fn reduce_key(token: &str) -> Result<String, ResponseError> {
@@ -13,41 +19,135 @@ pub(super) fn reduce_key(token: &str) -> Result<String, ResponseError> {
     Ok(token_profile.email().to_owned())
 }
 
+/// Get the account of the access token for the sticky client selection, only decoded in the affinity mode
+pub(super) fn affinity(token: Option<&str>) -> Option<String> {
+    token
+        .filter(|_| with_context!(proxy_affinity))
+        .and_then(|token| reduce_key(token.trim_start_matches("Bearer ")).ok())
+}
+
 async fn cache() -> &'static Cache<String, String> {
     PUID_CACHE
         .get_or_init(|| async {
             Cache::builder()
-                .time_to_live(std::time::Duration::from_secs(3600 * 24))
+                .time_to_live(std::time::Duration::from_secs(PUID_TTL))
                 .build()
         })
         .await
 }
 
+/// Get the cached PUID, the redis store is preferred when it is configured
+async fn cached(cache_id: &str) -> Option<String> {
+    match with_context!(redis) {
+        Some(redis) => {
+            let result = match redis.connection().await {
+                Ok(mut conn) => conn.get(store::key("puid", cache_id)).await,
+                Err(err) => Err(err),
+            };
+            result.unwrap_or_else(|err| {
+                warn!("Failed to get PUID from redis: {}", err);
+                None
+            })
+        }
+        None => cache().await.get(cache_id),
+    }
+}
+
+/// Cache the PUID, the redis store is preferred when it is configured
+async fn cache_puid(cache_id: String, puid: String) {
+    match with_context!(redis) {
+        Some(redis) => {
+            let result = match redis.connection().await {
+                Ok(mut conn) => {
+                    conn.set_ex::<_, _, ()>(store::key("puid", &cache_id), puid, PUID_TTL as usize)
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            if let Some(err) = result.err() {
+                warn!("Failed to cache PUID to redis: {}", err)
+            }
+        }
+        None => cache().await.insert(cache_id, puid),
+    }
+}
+
+/// Get the cached PUID keys (account email)
+pub(super) async fn cached_keys() -> anyhow::Result<Vec<String>> {
+    match with_context!(redis) {
+        Some(redis) => {
+            let prefix = store::key("puid", "");
+            let mut conn = redis.connection().await?;
+            // SCAN instead of KEYS, so a large keyspace does not block the server
+            let mut iter = conn.scan_match::<_, String>(format!("{prefix}*")).await?;
+            let mut keys = vec![];
+            while let Some(key) = iter.next_item().await {
+                keys.push(key.trim_start_matches(&prefix).to_owned());
+            }
+            Ok(keys)
+        }
+        None => Ok(cache().await.iter().map(|(k, _)| k.to_string()).collect()),
+    }
+}
+
+/// Flush the PUID cache, returns the number of the flushed entries
+pub(super) async fn flush() -> anyhow::Result<usize> {
+    let keys = cached_keys().await?;
+    match with_context!(redis) {
+        Some(redis) if !keys.is_empty() => {
+            let keys = keys
+                .iter()
+                .map(|k| store::key("puid", k))
+                .collect::<Vec<String>>();
+            let mut conn = redis.connection().await?;
+            conn.del::<_, ()>(keys).await?;
+        }
+        Some(_) => {}
+        None => cache().await.invalidate_all(),
+    }
+    Ok(keys.len())
+}
+
 pub(super) async fn get_or_init(
     token: &str,
-    model: &str,
+    model: &ModelEntry,
     cache_id: String,
 ) -> Result<Option<String>, ResponseError> {
     let token = token.trim_start_matches("Bearer ");
-    let puid_cache = cache().await;
 
-    if let Some(p) = puid_cache.get(&cache_id) {
-        return Ok(Some(p.clone()));
+    if let Some(p) = cached(&cache_id).await {
+        metric::puid_cache(true);
+        return Ok(Some(p));
     }
+    metric::puid_cache(false);
 
-    if GPTModel::from_str(model)?.is_gpt4() {
-        let resp = with_context!(api_client)
+    if model.puid {
+        let (client, health) = with_context!(checkout_api_client, Some(cache_id.as_str()));
+        let result = client
             .get(format!("{URL_CHATGPT_API}/backend-api/models"))
             .bearer_auth(token)
             .send()
-            .await
+            .await;
+        health.observe(&result);
+        let resp = result
             .map_err(ResponseError::InternalServerError)?
             .error_for_status()
             .map_err(ResponseError::BadRequest)?;
 
-        if let Some(c) = resp.cookies().into_iter().find(|c| c.name().eq("_puid")) {
-            let puid = c.value().to_owned();
-            puid_cache.insert(cache_id, puid.clone());
+        let puid = resp
+            .cookies()
+            .into_iter()
+            .find(|c| c.name().eq("_puid"))
+            .map(|c| c.value().to_owned());
+
+        // Refresh the model table with the upstream models
+        match resp.json::<GetModelsResponse>().await {
+            Ok(models) => with_context!(gpt_models).refresh(models.real_models()),
+            Err(err) => warn!("Failed to parse models: {}", err),
+        }
+
+        if let Some(puid) = puid {
+            cache_puid(cache_id, puid.clone()).await;
             return Ok(Some(puid));
         };
     }
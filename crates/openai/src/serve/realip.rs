@@ -0,0 +1,341 @@
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::{request::Parts, Extensions, HeaderMap, Request};
+use axum::Extension;
+use cidr::{Cidr, IpCidr};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
+use tower::Layer;
+use tower_http::trace::MakeSpan;
+use tracing::Span;
+
+use super::error::ResponseError;
+use crate::with_context;
+
+/// PROXY protocol v2 signature
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// PROXY protocol v1 max header length
+const V1_MAX_LENGTH: usize = 107;
+
+/// PROXY protocol header read timeout
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Source address carried by the PROXY protocol header
+#[derive(Clone, Copy, Debug)]
+pub(super) struct ProxyProtocolAddr(Option<SocketAddr>);
+
+/// Real client ip of the request
+pub(super) struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = ResponseError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let peer = peer_addr(&parts.extensions).ok_or_else(|| {
+            ResponseError::InternalServerError(anyhow::anyhow!("Missing peer address"))
+        })?;
+        Ok(ClientIp(client_ip(&parts.headers, peer.ip())))
+    }
+}
+
+/// Get the peer address, the PROXY protocol source address takes precedence
+fn peer_addr(extensions: &Extensions) -> Option<SocketAddr> {
+    extensions
+        .get::<ProxyProtocolAddr>()
+        .and_then(|addr| addr.0)
+        .or_else(|| {
+            extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|info| info.0)
+        })
+}
+
+/// Get the real client ip, the forwarded headers are only honored when the peer is a trusted proxy
+pub(super) fn client_ip(headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+    resolve(headers, peer, with_context!(trusted_proxies))
+}
+
+fn resolve(headers: &HeaderMap, peer: IpAddr, trusted: &[IpCidr]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    // Walk the chain from right to left, the first untrusted hop is the client
+    let mut chain = forwarded(headers);
+    if chain.is_empty() {
+        chain = x_forwarded_for(headers);
+    }
+    if let Some(ip) = chain
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or(chain.first())
+    {
+        return *ip;
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_node)
+        .unwrap_or(peer)
+}
+
+/// Parse the `for` parameters of the RFC 7239 Forwarded header
+fn forwarded(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim_matches('"')))
+                    .flatten()
+            })
+        })
+        .collect()
+}
+
+/// Parse the X-Forwarded-For header
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(parse_node)
+        .collect()
+}
+
+/// Parse the node, e.g. `1.1.1.1`, `1.1.1.1:80`, `[2001:db8::1]:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|v| v.strip_suffix(']'))
+                .and_then(|v| v.parse().ok())
+        })
+}
+
+/// Request span with the real client ip
+#[derive(Clone, Copy)]
+pub(super) struct MakeClientSpan;
+
+impl<B> MakeSpan<B> for MakeClientSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let client = peer_addr(request.extensions())
+            .map(|peer| client_ip(request.headers(), peer.ip()).to_string())
+            .unwrap_or_default();
+        tracing::info_span!(
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            version = ?request.version(),
+            client = %client
+        )
+    }
+}
+
+/// HAProxy PROXY protocol (v1/v2) acceptor
+#[derive(Clone, Copy)]
+pub(super) struct ProxyProtocolAcceptor {
+    enable: bool,
+}
+
+impl ProxyProtocolAcceptor {
+    pub fn new(enable: bool) -> Self {
+        Self { enable }
+    }
+}
+
+impl<I, S> axum_server::accept::Accept<I, S> for ProxyProtocolAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = BufReader<I>;
+    type Service = <Extension<ProxyProtocolAddr> as Layer<S>>::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let enable = self.enable;
+        Box::pin(async move {
+            let mut stream = BufReader::new(stream);
+            let source = match enable {
+                true => tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "PROXY protocol header timeout")
+                    })??,
+                false => None,
+            };
+            Ok((stream, Extension(ProxyProtocolAddr(source)).layer(service)))
+        })
+    }
+}
+
+fn invalid_header(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read the PROXY protocol header, the header is required on every connection
+async fn read_header<R>(stream: &mut R) -> io::Result<Option<SocketAddr>>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut signature = [0u8; 12];
+    stream.read_exact(&mut signature).await?;
+
+    if signature == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        return parse_v2(header[0], header[1], &payload);
+    }
+
+    if signature.starts_with(b"PROXY ") {
+        let mut line = signature.to_vec();
+        if !line.ends_with(b"\n") {
+            let mut limited = stream.take((V1_MAX_LENGTH - line.len()) as u64);
+            limited.read_until(b'\n', &mut line).await?;
+        }
+        return parse_v1(&line);
+    }
+
+    Err(invalid_header("missing PROXY protocol header"))
+}
+
+/// Parse the PROXY protocol v1 header, e.g. `PROXY TCP4 1.1.1.1 2.2.2.2 1111 2222\r\n`
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let header = std::str::from_utf8(header)
+        .ok()
+        .and_then(|v| v.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid_header("invalid PROXY protocol v1 header"))?;
+
+    let mut parts = header.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some("PROXY"), Some("TCP4" | "TCP6")) => {}
+        (Some("PROXY"), Some("UNKNOWN")) => return Ok(None),
+        _ => return Err(invalid_header("invalid PROXY protocol v1 header")),
+    }
+
+    let ip = parts.next().and_then(|v| v.parse::<IpAddr>().ok());
+    let port = parts.nth(1).and_then(|v| v.parse::<u16>().ok());
+    match (ip, port) {
+        (Some(ip), Some(port)) => Ok(Some(SocketAddr::new(ip, port))),
+        _ => Err(invalid_header("invalid PROXY protocol v1 address")),
+    }
+}
+
+/// Parse the PROXY protocol v2 address block
+fn parse_v2(ver_cmd: u8, family: u8, payload: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid_header("invalid PROXY protocol v2 version"));
+    }
+
+    // LOCAL command, e.g. the health check of the proxy itself
+    if ver_cmd & 0x0F == 0 {
+        return Ok(None);
+    }
+
+    let addr = match family >> 4 {
+        // AF_INET
+        1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        // AF_INET6
+        2 if payload.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        // AF_UNSPEC, AF_UNIX
+        0 | 3 => None,
+        _ => return Err(invalid_header("invalid PROXY protocol v2 address")),
+    };
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_resolve() {
+        let trusted = vec![IpCidr::from_str("10.0.0.0/8").unwrap()];
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "1.1.1.1, 2.2.2.2, 10.0.0.2".parse().unwrap(),
+        );
+        assert_eq!(
+            resolve(&headers, peer, &trusted),
+            "2.2.2.2".parse::<IpAddr>().unwrap()
+        );
+
+        // The headers of the untrusted peer are ignored
+        let untrusted: IpAddr = "3.3.3.3".parse().unwrap();
+        assert_eq!(resolve(&headers, untrusted, &trusted), untrusted);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "forwarded",
+            "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.3"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            resolve(&headers, peer, &trusted),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "4.4.4.4".parse().unwrap());
+        assert_eq!(
+            resolve(&headers, peer, &trusted),
+            "4.4.4.4".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let addr = parse_v1(b"PROXY TCP4 1.1.1.1 2.2.2.2 1111 2222\r\n").unwrap();
+        assert_eq!(addr, Some("1.1.1.1:1111".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP4 1.1.1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let payload = [1, 1, 1, 1, 2, 2, 2, 2, 0x04, 0x57, 0x08, 0xae];
+        let addr = parse_v2(0x21, 0x11, &payload).unwrap();
+        assert_eq!(addr, Some("1.1.1.1:1111".parse().unwrap()));
+        assert_eq!(parse_v2(0x20, 0x00, &[]).unwrap(), None);
+        assert!(parse_v2(0x11, 0x11, &payload).is_err());
+    }
+}
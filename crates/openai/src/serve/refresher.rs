@@ -0,0 +1,110 @@
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::interval;
+
+use super::pool::{self, PoolAccount};
+use crate::auth::provide::AuthProvider;
+use crate::token::model::Token;
+use crate::{info, now_duration, warn, with_context};
+
+/// Webhook event of the token refresher
+#[derive(Serialize)]
+struct RefreshEvent<'a> {
+    event: &'static str,
+    email: &'a str,
+    error: String,
+    timestamp: u64,
+}
+
+/// Periodically refresh the pool account tokens which are about to expire
+pub(super) async fn periodic_refresh(interval_secs: u32, window_secs: u32) {
+    info!("Token refresher periodic task is running");
+    let mut interval = interval(Duration::from_secs(interval_secs.max(1).into()));
+    loop {
+        interval.tick().await;
+        refresh_expiring(window_secs.into()).await;
+    }
+}
+
+/// Refresh the tokens that expire within the window
+async fn refresh_expiring(window_secs: i64) {
+    let now = match now_duration() {
+        Ok(d) => d.as_secs() as i64,
+        Err(err) => {
+            warn!("Failed to get now duration: {}", err);
+            return;
+        }
+    };
+
+    for account in pool::list() {
+        if account.token().expires() - now > window_secs {
+            continue;
+        }
+
+        match refresh(account.token()).await {
+            Ok(token) => {
+                info!("Token refresher: {} refreshed", account.email());
+                pool::put(token);
+            }
+            Err(err) => {
+                warn!(
+                    "Token refresher: {} refresh failed: {}",
+                    account.email(),
+                    err
+                );
+                notify(&account, err).await;
+            }
+        }
+    }
+}
+
+/// Refresh the token with the refresh token, or the session token
+async fn refresh(token: &Token) -> anyhow::Result<Token> {
+    let (auth_client, health) = with_context!(checkout_auth_client, Some(token.email()));
+
+    if let Some(refresh_token) = token.refresh_token() {
+        let result = auth_client.do_refresh_token(refresh_token).await;
+        health.observe_auth(&result);
+        let mut new_token = result?;
+        // The refresh token is not always rotated
+        if new_token.refresh_token.is_none() {
+            new_token.refresh_token = Some(refresh_token.to_owned());
+        }
+        return Token::try_from(new_token);
+    }
+
+    if let Some(session_token) = token.session_token() {
+        let result = auth_client.refresh_session(session_token).await;
+        health.observe_auth(&result);
+        let access_token = result?;
+        return Token::try_from(access_token);
+    }
+
+    anyhow::bail!("no refresh token or session token")
+}
+
+/// Post the refresh failed event to the webhook
+async fn notify(account: &PoolAccount, err: anyhow::Error) {
+    let webhook = match with_context!(token_refresh_webhook) {
+        Some(webhook) => webhook,
+        None => return,
+    };
+
+    let event = RefreshEvent {
+        event: "token_refresh_failed",
+        email: account.email(),
+        error: err.to_string(),
+        timestamp: now_duration().map(|d| d.as_secs()).unwrap_or_default(),
+    };
+
+    let result = with_context!(api_client)
+        .post(webhook)
+        .json(&event)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status());
+
+    if let Some(err) = result.err() {
+        warn!("Token refresher: failed to post webhook event: {}", err)
+    }
+}
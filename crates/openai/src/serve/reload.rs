@@ -0,0 +1,184 @@
+use crate::context::{args::Args, init::Reload};
+use crate::serve::middleware::limit::{LimitContext, Limiter};
+use crate::{info, warn};
+use hotwatch::{Event, EventKind, Hotwatch};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(target_family = "unix")]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+/// Debounce delay of the config file changes
+const WATCH_DELAY: Duration = Duration::from_secs(1);
+
+/// Load the serve args, e.g. re-parse the config file
+pub type Loader = Box<dyn Fn() -> anyhow::Result<Args> + Send + Sync>;
+
+/// Config reloader, triggered by SIGHUP or the config file changes.
+/// The whitelist, auth keys, client pools (proxies, impersonate user agents, timeouts), solver and limits are hot-swapped,
+/// the other settings take effect after a restart.
+pub struct Reloader {
+    path: PathBuf,
+    loader: Loader,
+}
+
+impl Reloader {
+    pub fn new(
+        path: PathBuf,
+        loader: impl Fn() -> anyhow::Result<Args> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            path,
+            loader: Box::new(loader),
+        }
+    }
+
+    /// Wait for the triggers and apply the reloaded config
+    pub(super) async fn watch(self, boot: Args, limit: Arc<LimitContext>) {
+        let (tx, mut rx) = mpsc::channel::<&'static str>(1);
+
+        // The config file is watched as long as the watcher is alive
+        let _hotwatch = self
+            .watch_file(tx.clone())
+            .map_err(|err| warn!("Failed to watch config file: {err}"))
+            .ok();
+
+        #[cfg(target_family = "unix")]
+        tokio::spawn(async move {
+            let mut sighup = signal(SignalKind::hangup()).expect("SIGHUP signal hanlde error");
+            while sighup.recv().await.is_some() {
+                let _ = tx.try_send("SIGHUP");
+            }
+        });
+
+        info!("Config reloading is enabled: {}", self.path.display());
+
+        let mut current = boot.clone();
+        while let Some(trigger) = rx.recv().await {
+            info!("{trigger} received: reloading config");
+            match self.reload(&boot, &current, &limit) {
+                Ok(args) => current = args,
+                Err(err) => warn!("Failed to reload config, the previous config is kept: {err}"),
+            }
+        }
+    }
+
+    /// Watch the parent directory, editors usually replace the file instead of writing it in place
+    fn watch_file(&self, tx: mpsc::Sender<&'static str>) -> anyhow::Result<Hotwatch> {
+        let path = self.path.canonicalize()?;
+        let dir = path
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("Invalid config path: {}", path.display()))?
+            .to_owned();
+
+        let mut hotwatch = Hotwatch::new_with_custom_delay(WATCH_DELAY)?;
+        hotwatch.watch(dir, move |event: Event| match event.kind {
+            EventKind::Create(_) | EventKind::Modify(_) => {
+                if event.paths.iter().any(|p| p.eq(&path)) {
+                    let _ = tx.try_send("Config file change");
+                }
+            }
+            _ => {}
+        })?;
+        Ok(hotwatch)
+    }
+
+    /// Reload the config, nothing is applied unless all the hot-swappable settings are built
+    fn reload(&self, boot: &Args, current: &Args, limit: &LimitContext) -> anyhow::Result<Args> {
+        let args = (self.loader)()?;
+        let reload = Reload::prepare(&args)?;
+        let limiter = match Limiter::changed(current, &args) {
+            true => Some(Limiter::from_args(&args)?),
+            false => None,
+        };
+
+        reload.apply();
+        if let Some(limiter) = limiter {
+            limit.swap(limiter);
+            info!("Limiter reloaded");
+        }
+
+        for setting in restart_required(boot, &args) {
+            warn!("Setting `{setting}` is changed, it takes effect after a restart");
+        }
+
+        info!("Config reloaded");
+        Ok(args)
+    }
+}
+
+/// The changed settings that are only applied at startup
+fn restart_required(boot: &Args, args: &Args) -> Vec<&'static str> {
+    macro_rules! changed {
+        ($($field:ident),* $(,)?) => {{
+            let mut changed = vec![];
+            $(
+                if boot.$field != args.$field {
+                    changed.push(stringify!($field));
+                }
+            )*
+            changed
+        }};
+    }
+
+    changed!(
+        bind,
+        concurrent_limit,
+        fastest_dns,
+        proxy_health_interval,
+        timeout,
+        tls_cert,
+        tls_key,
+        redis_url,
+        trusted_proxies,
+        proxy_protocol,
+        enable_webui,
+        enable_file_proxy,
+        enable_arkose_proxy,
+        enable_metrics,
+        enable_conversation_continuity,
+        conversation_expired,
+        gpt_models,
+        account_pool_key,
+        account_pool_file,
+        account_pool_cooldown,
+        enable_token_refresh,
+        token_refresh_interval,
+        token_refresh_window,
+        token_refresh_webhook,
+        cf_site_key,
+        cf_secret_key,
+        arkose_endpoint,
+        arkose_har_dir,
+        arkose_gpt3_experiment,
+        arkose_gpt3_experiment_solver,
+        arkose_solver_tguess_endpoint,
+        arkose_solver_image_dir,
+        arkose_pool_size,
+        arkose_pool_types,
+        arkose_pool_ttl,
+        arkose_pool_concurrency,
+        visitor_email_whitelist_file,
+        pbind,
+        pupstream,
+        pcert,
+        pkey,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_required() {
+        let boot = Args::builder().build();
+        let args = Args::builder()
+            .enable_webui(true)
+            .visitor_email_whitelist(vec!["a@example.com".to_owned()])
+            .build();
+        assert_eq!(restart_required(&boot, &args), vec!["enable_webui"]);
+        assert!(restart_required(&boot, &boot.clone()).is_empty());
+    }
+}
@@ -4,7 +4,6 @@ mod session;
 
 use axum::body;
 use axum::body::Body;
-use axum::extract::ConnectInfo;
 use axum::extract::Path;
 use axum::extract::Query;
 use axum::headers::authorization::Bearer;
@@ -28,7 +27,6 @@ use axum_csrf::Key;
 use axum_extra::extract::CookieJar;
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::net::SocketAddr;
 use std::sync::OnceLock;
 use tower::ServiceBuilder;
 use tower_http::ServiceBuilderExt;
@@ -43,10 +41,12 @@ use crate::constant::SITE_KEY;
 use crate::constant::SUPPORT_APPLE;
 use crate::constant::USERNAME;
 use crate::context::args::Args;
+use crate::context::authkey::Scope;
 use crate::serve::error::ProxyError;
 use crate::serve::error::ResponseError;
 use crate::serve::middleware::csrf;
 use crate::serve::proxy::header_convert;
+use crate::serve::realip::ClientIp;
 use crate::serve::turnstile;
 use crate::serve::whitelist;
 use crate::with_context;
@@ -87,7 +87,7 @@ pub(super) fn config(router: Router, args: &Args) -> Router {
 
     // Configure arkose routing
     let router =     // If the auth key is empty, then the auth page is not required
-    if with_context!(auth_key).is_some() {
+    if with_context!(auth_keys).is_protected(Scope::Login) {
         router
     } else {
         router.route("/auth", get(auth))
@@ -167,7 +167,7 @@ async fn login_index(token: CsrfToken) -> Result<impl IntoResponse, ResponseErro
 
 /// Login from username and password
 async fn login(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ClientIp(ip): ClientIp,
     token: CsrfToken,
     account: axum::Form<AuthAccount>,
 ) -> Result<impl IntoResponse, ResponseError> {
@@ -189,16 +189,18 @@ async fn login(
     };
 
     // Check if the request is in the turnstile
-    if let Some(err) =
-        turnstile::cf_turnstile_check(addr.ip(), account.cf_turnstile_response.as_deref())
-            .await
-            .map_err(|err| err_handler(err.to_string()))
-            .err()
+    if let Some(err) = turnstile::cf_turnstile_check(ip, account.cf_turnstile_response.as_deref())
+        .await
+        .map_err(|err| err_handler(err.to_string()))
+        .err()
     {
         return Ok(err.into_response());
     };
 
-    match with_context!(auth_client).do_access_token(&account).await {
+    let (client, health) = with_context!(checkout_auth_client, Some(account.username.as_str()));
+    let result = client.do_access_token(&account).await;
+    health.observe_auth(&result);
+    match result {
         Ok(access_token) => {
             // Build session
             let session = Session::from(
@@ -631,9 +633,9 @@ fn settings_template_data(ctx: &mut tera::Context) {
     let context = with_context!();
 
     // If auth key is not empty, well close the auth page
-    context.auth_key().map(|_| {
+    if context.auth_keys().is_protected(Scope::Login) {
         ctx.insert(AUTH_KEY, EMPTY);
-    });
+    }
 
     // If the turnstile is not empty, well enable the turnstile captcha
     context.cf_turnstile().map(|site_key| {
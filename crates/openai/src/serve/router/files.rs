@@ -5,7 +5,7 @@ use crate::{
     context::args::Args,
     serve::{
         error::ResponseError, proxy::ext::RequestExt, proxy::ext::SendRequestExt,
-        proxy::resp::response_convert,
+        proxy::resp::response_convert, puid,
     },
     with_context,
 };
@@ -22,7 +22,8 @@ pub(super) fn config(router: Router, args: &Args) -> Router {
 async fn proxy(mut req: RequestExt) -> Result<impl IntoResponse, ResponseError> {
     req.trim_start_path("/files")?;
     req.append_haeder(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")?;
-    let resp = with_context!(api_client)
+    let account = puid::affinity(req.bearer_auth());
+    let resp = with_context!(checkout_api_client, account.as_deref())
         .send_request("https://files.oaiusercontent.com", req)
         .await?;
     response_convert(resp).await
@@ -2,7 +2,9 @@ mod token;
 
 use crate::context::args::Args;
 use crate::context::arkose::har;
+use crate::context::authkey::Scope;
 use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::realip::ClientIp;
 use crate::{arkose, warn, with_context};
 use axum::body::Body;
 use axum::extract::{Multipart, Query};
@@ -54,7 +56,7 @@ fn success_html(title: &str, success_message: &str) -> Html<String> {
 
 /// Check session
 async fn check_session(jar: CookieJar) -> bool {
-    if with_context!(auth_key).is_none() {
+    if !with_context!(auth_keys).is_protected(Scope::Har) {
         return true;
     }
     if let Some(cookie) = jar.get(COOKIE_NAME) {
@@ -97,17 +99,14 @@ struct AuthenticateKey {
 
 /// Login with password
 async fn post_login(
+    ClientIp(ip): ClientIp,
     password: Option<Form<AuthenticateKey>>,
 ) -> Result<impl IntoResponse, ResponseError> {
-    if let Some(upload_key) = with_context!(auth_key) {
-        if password.as_ref().map(|p| p.0.password.as_ref()) == Some(upload_key) {
-            return Ok(generate_success_response().await.into_response());
-        }
-    } else {
-        return Ok(generate_success_response().await.into_response());
+    let password = password.as_ref().map(|p| p.0.password.as_str());
+    match with_context!(auth_keys).verify(password, Scope::Har, ip) {
+        Ok(_) => Ok(generate_success_response().await.into_response()),
+        Err(err) => Ok(error_html(FAILED_AUTH_TITLE, &err.to_string(), true).into_response()),
     }
-
-    Ok(error_html(FAILED_AUTH_TITLE, "Invalid authentication", true).into_response())
 }
 
 /// Upload page
@@ -6,6 +6,7 @@ use tokio::sync::OnceCell;
 
 use crate::{
     arkose::{self},
+    context::authkey::Scope,
     generate_random_string,
     homedir::home_dir,
     now_duration, with_context,
@@ -20,8 +21,8 @@ async fn get_or_init_secret() -> &'static String {
             let path = home_dir()
                 .unwrap_or_else(|| PathBuf::from("."))
                 .join(".token_secret");
-            let key = if let Some(upload_key) = with_context!(auth_key) {
-                upload_key.to_owned()
+            let key = if let Some(upload_key) = with_context!(auth_keys).first(Scope::Har) {
+                upload_key.key
             } else {
                 generate_random_string(31)
             };
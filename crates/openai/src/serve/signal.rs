@@ -5,7 +5,9 @@ use std::time::Duration;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::time::sleep;
 
-pub(super) async fn graceful_shutdown(handle: Handle) {
+/// Wait for the shutdown signals, SIGHUP is left to the config reloader when `reloading` is enabled
+#[cfg_attr(target_family = "windows", allow(unused_variables))]
+pub(super) async fn graceful_shutdown(handle: Handle, reloading: bool) {
     #[cfg(target_family = "windows")]
     {
         tokio::signal::ctrl_c()
@@ -30,7 +32,7 @@ pub(super) async fn graceful_shutdown(handle: Handle) {
             _ = sigchld.recv() => {
                 sending_graceful_shutdown_signal(handle, "SIGCHLD").await;
             },
-            _ = sighup.recv() => {
+            _ = sighup.recv(), if !reloading => {
                 sending_graceful_shutdown_signal(handle, "SIGHUP").await;
             },
             _ = tokio::signal::ctrl_c() => {
@@ -1,6 +1,11 @@
-use crate::parse;
+use crate::{dataset, parse};
 use clap::{Args, Subcommand};
-use openai::{arkose::funcaptcha::solver::Solver, proxy};
+use openai::{
+    arkose,
+    arkose::funcaptcha::solver::{Solver, SolverEntry},
+    gpt_model::ModelEntry,
+    proxy,
+};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -52,6 +57,9 @@ pub enum ServeSubcommand {
     /// Restart the HTTP server daemon
     #[cfg(target_family = "unix")]
     Restart(ServeArgs),
+    /// Reload the config file of the HTTP server daemon
+    #[cfg(target_family = "unix")]
+    Reload,
     /// Status of the Http server daemon process
     #[cfg(target_family = "unix")]
     Status,
@@ -70,6 +78,21 @@ pub enum ServeSubcommand {
     },
     /// Update the application
     Update,
+    /// Export the funcaptcha image store as a labelled dataset
+    Dataset {
+        /// Funcaptcha image store directory (--arkose-solver-image-dir)
+        #[clap(short, long, value_parser = parse::parse_dir_path)]
+        dir: PathBuf,
+        /// Dataset output directory
+        #[clap(short, long)]
+        out: PathBuf,
+        /// Dataset layout (folders/csv)
+        #[clap(short, long, default_value = "folders")]
+        layout: dataset::Layout,
+        /// Include the samples with rejected answers
+        #[clap(long)]
+        include_rejected: bool,
+    },
 }
 
 #[derive(Args, Debug, Default, Serialize, Deserialize)]
@@ -121,6 +144,22 @@ pub struct ServeArgs {
     #[clap(long, env = "ENABLE_DIRECT")]
     pub(super) enable_direct: bool,
 
+    /// Client pool health check interval (seconds), 0 disables the active probes
+    #[clap(long, env = "PROXY_HEALTH_INTERVAL", default_value = "60")]
+    pub(super) proxy_health_interval: u32,
+
+    /// Consecutive failures (connect errors, timeouts, 5xx, Cloudflare challenges including challenged 403s) to eject a client pool member, 0 disables the ejection
+    #[clap(long, env = "PROXY_EJECT_THRESHOLD", default_value = "3")]
+    pub(super) proxy_eject_threshold: u32,
+
+    /// Ejection backoff of a client pool member (seconds), doubled on every consecutive ejection
+    #[clap(long, env = "PROXY_EJECT_BACKOFF", default_value = "30")]
+    pub(super) proxy_eject_backoff: u32,
+
+    /// Stick each account to a client pool member, or a stable IPv6 of the subnets, failover only when the member is unhealthy
+    #[clap(long, env = "PROXY_AFFINITY")]
+    pub(super) proxy_affinity: bool,
+
     /// Impersonate User-Agent, separate multiple ones with ","
     #[clap(short = 'I',long, env = "IMPERSONATE_UA", value_parser = parse::parse_impersonate_uas, verbatim_doc_comment)]
     pub(super) impersonate_uas: Option<std::vec::Vec<String>>,
@@ -141,6 +180,18 @@ pub struct ServeArgs {
     #[clap(long, env = "TLS_KEY", requires = "tls_cert")]
     pub(super) tls_key: Option<PathBuf>,
 
+    /// Redis url, share the rate limit buckets, PUID cache, preauth cookies and Arkose version across instances, e.g. redis://127.0.0.1:6379
+    #[clap(long, env = "REDIS_URL", value_parser = parse::parse_url)]
+    pub(super) redis_url: Option<String>,
+
+    /// Trusted reverse proxy networks, the X-Forwarded-For/X-Real-IP/Forwarded headers are only honored from these, e.g. 127.0.0.1,10.0.0.0/8
+    #[clap(long, env = "TRUSTED_PROXIES", value_parser = parse::parse_trusted_proxies)]
+    pub(super) trusted_proxies: Option<std::vec::Vec<cidr::IpCidr>>,
+
+    /// Require the HAProxy PROXY protocol (v1/v2) header on the listener
+    #[clap(long, env = "PROXY_PROTOCOL")]
+    pub(super) proxy_protocol: bool,
+
     /// Cloudflare turnstile captcha site key
     #[clap(long, env = "CF_SECRET_KEY", requires = "cf_secret_key")]
     pub(super) cf_site_key: Option<String>,
@@ -153,6 +204,10 @@ pub struct ServeArgs {
     #[clap(short = 'A', long, env = "AUTH_KEY")]
     pub(super) auth_key: Option<String>,
 
+    /// Scoped authentication keys (name/key/scopes/expiry/IP allowlist/rate limit), only configurable in the config file
+    #[clap(skip)]
+    pub(super) auth_keys: Option<std::vec::Vec<openai::context::authkey::AuthKey>>,
+
     /// Enable WebUI
     #[clap(long, env = "ENABLE_WEBUI", requires = "arkose_endpoint")]
     pub(super) enable_webui: bool,
@@ -165,10 +220,68 @@ pub struct ServeArgs {
     #[clap(short = 'G', long, env = "ENABLE_ARKOSE_PROXY")]
     pub(super) enable_arkose_proxy: bool,
 
-    /// Visitor email whitelist
+    /// Enable Prometheus metrics endpoint (/metrics), it requires an auth key of the metrics scope
+    #[clap(long, env = "ENABLE_METRICS")]
+    pub(super) enable_metrics: bool,
+
+    /// Enable conversation continuity of the OpenAI-compatible endpoint
+    /// Conversation key from `X-Conversation-Key` header or `user` field
+    #[clap(long, env = "ENABLE_CONVERSATION_CONTINUITY", verbatim_doc_comment)]
+    pub(super) enable_conversation_continuity: bool,
+
+    /// Conversation continuity expired (seconds)
+    #[clap(
+        long,
+        default_value = "86400",
+        requires = "enable_conversation_continuity"
+    )]
+    pub(super) conversation_expired: u32,
+
+    /// Account pool API key of the OpenAI-compatible endpoint, e.g. sk-ninja-xxx
+    /// The requests with this key are served by the pool accounts
+    #[clap(long, env = "ACCOUNT_POOL_KEY", verbatim_doc_comment)]
+    pub(super) account_pool_key: Option<String>,
+
+    /// Account pool tokens file path, JSON array of the access tokens
+    #[clap(long, value_parser = parse::parse_file_path, requires = "account_pool_key")]
+    pub(super) account_pool_file: Option<PathBuf>,
+
+    /// Cooldown of the rate limited pool account (seconds)
+    #[clap(long, default_value = "3600", requires = "account_pool_key")]
+    pub(super) account_pool_cooldown: u32,
+
+    /// Enable the background token refresher of the account pool
+    #[clap(long, env = "ENABLE_TOKEN_REFRESH", requires = "account_pool_key")]
+    pub(super) enable_token_refresh: bool,
+
+    /// Token refresh scan interval (seconds)
+    #[clap(long, default_value = "600", requires = "enable_token_refresh")]
+    pub(super) token_refresh_interval: u32,
+
+    /// Refresh the token that expires within the window (seconds)
+    #[clap(long, default_value = "86400", requires = "enable_token_refresh")]
+    pub(super) token_refresh_window: u32,
+
+    /// Token refresh failed webhook url, the failed event is posted as JSON
+    #[clap(long, value_parser = parse::parse_url, requires = "enable_token_refresh")]
+    pub(super) token_refresh_webhook: Option<String>,
+
+    /// GPT model table (configuration file only), the built-in table is used if not set
+    #[clap(skip)]
+    pub(super) gpt_models: Option<std::vec::Vec<ModelEntry>>,
+
+    /// Visitor email whitelist, e.g. a@example.com,*@example.com,@example.org,/^dev-.*@example\.com$/
     #[clap(short = 'W', long, env = "VISITOR_EMAIL_WHITELIST", value_parser = parse::parse_email_whitelist)]
     pub(super) visitor_email_whitelist: Option<std::vec::Vec<String>>,
 
+    /// Visitor email deny list, takes precedence over the whitelist
+    #[clap(long, env = "VISITOR_EMAIL_DENYLIST", value_parser = parse::parse_email_whitelist)]
+    pub(super) visitor_email_denylist: Option<std::vec::Vec<String>>,
+
+    /// Visitor email whitelist file, one rule per line, `!` prefixed rules deny, reloaded on changes
+    #[clap(long, env = "VISITOR_EMAIL_WHITELIST_FILE", value_parser = parse::parse_file_path)]
+    pub(super) visitor_email_whitelist_file: Option<PathBuf>,
+
     /// Arkose endpoint, e.g. https://client-api.arkoselabs.com
     #[clap(long, value_parser = parse::parse_url)]
     pub(super) arkose_endpoint: Option<String>,
@@ -185,13 +298,8 @@ pub struct ServeArgs {
     #[clap(long, value_parser = parse::parse_dir_path)]
     pub(super) arkose_har_dir: Option<PathBuf>,
 
-    /// About ArkoseLabs solver platform
-    #[clap(
-        short = 's',
-        long,
-        default_value = "fcsrv",
-        requires = "arkose_solver_key"
-    )]
+    /// About ArkoseLabs solver platform (yescaptcha/capsolver/fcsrv/generic/onnx), the generic and onnx solvers do not require a client key
+    #[clap(short = 's', long, default_value = "fcsrv")]
     pub(super) arkose_solver: Solver,
 
     /// About the solver client key by ArkoseLabs
@@ -199,13 +307,30 @@ pub struct ServeArgs {
     pub(super) arkose_solver_key: Option<String>,
 
     /// About the solver client endpoint by ArkoseLabs
-    #[clap(long, value_parser = parse::parse_url, requires = "arkose_solver_key")]
+    #[clap(long, value_parser = parse::parse_url)]
     pub(super) arkose_solver_endpoint: Option<String>,
 
+    /// About the solver asynchronous task result endpoint by ArkoseLabs
+    #[clap(long, value_parser = parse::parse_url)]
+    pub(super) arkose_solver_result_endpoint: Option<String>,
+
+    /// About the onnx solver model directory by ArkoseLabs, one `<game_variant>.onnx` model per game variant
+    #[clap(long, value_parser = parse::parse_dir_path)]
+    #[cfg(feature = "onnx")]
+    pub(super) arkose_solver_model_dir: Option<PathBuf>,
+
     /// About the solver submit multiple image limit by ArkoseLabs
-    #[clap(long, default_value = "1", requires = "arkose_solver_key")]
+    #[clap(long, default_value = "1")]
     pub(super) arkose_solver_limit: usize,
 
+    /// Fallback solvers after the solver above, tried in order, only configurable in the config file
+    #[clap(skip)]
+    pub(super) arkose_fallback_solvers: Option<std::vec::Vec<SolverEntry>>,
+
+    /// Solver retries of a funcaptcha, each retry fails over to the next solver
+    #[clap(long, default_value = "2")]
+    pub(super) arkose_solver_retries: usize,
+
     /// About the solver tguess endpoint by ArkoseLabs
     #[clap(long, value_parser = parse::parse_url)]
     pub(super) arkose_solver_tguess_endpoint: Option<String>,
@@ -214,12 +339,29 @@ pub struct ServeArgs {
     #[clap(long, value_parser = parse::parse_dir_path)]
     pub(super) arkose_solver_image_dir: Option<PathBuf>,
 
+    /// Arkose token pre-fetch pool size per type, 0 disables the pool
+    #[clap(long, env = "ARKOSE_POOL_SIZE", default_value = "0")]
+    pub(super) arkose_pool_size: usize,
+
+    /// Arkose token pre-fetch pool types (gpt3/auth/platform), all of them if not set.
+    /// The gpt4 and signup tokens are bound to the account, they can not be pooled
+    #[clap(long, env = "ARKOSE_POOL_TYPES", value_parser = parse::parse_arkose_types)]
+    pub(super) arkose_pool_types: Option<std::vec::Vec<arkose::Type>>,
+
+    /// Arkose token pre-fetch pool token TTL (seconds)
+    #[clap(long, default_value = "120")]
+    pub(super) arkose_pool_ttl: u32,
+
+    /// Arkose token pre-fetch pool refill concurrency
+    #[clap(long, default_value = "2")]
+    pub(super) arkose_pool_concurrency: usize,
+
     /// Enable token bucket flow limitation
     #[clap(short = 'T', long)]
     #[cfg(feature = "limit")]
     pub(super) tb_enable: bool,
 
-    /// Token bucket store strategy (mem/redb)
+    /// Token bucket store strategy (mem/redb/redis), redis requires the redis url
     #[clap(long, default_value = "mem", requires = "tb_enable")]
     #[cfg(feature = "limit")]
     pub(super) tb_strategy: String,
@@ -239,6 +381,21 @@ pub struct ServeArgs {
     #[cfg(feature = "limit")]
     pub(super) tb_expired: u32,
 
+    /// Token bucket limit key (ip/email/auth_key)
+    #[clap(long, default_value = "ip", requires = "tb_enable")]
+    #[cfg(feature = "limit")]
+    pub(super) tb_key: String,
+
+    /// Token bucket rules of the specific key, format: key=capacity/fill_rate, separated by commas
+    #[clap(long, value_parser = parse::parse_tb_rules, requires = "tb_enable")]
+    #[cfg(feature = "limit")]
+    pub(super) tb_rules: Option<std::vec::Vec<String>>,
+
+    /// Route group limit policies (sliding window/quota/model cap), only configurable in the config file
+    #[clap(skip)]
+    #[cfg(feature = "limit")]
+    pub(super) limit_policies: Option<std::vec::Vec<openai::serve::LimitPolicy>>,
+
     /// Preauth MITM server bind address
     #[clap(
     short = 'B',
@@ -4,10 +4,19 @@ use crate::{
     args::{self, ServeArgs},
     utils::unix::fix_relative_path,
 };
-use clap::CommandFactory;
-use openai::{arkose::funcaptcha::solver::ArkoseSolver, context::args::Args, proxy, serve::Serve};
+use openai::{
+    arkose::funcaptcha::solver::ArkoseSolver,
+    context::args::Args,
+    gpt_model, proxy,
+    serve::{Reloader, Serve},
+};
 use reqwest::impersonate::Impersonate;
-use std::{net::IpAddr, ops::Not, path::PathBuf, str::FromStr};
+use std::{
+    net::IpAddr,
+    ops::Not,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use url::Url;
 
 pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<()> {
@@ -15,22 +24,11 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         fix_relative_path(&mut args);
     }
 
-    if let Some(config_path) = args.config {
-        let bytes = std::fs::read(config_path)?;
-        let data = String::from_utf8(bytes)?;
-        args = toml::from_str::<ServeArgs>(&data)?;
+    let config = args.config.clone();
+    if let Some(ref config_path) = config {
+        args = read_config(config_path)?;
     }
 
-    let arkose_solver = match args.arkose_solver_key.as_ref() {
-        Some(client_key) => Some(ArkoseSolver::new(
-            args.arkose_solver,
-            client_key.clone(),
-            args.arkose_solver_endpoint,
-            args.arkose_solver_limit,
-        )),
-        None => None,
-    };
-
     #[cfg(target_os = "linux")]
     if let Some(ref proxies) = args.proxies {
         proxies.iter().for_each(|p| {
@@ -49,13 +47,84 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
     }
 
     // Set the log level
-    std::env::set_var("RUST_LOG", args.level);
+    std::env::set_var("RUST_LOG", &args.level);
+
+    let serve = Serve::new(build_args(args)?);
+    match config {
+        // Reload the config on SIGHUP or the config file changes
+        Some(path) => serve
+            .reloader(Reloader::new(path.clone(), move || {
+                build_args(read_config(&path)?)
+            }))
+            .run(),
+        None => serve.run(),
+    }
+}
+
+/// Read the serve args from the TOML config file
+fn read_config(path: &Path) -> anyhow::Result<ServeArgs> {
+    let bytes = std::fs::read(path)?;
+    let data = String::from_utf8(bytes)?;
+    Ok(toml::from_str::<ServeArgs>(&data)?)
+}
+
+/// Build the serve args of the library
+fn build_args(args: ServeArgs) -> anyhow::Result<Args> {
+    #[cfg(feature = "onnx")]
+    if args.arkose_solver.requires_model_dir() && args.arkose_solver_model_dir.is_none() {
+        anyhow::bail!("The onnx solver requires `--arkose-solver-model-dir`")
+    }
+
+    // The config file bypasses the command line parser
+    if let Some(typed) = args
+        .arkose_pool_types
+        .iter()
+        .flatten()
+        .find(|typed| typed.is_account_bound())
+    {
+        anyhow::bail!(
+            "Arkose token type `{}` is bound to the account, it can not be pooled",
+            format!("{typed:?}").to_lowercase()
+        )
+    }
+
+    // The generic and onnx solvers, e.g. an in-house classifier, do not require a client key
+    let arkose_solver = (args.arkose_solver_key.is_some() || !args.arkose_solver.requires_key())
+        .then(|| {
+            let solver = ArkoseSolver::new(
+                args.arkose_solver,
+                args.arkose_solver_key.unwrap_or_default(),
+                args.arkose_solver_endpoint,
+                args.arkose_solver_result_endpoint,
+                args.arkose_solver_limit,
+            );
+            #[cfg(feature = "onnx")]
+            let solver = match args.arkose_solver_model_dir {
+                Some(dir) => solver.with_model_dir(dir),
+                None => solver,
+            };
+            solver
+        });
+    let arkose_solvers = arkose_solver
+        .into_iter()
+        .map(Ok)
+        .chain(
+            args.arkose_fallback_solvers
+                .unwrap_or_default()
+                .into_iter()
+                .map(ArkoseSolver::try_from),
+        )
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
     let builder = Args::builder()
         .bind(args.bind)
         .fastest_dns(args.fastest_dns)
         .proxies(args.proxies.unwrap_or_default())
         .enable_direct(args.enable_direct)
+        .proxy_health_interval(args.proxy_health_interval)
+        .proxy_eject_threshold(args.proxy_eject_threshold)
+        .proxy_eject_backoff(args.proxy_eject_backoff)
+        .proxy_affinity(args.proxy_affinity)
         .cookie_store(args.cookie_store)
         .tcp_keepalive(args.tcp_keepalive)
         .no_keepalive(args.no_keepalive)
@@ -65,19 +134,41 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .concurrent_limit(args.concurrent_limit)
         .tls_cert(args.tls_cert)
         .tls_key(args.tls_key)
+        .redis_url(args.redis_url)
+        .trusted_proxies(args.trusted_proxies.unwrap_or_default())
+        .proxy_protocol(args.proxy_protocol)
         .auth_key(args.auth_key)
+        .auth_keys(args.auth_keys.unwrap_or_default())
         .visitor_email_whitelist(args.visitor_email_whitelist)
+        .visitor_email_denylist(args.visitor_email_denylist)
+        .visitor_email_whitelist_file(args.visitor_email_whitelist_file)
         .cf_site_key(args.cf_site_key)
         .cf_secret_key(args.cf_secret_key)
         .enable_webui(args.enable_webui)
         .arkose_endpoint(args.arkose_endpoint)
         .arkose_gpt3_experiment(args.arkose_gpt3_experiment)
         .arkose_gpt3_experiment_solver(args.arkose_gpt3_experiment_solver)
-        .arkose_solver(arkose_solver)
+        .arkose_solvers(arkose_solvers)
+        .arkose_solver_retries(args.arkose_solver_retries)
         .arkose_solver_tguess_endpoint(args.arkose_solver_tguess_endpoint)
         .arkose_solver_image_dir(args.arkose_solver_image_dir)
+        .arkose_pool_size(args.arkose_pool_size)
+        .arkose_pool_types(args.arkose_pool_types.unwrap_or_default())
+        .arkose_pool_ttl(args.arkose_pool_ttl)
+        .arkose_pool_concurrency(args.arkose_pool_concurrency)
         .enable_file_proxy(args.enable_file_proxy)
         .enable_arkose_proxy(args.enable_arkose_proxy)
+        .enable_metrics(args.enable_metrics)
+        .enable_conversation_continuity(args.enable_conversation_continuity)
+        .conversation_expired(args.conversation_expired)
+        .account_pool_key(args.account_pool_key)
+        .account_pool_file(args.account_pool_file)
+        .account_pool_cooldown(args.account_pool_cooldown)
+        .enable_token_refresh(args.enable_token_refresh)
+        .token_refresh_interval(args.token_refresh_interval)
+        .token_refresh_window(args.token_refresh_window)
+        .token_refresh_webhook(args.token_refresh_webhook)
+        .gpt_models(args.gpt_models.unwrap_or_default())
         .pbind(args.pbind)
         .pupstream(args.pupstream)
         .pcert(args.pcert)
@@ -89,31 +180,24 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .tb_strategy(args.tb_strategy)
         .tb_capacity(args.tb_capacity)
         .tb_fill_rate(args.tb_fill_rate)
-        .tb_expired(args.tb_expired);
+        .tb_expired(args.tb_expired)
+        .tb_key(args.tb_key)
+        .tb_rules(args.tb_rules.unwrap_or_default())
+        .limit_policies(args.limit_policies.unwrap_or_default());
 
     // Parse the impersonate user agents
-    if let Some(impersonate_list) = args.impersonate_uas {
-        let mut impersonate_uas: Vec<Impersonate> = Vec::new();
-        for ua in impersonate_list {
-            match Impersonate::from_str(ua.as_str()) {
-                Ok(impersonate) => {
-                    impersonate_uas.push(impersonate);
-                }
-                Err(_) => {
-                    let mut cmd = args::cmd::Opt::command();
-                    cmd.error(
-                        clap::error::ErrorKind::ArgumentConflict,
-                        &format!("Unsupport impersonate user agent: {}", ua),
-                    )
-                    .exit();
-                }
-            }
+    match args.impersonate_uas {
+        Some(impersonate_list) => {
+            let impersonate_uas = impersonate_list
+                .iter()
+                .map(|ua| {
+                    Impersonate::from_str(ua.as_str())
+                        .map_err(|_| anyhow::anyhow!("Unsupport impersonate user agent: {}", ua))
+                })
+                .collect::<anyhow::Result<Vec<Impersonate>>>()?;
+            Ok(builder.impersonate_uas(impersonate_uas).build())
         }
-
-        let args = builder.impersonate_uas(impersonate_uas).build();
-        Serve::new(args).run()
-    } else {
-        Serve::new(builder.build()).run()
+        None => Ok(builder.build()),
     }
 }
 
@@ -198,6 +282,25 @@ pub(super) fn serve_restart(args: ServeArgs) -> anyhow::Result<()> {
     serve_start(args)
 }
 
+#[cfg(target_family = "unix")]
+pub(super) fn serve_reload() -> anyhow::Result<()> {
+    use crate::utils::unix::{check_root, get_pid};
+    use nix::sys::signal;
+    use nix::unistd::Pid;
+
+    check_root();
+
+    match get_pid() {
+        Some(pid) => {
+            signal::kill(Pid::from_raw(pid.parse::<i32>()?), signal::SIGHUP)?;
+            println!("Ninja config reload is requested, pid: {}", pid);
+        }
+        None => println!("Ninja is not running"),
+    }
+
+    Ok(())
+}
+
 #[cfg(target_family = "unix")]
 pub(super) fn serve_status() -> anyhow::Result<()> {
     use crate::utils::unix::get_pid;
@@ -277,20 +380,32 @@ pub(super) fn generate_template(out: Option<PathBuf>) -> anyhow::Result<()> {
         concurrent_limit: 65535,
         timeout: 600,
         connect_timeout: 60,
+        proxy_health_interval: 60,
+        proxy_eject_threshold: 3,
+        proxy_eject_backoff: 30,
         tcp_keepalive: 60,
         tb_strategy: "mem".to_string(),
         tb_enable: false,
         tb_capacity: 60,
         tb_fill_rate: 1,
         tb_expired: 86400,
+        tb_key: "ip".to_string(),
         cookie_store: true,
         pool_idle_timeout: 90,
         arkose_solver_limit: 3,
+        arkose_solver_retries: 2,
+        arkose_pool_ttl: 120,
+        arkose_pool_concurrency: 2,
         level: "info".to_owned(),
         pcert: PathBuf::from("ca/cert.crt"),
         pkey: PathBuf::from("ca/key.pem"),
         arkose_gpt3_experiment: false,
         enable_file_proxy: false,
+        conversation_expired: 86400,
+        account_pool_cooldown: 3600,
+        token_refresh_interval: 600,
+        token_refresh_window: 86400,
+        gpt_models: Some(gpt_model::default_models()),
         proxies: Some(vec![
             proxy::Proxy::try_from(("all", "socks5://127.0.0.1:8888".parse::<Url>()?))?,
             proxy::Proxy::try_from(("all", "http://127.0.0.1:8889".parse::<Url>()?))?,
@@ -0,0 +1,109 @@
+use openai::arkose::funcaptcha::sample::{read_samples, Sample, Verdict};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Dataset layout
+#[derive(Clone, Copy, Debug)]
+pub enum Layout {
+    /// `<out>/<game_variant>/<answer>/<image>.png`, the rejected samples go to `<out>/<game_variant>/rejected/<answer>/`
+    Folders,
+    /// `<out>/<game_variant>/<image>.png` with a `<out>/manifest.csv`
+    Csv,
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "folders" => Ok(Self::Folders),
+            "csv" => Ok(Self::Csv),
+            _ => anyhow::bail!("Only support `folders` / `csv` layout"),
+        }
+    }
+}
+
+/// Export the funcaptcha image store as a labelled dataset
+pub(super) fn export(
+    dir: PathBuf,
+    out: PathBuf,
+    layout: Layout,
+    include_rejected: bool,
+) -> anyhow::Result<()> {
+    let samples = read_samples(&dir)?
+        .into_iter()
+        .filter(|(_, sample)| include_rejected || sample.verdict == Verdict::Solved)
+        .collect::<Vec<_>>();
+
+    std::fs::create_dir_all(&out)?;
+    let mut manifest = String::from("image,game_variant,instructions,answer,verdict\n");
+    let mut counts = BTreeMap::<&str, (usize, usize)>::new();
+
+    for (image, sample) in &samples {
+        let file_name = image
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid image path: {}", image.display()))?;
+        // The sidecar is not trusted as a path, use the variant directory of the image store
+        let variant = image
+            .parent()
+            .and_then(Path::file_name)
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid image path: {}", image.display()))?;
+        let target = match layout {
+            Layout::Folders => {
+                let mut target = out.join(variant);
+                if sample.verdict == Verdict::Rejected {
+                    target.push("rejected");
+                }
+                target.join(sample.answer.to_string())
+            }
+            Layout::Csv => out.join(variant),
+        };
+        std::fs::create_dir_all(&target)?;
+        let target = target.join(file_name);
+        std::fs::copy(image, &target)?;
+
+        if let Layout::Csv = layout {
+            manifest.push_str(&manifest_row(&out, &target, sample));
+        }
+
+        let count = counts.entry(variant).or_default();
+        match sample.verdict {
+            Verdict::Solved => count.0 += 1,
+            Verdict::Rejected => count.1 += 1,
+        }
+    }
+
+    if let Layout::Csv = layout {
+        std::fs::write(out.join("manifest.csv"), manifest)?;
+    }
+
+    for (variant, (solved, rejected)) in counts {
+        println!("{variant}: {solved} solved, {rejected} rejected");
+    }
+    println!("Exported {} samples to {}", samples.len(), out.display());
+    Ok(())
+}
+
+/// Manifest row of the sample, the image path is relative to the dataset directory
+fn manifest_row(out: &Path, image: &Path, sample: &Sample) -> String {
+    let image = image.strip_prefix(out).unwrap_or(image);
+    format!(
+        "{},{},{},{},{}\n",
+        csv_field(&image.to_string_lossy()),
+        csv_field(&sample.game_variant),
+        csv_field(&sample.instructions),
+        sample.answer,
+        sample.verdict.as_str()
+    )
+}
+
+/// Quote the CSV field if it contains a separator, a quote or a line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
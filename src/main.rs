@@ -9,6 +9,7 @@ pub mod store;
 
 mod args;
 mod daemon;
+mod dataset;
 mod parse;
 mod update;
 mod utils;
@@ -27,6 +28,8 @@ fn main() -> anyhow::Result<()> {
             #[cfg(target_family = "unix")]
             args::ServeSubcommand::Restart(args) => daemon::serve_restart(args)?,
             #[cfg(target_family = "unix")]
+            args::ServeSubcommand::Reload => daemon::serve_reload()?,
+            #[cfg(target_family = "unix")]
             args::ServeSubcommand::Status => daemon::serve_status()?,
             #[cfg(target_family = "unix")]
             args::ServeSubcommand::Log => daemon::serve_log()?,
@@ -36,6 +39,12 @@ fn main() -> anyhow::Result<()> {
             args::ServeSubcommand::UA => print_ua_help(),
             args::ServeSubcommand::GT { out } => daemon::generate_template(out)?,
             args::ServeSubcommand::Update => update::update()?,
+            args::ServeSubcommand::Dataset {
+                dir,
+                out,
+                layout,
+                include_rejected,
+            } => dataset::export(dir, out, layout, include_rejected)?,
         }
     }
 
@@ -52,6 +61,8 @@ fn main() -> anyhow::Result<()> {
                 #[cfg(target_family = "unix")]
                 args::ServeSubcommand::Restart(args) => daemon::serve_restart(args)?,
                 #[cfg(target_family = "unix")]
+                args::ServeSubcommand::Reload => daemon::serve_reload()?,
+                #[cfg(target_family = "unix")]
                 args::ServeSubcommand::Status => daemon::serve_status()?,
                 #[cfg(target_family = "unix")]
                 args::ServeSubcommand::Log => daemon::serve_log()?,
@@ -61,6 +72,12 @@ fn main() -> anyhow::Result<()> {
                 args::ServeSubcommand::UA => print_ua_help(),
                 args::ServeSubcommand::GT { out } => daemon::generate_template(out)?,
                 args::ServeSubcommand::Update => update::update()?,
+                args::ServeSubcommand::Dataset {
+                    dir,
+                    out,
+                    layout,
+                    include_rejected,
+                } => dataset::export(dir, out, layout, include_rejected)?,
             },
             SubCommands::Terminal => {
                 let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -1,4 +1,6 @@
 use anyhow::Context;
+use openai::arkose;
+use openai::context::whitelist::EmailRule;
 use openai::proxy;
 use std::net::IpAddr;
 use std::path::PathBuf;
@@ -50,6 +52,26 @@ pub fn parse_proxies_url(s: &str) -> anyhow::Result<Vec<proxy::Proxy>> {
     Ok(proxies)
 }
 
+// parse trusted proxies, format: ip or cidr, separated by commas
+pub fn parse_trusted_proxies(s: &str) -> anyhow::Result<Vec<cidr::IpCidr>> {
+    let split = s.split(',');
+    let mut networks: Vec<_> = vec![];
+
+    for ele in split {
+        let network = ele.trim();
+        if network.is_empty() {
+            continue;
+        }
+
+        match network.parse::<cidr::IpCidr>() {
+            Ok(cidr) => networks.push(cidr),
+            Err(_) => anyhow::bail!("Invalid trusted proxy format: {}", network),
+        }
+    }
+
+    Ok(networks)
+}
+
 /// parse file path
 pub fn parse_file_path(s: &str) -> anyhow::Result<PathBuf> {
     let path =
@@ -83,7 +105,7 @@ pub fn parse_dir_path(s: &str) -> anyhow::Result<PathBuf> {
 }
 
 /// parse email whitelist
-/// format: email1,email2,email3
+/// format: email1,*@domain2,@domain3,/regex/
 pub fn parse_email_whitelist(s: &str) -> anyhow::Result<Vec<String>> {
     let split = s.split(',');
     let mut emails: Vec<_> = vec![];
@@ -94,16 +116,54 @@ pub fn parse_email_whitelist(s: &str) -> anyhow::Result<Vec<String>> {
             continue;
         }
 
-        if email.contains('@') {
-            emails.push(email.to_string());
-        } else {
-            anyhow::bail!("Invalid email format: {}", email)
-        }
+        EmailRule::from_str(email)?;
+        emails.push(email.to_string());
     }
 
     Ok(emails)
 }
 
+/// parse arkose types
+/// format: gpt4,auth
+pub fn parse_arkose_types(s: &str) -> anyhow::Result<Vec<arkose::Type>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| match arkose::Type::from_str(s)? {
+            typed if typed.is_account_bound() => {
+                anyhow::bail!(
+                    "Arkose token type `{s}` is bound to the account, it can not be pooled"
+                )
+            }
+            typed => Ok(typed),
+        })
+        .collect()
+}
+
+// parse token bucket rules, format: key=capacity/fill_rate
+pub fn parse_tb_rules(s: &str) -> anyhow::Result<Vec<String>> {
+    let split = s.split(',');
+    let mut rules: Vec<_> = vec![];
+
+    for ele in split {
+        let rule = ele.trim();
+        if rule.is_empty() {
+            continue;
+        }
+
+        match rule.rsplit_once('=').and_then(|(_, v)| v.split_once('/')) {
+            Some((capacity, fill_rate))
+                if capacity.parse::<u32>().is_ok() && fill_rate.parse::<u32>().is_ok() =>
+            {
+                rules.push(rule.to_string())
+            }
+            _ => anyhow::bail!("Invalid token bucket rule: {}", rule),
+        }
+    }
+
+    Ok(rules)
+}
+
 // parse impersonate user-agent
 pub fn parse_impersonate_uas(s: &str) -> anyhow::Result<Vec<String>> {
     let split = s.split(',');